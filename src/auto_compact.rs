@@ -0,0 +1,252 @@
+//! Compact a database automatically.
+//!
+//! A [`CompactionPolicy`] decides from the number and size of
+//! the transaction files when a compaction is due, and an
+//! [`AutoCompactor`] checks the database periodically on
+//! a background thread and runs the compactions.
+
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::time::{Duration,SystemTime};
+
+use parking_lot::{Condvar,Mutex};
+
+use crate::compact::{Compaction,CompactionReport};
+use crate::write::WriteFailure;
+
+/// When to compact a database.
+#[derive(Debug,Clone)]
+pub struct CompactionPolicy
+{
+	/// Do a minor compaction once there are this many transaction
+	/// files. Values less than 2 are treated as 2.
+	pub max_transactions: usize,
+	/// Do a minor compaction once the transaction files
+	/// add up to this many bytes, if there are at least 2.
+	pub max_transaction_bytes: u64,
+	/// Do a major compaction once the transaction files are
+	/// this large compared to `main`, e.g. `0.5` for half its size.
+	pub major_ratio: Option<f64>,
+	/// Do a major compaction if there are transactions and
+	/// `main` hasn't been replaced for this long.
+	pub major_interval: Option<Duration>,
+	/// How often to look at the database
+	pub poll_interval: Duration,
+	/// Limit each compaction's bandwidth, see
+	/// [`Compaction::max_bytes_per_second`](../compact/struct.Compaction.html#method.max_bytes_per_second)
+	pub max_bytes_per_second: Option<u64>,
+}
+
+/// Minor compactions at 16 transactions or 1GiB, major ones
+/// when those are a quarter of `main` or daily, checking every 10 seconds.
+impl Default for CompactionPolicy
+{
+	fn default() -> Self
+	{
+		CompactionPolicy
+		{
+			max_transactions: 16,
+			max_transaction_bytes: 1024*1024*1024,
+			major_ratio: Some(0.25),
+			major_interval: Some(Duration::from_secs(24*3600)),
+			poll_interval: Duration::from_secs(10),
+			max_bytes_per_second: None,
+		}
+	}
+}
+
+/// The files in a database, as far as a [`CompactionPolicy`] cares.
+#[derive(Debug,Clone)]
+pub struct DatabaseFiles
+{
+	/// The number of transaction files, not including `main`
+	pub transactions: usize,
+	/// The total size of the transaction files
+	pub transaction_bytes: u64,
+	/// The size of `main`
+	pub main_bytes: u64,
+	/// How long ago `main` was last replaced
+	pub main_age: Duration,
+}
+
+impl DatabaseFiles
+{
+	/// Look at the files in the database at `dir`
+	pub fn scan(dir: &Path) -> std::io::Result<DatabaseFiles>
+	{
+		crate::database::check(dir)?;
+		let main = std::fs::metadata(dir.join("main"))?;
+		let main_age = main.modified()
+			.ok()
+			.and_then(|m| SystemTime::now().duration_since(m).ok())
+			.unwrap_or_default();
+
+		let mut files = DatabaseFiles
+		{
+			transactions: 0,
+			transaction_bytes: 0,
+			main_bytes: main.len(),
+			main_age,
+		};
+
+		for path in crate::database_reader::transaction_files(dir)?
+		{
+			// it may have been compacted away in the meantime
+			if let Ok(m) = std::fs::metadata(&path)
+			{
+				files.transactions += 1;
+				files.transaction_bytes += m.len();
+			}
+		}
+		Ok(files)
+	}
+}
+
+/// The kinds of compaction.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum CompactionKind
+{
+	/// See [`Compaction::minor`](../compact/struct.Compaction.html#method.minor)
+	Minor,
+	/// See [`Compaction::major`](../compact/struct.Compaction.html#method.major)
+	Major,
+}
+
+impl CompactionPolicy
+{
+	/// Decide which compaction, if any, `files` needs now.
+	pub fn decide(&self, files: &DatabaseFiles) -> Option<CompactionKind>
+	{
+		if files.transactions == 0
+			{ return None; }
+
+		if let Some(ratio) = self.major_ratio
+		{
+			if files.main_bytes > 0
+				&& files.transaction_bytes as f64 >= files.main_bytes as f64 * ratio
+			{
+				return Some(CompactionKind::Major);
+			}
+		}
+		if let Some(interval) = self.major_interval
+		{
+			if files.main_age >= interval
+				{ return Some(CompactionKind::Major); }
+		}
+
+		// a minor compaction of one transaction wouldn't change anything
+		if files.transactions < 2
+			{ return None; }
+		if files.transactions >= self.max_transactions
+			|| files.transaction_bytes >= self.max_transaction_bytes
+		{
+			return Some(CompactionKind::Minor);
+		}
+		None
+	}
+}
+
+/// Check the database now, and do a compaction if `policy` wants one.
+///
+/// Returns `None` if no compaction was necessary.
+pub fn compact_if_needed(dir: &Path, policy: &CompactionPolicy)
+	-> Option<Result<CompactionReport, WriteFailure>>
+{
+	let files = match DatabaseFiles::scan(dir)
+	{
+		Ok(f) => f,
+		Err(e) => return Some(Err(e.into())),
+	};
+
+	let mut compaction = match policy.decide(&files)?
+	{
+		CompactionKind::Minor => Compaction::minor(dir),
+		CompactionKind::Major => Compaction::major(dir),
+	};
+	if let Some(limit) = policy.max_bytes_per_second
+	{
+		compaction = compaction.max_bytes_per_second(limit);
+	}
+	Some(compaction.run())
+}
+
+/// Compacts a database on a background thread.
+///
+/// Dropping it stops the thread, after the current
+/// compaction (if any) completes.
+pub struct AutoCompactor
+{
+	stop: Arc<(Mutex<bool>, Condvar)>,
+	thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl AutoCompactor
+{
+	/// Start checking `dir` every `policy.poll_interval`.
+	///
+	/// `on_compaction` is called after each compaction
+	/// with its result.
+	pub fn spawn<F>(dir: &Path, policy: CompactionPolicy, mut on_compaction: F)
+		-> AutoCompactor
+	where
+		F: FnMut(Result<CompactionReport, WriteFailure>) + Send + 'static
+	{
+		let stop = Arc::new((Mutex::new(false), Condvar::new()));
+		let dir: PathBuf = dir.to_owned();
+
+		let thread_stop = stop.clone();
+		let thread = std::thread::Builder::new()
+			.name("sonnerie-compact".to_string())
+			.spawn(
+				move ||
+				{
+					let (stopped, wake) = &*thread_stop;
+					loop
+					{
+						{
+							let mut stopped = stopped.lock();
+							if !*stopped
+							{
+								wake.wait_for(&mut stopped, policy.poll_interval);
+							}
+							if *stopped { break; }
+						}
+
+						if let Some(result) = compact_if_needed(&dir, &policy)
+						{
+							on_compaction(result);
+						}
+					}
+				}
+			)
+			.expect("spawning compaction thread");
+
+		AutoCompactor
+		{
+			stop,
+			thread: Some(thread),
+		}
+	}
+
+	/// Block forever, letting the compactions happen.
+	pub fn join(mut self)
+	{
+		if let Some(thread) = self.thread.take()
+		{
+			let _ = thread.join();
+		}
+	}
+}
+
+impl Drop for AutoCompactor
+{
+	fn drop(&mut self)
+	{
+		*self.stop.0.lock() = true;
+		self.stop.1.notify_all();
+		if let Some(thread) = self.thread.take()
+		{
+			let _ = thread.join();
+		}
+	}
+}
@@ -5,8 +5,6 @@ use std::sync::Arc;
 use parking_lot::RwLock;
 use std::time::{Instant,Duration};
 
-use serde_derive::*;
-
 use sonnerie::*;
 
 pub use hyper::Body;
@@ -14,7 +12,6 @@ pub type Response = hyper::Response<Body>;
 pub type Request = hyper::Request<Body>;
 
 use futures::stream::StreamExt;
-use futures::sink::SinkExt;
 use escape_string::split_one;
 
 fn main()
@@ -39,12 +36,47 @@ fn main()
 				.required(true)
 				.takes_value(true)
 			)
+			.arg(Arg::with_name("commit-interval")
+				.long("commit-interval")
+				.help("wait up to this many milliseconds to commit PUTs \
+					together in a single transaction (default 1000)")
+				.takes_value(true)
+			)
+			.arg(Arg::with_name("no-auto-compact")
+				.long("no-auto-compact")
+				.help("don't compact the database automatically in the background")
+			)
+			.arg(Arg::with_name("compact-bytes-per-second")
+				.long("compact-bytes-per-second")
+				.help("limit the automatic compactions to reading and \
+					writing this many bytes per second")
+				.takes_value(true)
+				.conflicts_with("no-auto-compact")
+			)
+			.arg(Arg::with_name("commit-size")
+				.long("commit-size")
+				.help("commit waiting PUTs once they are this many bytes. \
+					Larger PUTs get their own transaction (default 16MiB)")
+				.takes_value(true)
+			)
+			.arg(Arg::with_name("sort-memory")
+				.long("sort-memory")
+				.help("sort each large PUT up to this many bytes at a time \
+					in memory, and the rest in temporary files in the \
+					database directory (default 16MiB)")
+				.takes_value(true)
+			)
 			.get_matches();
 
 	let addr = matches.value_of("listen").expect("--listen");
 	let addr = addr.parse().unwrap();
 	let dir = matches.value_of_os("dir").expect("--dir");
 	let dir = std::path::Path::new(dir);
+	if let Err(e) = Database::open(dir)
+	{
+		eprintln!("{}", e);
+		std::process::exit(1);
+	}
 
 	let mut runtime = tokio::runtime::Builder::new()
 		.threaded_scheduler()
@@ -55,15 +87,63 @@ fn main()
 		.build()
 		.expect("tokio runtime");
 
+	let mut ingest_options = IngestOptions::default();
+	if let Some(ms) = matches.value_of("commit-interval")
+	{
+		let ms = ms.parse().expect("--commit-interval must be a number");
+		ingest_options.max_delay = Duration::from_millis(ms);
+	}
+	if let Some(bytes) = matches.value_of("commit-size")
+	{
+		ingest_options.max_bytes = bytes.parse().expect("--commit-size must be a number");
+	}
+
+	let sort_memory = matches.value_of("sort-memory")
+		.map(|bytes| bytes.parse().expect("--sort-memory must be a number"))
+		.unwrap_or(16*1024*1024);
+
 	let srv = Tsrv
 	{
 		dir: dir.to_owned(),
+		sort_memory,
+		ingest_limit: ingest_options.max_bytes,
+		ingest: IngestBuffer::new(dir, ingest_options),
 		shared_reader: RwLock::new(Arc::new(DatabaseReader::new(dir).unwrap())),
 		shared_reader_age: RwLock::new(Some(Instant::now())),
 	};
 
 	let srv = Arc::new(srv);
 
+	let _compactor;
+	if !matches.is_present("no-auto-compact")
+	{
+		let srv = srv.clone();
+		let mut policy = CompactionPolicy::default();
+		if let Some(bytes) = matches.value_of("compact-bytes-per-second")
+		{
+			policy.max_bytes_per_second = Some(
+				bytes.parse().expect("--compact-bytes-per-second must be a number")
+			);
+		}
+		_compactor = Some(AutoCompactor::spawn(
+			dir,
+			policy,
+			move |result|
+			{
+				match result
+				{
+					Ok(report) => eprintln!("{}", report),
+					Err(e) => eprintln!("compaction failed: {:?}", e),
+				}
+				srv.invalidate_shared_reader();
+			}
+		));
+	}
+	else
+	{
+		_compactor = None;
+	}
+
 	let make_service = hyper::service::make_service_fn(
 		move |_conn|
 		{
@@ -109,6 +189,10 @@ fn main()
 struct Tsrv
 {
 	dir: PathBuf,
+	// how much of a large PUT to sort in memory
+	sort_memory: usize,
+	ingest: IngestBuffer,
+	ingest_limit: usize,
 	shared_reader: RwLock<Arc<DatabaseReader>>,
 	shared_reader_age: RwLock<Option<Instant>>,
 }
@@ -119,16 +203,20 @@ impl Tsrv
 	async fn run(self: Arc<Tsrv>, req: Request)
 		-> Result<Response, String>
 	{
-		match req.method()
+		match *req.method()
 		{
-			&hyper::Method::GET =>
+			hyper::Method::GET =>
 			{
 				self.get(req).await
 			},
-			&hyper::Method::PUT =>
+			hyper::Method::PUT =>
 			{
 				self.put(req).await
 			},
+			hyper::Method::POST if req.uri().path() == "/compact" =>
+			{
+				self.compact(req).await
+			},
 			_ =>
 				Ok(hyper::Response::builder()
 					.status(hyper::StatusCode::BAD_REQUEST)
@@ -140,109 +228,155 @@ impl Tsrv
 	async fn put(&self, req: Request)
 		-> Result<Response, String>
 	{
-		// let db = DatabaseReader::new(&self.dir).unwrap();
-		let mut tx = CreateTx::new(&self.dir)
-			.map_err(|e| format!("create tx: {}", e))?;
+		let mut lines = lines_from_request::lines(req.into_body());
 
-		/*enum CheckingFormat
-		{
-			NotYet,
-			KeyAndFmt(String, String),
-			NoMore,
-		}*/
+		// small requests are committed together with others by
+		// `ingest`, large ones get their own transaction
+		let mut records = vec!();
+		let mut size = 0usize;
 
-		//let mut next_key_to_check_fmt = CheckingFormat::NotYet;
+		while let Some(line) = lines.next().await
+		{
+			let rec = parse_line(line)?;
+			if let Some(rec) = rec
+			{
+				size += rec.key.len() + rec.format.len() + rec.tail.len() + 8;
+				records.push(rec);
+			}
+			if size >= self.ingest_limit
+			{
+				return self.put_large(records, lines).await;
+			}
+		}
 
+		let mut batch = IngestBatch::new();
+		let mut row_data = vec!();
+		for SortingRecord{ key, ts, format, tail } in records
+		{
+			let row_format = parse_row_format(&format);
+			row_format.to_stored_format(ts, &tail, &mut row_data)
+				.map_err(|e| format!("parsing data according to format: {}", e))?;
+			batch.add_record(&key, &format, &row_data)
+				.map_err(|e| format!("processing record {}[{}]: {}", key, ts, e))?;
+			row_data.clear();
+		}
 
-		let tmpfile = tempfile::NamedTempFile::new()
-			.map_err(|e| format!("creating tempfile {}", e))?;
+		let summary;
+		if batch.is_empty()
+		{
+			summary = "committed nothing".to_string();
+		}
+		else
+		{
+			let ticket = tokio::task::block_in_place(|| self.ingest.submit(batch))
+				.map_err(|e| format!("{}", e))?;
+			let committed = ticket.await
+				.map_err(|e| format!("{}", e))?;
+			self.invalidate_shared_reader();
+			summary = committed.to_string();
+		}
 
-		let mut sorted_file = shardio::ShardWriter::<SortingRecord>::new(
-			tmpfile.path(),
-			1024*100, // kind of random numbers
-			1024*100*4,
-			1024*100*4*4,
-		).map_err(|e| format!("opening sorted writer {}", e))?;
+		hyper::Response::builder()
+			.status(201)
+			.header(hyper::header::CONTENT_TYPE, "text/plain")
+			.body(format!("{}\n", summary).into())
+			.map_err(|e| format!("{}", e))
+	}
 
+	/// Sort a request that's too big to keep in memory
+	/// and commit it as its own transaction
+	async fn put_large(
+		&self,
+		first: Vec<SortingRecord>,
+		mut lines: lines_from_request::Lines,
+	) -> Result<Response, String>
+	{
+		let mut tx = CreateTx::new(&self.dir)
+			.map_err(|e| format!("create tx: {}", e))?
+			.unsorted(
+				ExternalSort::new()
+					.memory(self.sort_memory)
+					.spill_dir(&self.dir)
+			);
+
+		let mut row_data = vec!();
+		let mut add = |record: SortingRecord| -> Result<(), String>
 		{
-			let mut writer = sorted_file.get_sender();
-
-			let mut lines = lines_from_request::lines(req.into_body());
+			let SortingRecord{ key, ts, format, tail } = record;
+			let row_format = parse_row_format(&format);
+			row_format.to_stored_format(ts, &tail, &mut row_data)
+				.map_err(|e| format!("parsing data according to format: {}", e))?;
+			tokio::task::block_in_place(|| tx.add_record(&key, &format, &row_data))
+				.map_err(|e| format!("processing record {}[{}]: {:?}", key, ts, e))?;
+			row_data.clear();
+			Ok(())
+		};
+
+		for rec in first
+		{
+			add(rec)?;
+		}
 
-			while let Some(line) = lines.next().await
+		while let Some(line) = lines.next().await
+		{
+			if let Some(rec) = parse_line(line)?
 			{
-				let line = line.map_err(|e| format!("reading one row from network: {}", e))?;
-				let line = String::from_utf8(line)
-					.map_err(|e| format!("data must be utf-8: {}", e))?;
-				let tail = line.trim_end();
-				if tail.is_empty() { continue; }
-				let (key, tail) = split_one(&tail).ok_or_else(|| format!("reading key"))?;
-				let (timestamp, tail) = split_one(&tail).ok_or_else(|| format!("reading timestamp"))?;
-				let ts: Timestamp = timestamp.parse().map_err(|e| format!("parsing timestamp {}", e))?;
-				let (format, tail) = split_one(&tail).ok_or_else(|| format!("reading timestamp"))?;
-
-				let rec = SortingRecord
-				{
-					key: key.to_string(),
-					ts,
-					format: format.to_string(),
-					tail: tail.to_string(),
-				};
-
-				tokio::task::block_in_place(
-					|| -> Result<(), String>
-					{
-						writer.send(rec)
-							.map_err(|e| format!("writing to sorted tempfile: {}", e))
-					}
-				)?;
+				add(rec)?;
 			}
 		}
 
-		tokio::task::block_in_place(
-			|| -> Result<(), String>
-			{
-				sorted_file.finish()
-					.map_err(|e| format!("doing the external sorting {}", e))?;
+		let summary = tokio::task::block_in_place(|| tx.commit())
+			.map_err(|e| format!("committing tx: {}", e))?;
 
-				let reader = shardio::ShardReader::<SortingRecord>::open(tmpfile.path())
-					.map_err(|e| format!("opening sorted: {}", e))?;
+		self.invalidate_shared_reader();
 
-				let mut row_data = vec!();
+		hyper::Response::builder()
+			.status(201)
+			.header(hyper::header::CONTENT_TYPE, "text/plain")
+			.body(format!("{}\n", summary).into())
+			.map_err(|e| format!("{}", e))
+	}
 
-				for record in reader.iter()
-					.map_err(|e| format!("reading from sorted: {}", e))?
-				{
-					let SortingRecord{ key, ts, format, tail } = record
-						.map_err(|e| format!("parsing temporary data: {}", e))?;
-					let row_format = parse_row_format(&format);
-					row_format.to_stored_format(ts, &tail, &mut row_data)
-						.map_err(|e| format!("parsing data according to format: {}", e))?;
-					tx.add_record(&key, &format, &row_data)
-						.map_err(|e| format!("processing record {}[{}]: {:?}", key, ts, e))?;
-					row_data.clear();
-				}
+	/// `POST /compact` does a minor compaction, `POST /compact?major`
+	/// does a major one.
+	async fn compact(&self, req: Request)
+		-> Result<Response, String>
+	{
+		let major = req.uri().query()
+			.map(
+				|q|
+					url::form_urlencoded::parse(q.as_bytes())
+						.any(|(k, _)| k == "major")
+			)
+			.unwrap_or(false);
 
-				tx.commit()
-					.map_err(|e| format!("committing tx: {}", e))?;
+		let compaction =
+			if major
+				{ Compaction::major(&self.dir) }
+			else
+				{ Compaction::minor(&self.dir) };
 
-				// after a commit happens, invalidate the shared reader
-				{
-					let mut age = self.shared_reader_age.write();
-					*age = None;
-				}
+		let report = tokio::task::spawn_blocking(move || compaction.run())
+			.await
+			.map_err(|e| format!("compacting: {}", e))?
+			.map_err(|e| format!("compacting: {:?}", e))?;
 
-				Ok(())
-			}
-		)?;
+		self.invalidate_shared_reader();
 
 		hyper::Response::builder()
-			.status(201)
+			.status(200)
 			.header(hyper::header::CONTENT_TYPE, "text/plain")
-			.body("ok".into())
+			.body(format!("{}\n", report).into())
 			.map_err(|e| format!("{}", e))
 	}
 
+	/// after a commit happens, invalidate the shared reader
+	fn invalidate_shared_reader(&self)
+	{
+		let mut age = self.shared_reader_age.write();
+		*age = None;
+	}
+
 	async fn get(self: Arc<Self>, req: Request)
 		-> Result<Response, String>
 	{
@@ -269,94 +403,116 @@ impl Tsrv
 			};
 
 		let human_dates = query_string.iter().find(|k|k.0=="human").is_some();
+		let jsonl = query_string.iter().any(|k|k.0=="jsonl");
 
-		let timestamp_fmt;
-		if human_dates
-			{ timestamp_fmt = Default::default(); }
-		else
-			{ timestamp_fmt = sonnerie::formatted::PrintTimestamp::Nanos; }
+		let timestamp_fmt =
+			if human_dates
+				{ Default::default() }
+			else
+				{ sonnerie::formatted::PrintTimestamp::Nanos };
 
 		let filter = sonnerie::Wildcard::new(key);
-		let (mut send, recv) = futures::channel::mpsc::channel(16);
 
 		let srv = self.clone();
-		std::thread::spawn(
-			move ||
-			futures::executor::block_on(
-				async
+		let db = tokio::task::spawn_blocking(move || srv.shared_reader())
+			.await
+			.map_err(|e| format!("opening database: {}", e))?;
+
+		let rows = sonnerie::nonblocking::stream_filter(db, filter)
+			.map(
+				move |record| -> Result<_, std::io::Error>
 				{
-					let db;
+					let mut row: Vec<u8> = vec!();
+					if jsonl
 					{
-						// reuse the same reader object so that
-						// we don't have to do a "dirent" on the db directory
-						// and then open all the files all the time
-						let mut make_new_reader = false;
-						{
-							let age = srv.shared_reader_age.read();
-							if age.is_none() || age.unwrap().elapsed() > Duration::from_secs(10)
-							{
-								drop(age);
-								// make sure another reader thread didn't get here first
-								let mut age = srv.shared_reader_age.write();
-								if age.is_none() || age.unwrap().elapsed() > Duration::from_secs(10)
-								{
-									*age = Some(Instant::now());
-									make_new_reader = true;
-								}
-							}
-						}
-
-						if make_new_reader
-						{
-							let newdb = Arc::new(DatabaseReader::new(&srv.dir).unwrap());
-							db = newdb.clone();
-							let mut rdr = srv.shared_reader.write();
-							*rdr = newdb;
-						}
-						else
-						{
-							let rdr = srv.shared_reader.read();
-							db = rdr.clone();
-						}
+						sonnerie::formatted::print_record_jsonl(
+							&record,
+							&mut row,
+							timestamp_fmt,
+							sonnerie::formatted::PrintRecordFormat::No,
+						)?;
 					}
-
-					// trick sonnerie to not do an fadvise when you search for a single key
-					let searcher: Box<dyn Iterator<Item=sonnerie::record::OwnedRecord>>;
-					if filter.is_exact()
-						{ searcher = Box::new(db.get(filter.prefix())); }
 					else
-						{ searcher = Box::new(db.get_filter(&filter)); }
-
-					for record in searcher
 					{
-						let mut row: Vec<u8> = vec!();
 						sonnerie::formatted::print_record2(
 							&record,
 							&mut row,
 							timestamp_fmt,
 							sonnerie::formatted::PrintRecordFormat::No,
-						).unwrap();
-						row.push(b'\n');
-						let e = send.send(row).await;
-						if let Err(e) = e
-						{
-							eprintln!("channel error: {}", e);
-							break;
-						}
+						)?;
 					}
+					row.push(b'\n');
+					Ok(row)
 				}
-			)
-		);
+			);
+
+		let content_type = if jsonl { "application/x-ndjson" } else { "text/plain" };
 
 		Ok(hyper::Response::builder()
-			.header(hyper::header::CONTENT_TYPE, "text/plain")
-			.body(Body::wrap_stream(recv.map(|a| -> Result<_, std::io::Error> { Ok(a) } )))
+			.header(hyper::header::CONTENT_TYPE, content_type)
+			.body(Body::wrap_stream(rows))
 			.expect("creating response"))
 	}
+
+	/// reuse the same reader object so that
+	/// we don't have to do a "dirent" on the db directory
+	/// and then open all the files all the time
+	fn shared_reader(&self) -> Arc<DatabaseReader>
+	{
+		let mut make_new_reader = false;
+		{
+			let age = self.shared_reader_age.read();
+			if age.is_none() || age.unwrap().elapsed() > Duration::from_secs(10)
+			{
+				drop(age);
+				// make sure another reader thread didn't get here first
+				let mut age = self.shared_reader_age.write();
+				if age.is_none() || age.unwrap().elapsed() > Duration::from_secs(10)
+				{
+					*age = Some(Instant::now());
+					make_new_reader = true;
+				}
+			}
+		}
+
+		if make_new_reader
+		{
+			let newdb = Arc::new(DatabaseReader::new(&self.dir).unwrap());
+			let mut rdr = self.shared_reader.write();
+			*rdr = newdb.clone();
+			newdb
+		}
+		else
+		{
+			self.shared_reader.read().clone()
+		}
+	}
 }
 
 
-#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, PartialOrd, Ord)]
+/// parse one line of a PUT request, `None` if it's blank
+fn parse_line(line: Result<Vec<u8>, hyper::Error>)
+	-> Result<Option<SortingRecord>, String>
+{
+	let line = line.map_err(|e| format!("reading one row from network: {}", e))?;
+	let line = String::from_utf8(line)
+		.map_err(|e| format!("data must be utf-8: {}", e))?;
+	let tail = line.trim_end();
+	if tail.is_empty() { return Ok(None); }
+	let (key, tail) = split_one(tail).ok_or_else(|| "reading key".to_string())?;
+	let (timestamp, tail) = split_one(tail).ok_or_else(|| "reading timestamp".to_string())?;
+	let ts: Timestamp = timestamp.parse().map_err(|e| format!("parsing timestamp {}", e))?;
+	let (format, tail) = split_one(tail).ok_or_else(|| "reading timestamp".to_string())?;
+
+	Ok(Some(SortingRecord
+	{
+		key: key.to_string(),
+		ts,
+		format: format.to_string(),
+		tail: tail.to_string(),
+	}))
+}
+
 struct SortingRecord
 {
 	key: String,
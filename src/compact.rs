@@ -0,0 +1,1085 @@
+//! Merge transactions together.
+//!
+//! Every transaction is its own file and reading gets slower
+//! as there are more of them. A compaction merges them.
+//!
+//! A minor compaction ([`Compaction::minor`]) merges all of the
+//! transaction files into a single new one, leaving `main` alone.
+//! It can also merge just some of them ([`Compaction::select`]),
+//! so that one big transaction isn't rewritten every time.
+//! A major compaction ([`Compaction::major`]) merges everything,
+//! including `main`, into a new `main`. An incremental major
+//! compaction ([`Compaction::incremental`]) copies the segments of
+//! `main` that no transaction touches without compressing them again.
+//!
+//! Major compactions also drop the records that the database's
+//! [retention rules](../retention/index.html) say have expired,
+//! and replace old records with the aggregates that the
+//! [rollup rules](../rollup/index.html) ask for.
+//! A major compaction can also delete or rename keys with
+//! [`Compaction::transforms`].
+//!
+//! A compaction can report its progress as it goes
+//! ([`Compaction::progress`]) and limit how fast it reads and
+//! writes ([`Compaction::max_bytes_per_second`]).
+//!
+//! With [`Compaction::verify`], the new file is read back and
+//! checked before it replaces anything or any file is deleted.
+//!
+//! Only one compaction can run at a time, so a [lock](../lock/index.html)
+//! is taken on the file `.compact` in the database directory.
+
+use std::ffi::{OsStr,OsString};
+use std::io::Write;
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::time::{Duration,Instant};
+
+use byteorder::{ByteOrder,BigEndian};
+
+use crate::create_tx::{CreateTx,CommitSummary};
+use crate::database_reader::DatabaseReader;
+use crate::formatted;
+use crate::lock::{Lock,LockWait};
+use crate::retention::{Retention,RetentionFilter};
+use crate::rollup::{Rollups,RollupFilter,RolledUp};
+use crate::transform::Transforms;
+use crate::write::WriteFailure;
+
+/// Describes a compaction. Start it with [`run`](#method.run).
+#[derive(Debug,Clone)]
+pub struct Compaction
+{
+	dir: PathBuf,
+	major: bool,
+	gegnum: Option<OsString>,
+	gegnum_format: GegnumFormat,
+	timestamp_format: String,
+	nocheck: bool,
+	selection: TransactionSelection,
+	incremental: bool,
+	retention: Option<Retention>,
+	rollups: Option<Rollups>,
+	transforms: Transforms,
+	progress: Option<(Duration, ProgressCallback)>,
+	max_bytes_per_second: Option<u64>,
+	verify: bool,
+	wait: LockWait,
+}
+
+#[derive(Clone)]
+struct ProgressCallback(Arc<dyn Fn(&CompactionProgress) + Send + Sync>);
+
+impl std::fmt::Debug for ProgressCallback
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(f, "ProgressCallback")
+	}
+}
+
+/// How records are written to and read from a
+/// [`gegnum`](struct.Compaction.html#method.gegnum) command.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum GegnumFormat
+{
+	/// Text, as by `sonnerie read --print-format`, with the
+	/// timestamps in the [`timestamp_format`](struct.Compaction.html#method.timestamp_format).
+	/// Timestamps and floats may lose precision.
+	Text,
+	/// Text with timestamps in nanoseconds and floats written
+	/// so that they parse back to exactly the same value
+	Exact,
+	/// The records exactly as they're stored, see
+	/// [`print_record_binary`](../formatted/fn.print_record_binary.html)
+	Binary,
+}
+
+/// Text
+impl Default for GegnumFormat
+{
+	fn default() -> Self
+	{
+		GegnumFormat::Text
+	}
+}
+
+/// Which transactions a minor compaction merges.
+///
+/// The merged file takes the name of the newest transaction
+/// that went into it, so it still sorts before any newer
+/// transactions that weren't merged, and their records
+/// still take precedence. For this reason, the transactions
+/// merged are always consecutive.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum TransactionSelection
+{
+	/// All of the transactions
+	All,
+	/// The oldest `n` transactions
+	Oldest(usize),
+	/// The longest run of consecutive transactions that are each
+	/// smaller than this many bytes (the oldest, if there's a tie)
+	SmallerThan(u64),
+	/// Exactly these transactions, which must be consecutive
+	Paths(Vec<PathBuf>),
+}
+
+impl TransactionSelection
+{
+	/// Choose from `transactions`, which are sorted oldest first
+	fn choose(&self, transactions: Vec<PathBuf>)
+		-> std::io::Result<Vec<PathBuf>>
+	{
+		match self
+		{
+			TransactionSelection::All =>
+				Ok(transactions),
+			TransactionSelection::Oldest(n) =>
+				Ok(transactions.into_iter().take(*n).collect()),
+			TransactionSelection::SmallerThan(bytes) =>
+			{
+				let mut best = 0..0;
+				let mut start = 0;
+				for (idx, path) in transactions.iter().enumerate()
+				{
+					// a missing file was compacted in the meantime
+					let small = std::fs::metadata(path)
+						.map(|m| m.len() < *bytes)
+						.unwrap_or(false);
+					if !small
+					{
+						start = idx+1;
+					}
+					else if idx+1-start > best.len()
+					{
+						best = start .. idx+1;
+					}
+				}
+				Ok(transactions[best].to_vec())
+			},
+			TransactionSelection::Paths(paths) =>
+			{
+				let name = |p: &Path| p.file_name().map(|n| n.to_owned());
+				let mut positions = vec!();
+				for p in paths
+				{
+					let pos = transactions.iter()
+						.position(|t| name(t) == name(p))
+						.ok_or_else(
+							|| std::io::Error::new(
+								std::io::ErrorKind::NotFound,
+								format!("{:?} is not a transaction", p),
+							)
+						)?;
+					positions.push(pos);
+				}
+				positions.sort_unstable();
+				positions.dedup();
+				if positions.windows(2).any(|w| w[1] != w[0]+1)
+				{
+					return Err(std::io::Error::new(
+						std::io::ErrorKind::InvalidInput,
+						"the transactions to compact are not consecutive",
+					));
+				}
+				Ok(positions.into_iter().map(|i| transactions[i].clone()).collect())
+			},
+		}
+	}
+}
+
+/// What a compaction did.
+#[derive(Debug,Clone)]
+pub struct CompactionReport
+{
+	/// True if this was a major compaction
+	pub major: bool,
+	/// The files that were merged
+	pub inputs: Vec<PathBuf>,
+	/// The transaction files that were deleted after merging
+	pub removed: Vec<PathBuf>,
+	/// The number of records written, not including those in
+	/// the segments copied by an incremental compaction
+	pub records: u64,
+	/// The number of segments of `main` that an incremental
+	/// compaction copied unchanged
+	pub copied_segments: u64,
+	/// The number of records dropped because they expired
+	pub expired: u64,
+	/// The number of records replaced by aggregates
+	pub rolled_up: u64,
+	/// The number of records deleted by the transforms
+	pub deleted: u64,
+	/// What was committed, `None` if there was nothing to do.
+	pub commit: Option<CommitSummary>,
+	/// How long it took, including waiting for the lock
+	pub elapsed: Duration,
+}
+
+impl std::fmt::Display for CompactionReport
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		if self.commit.is_none()
+		{
+			return write!(f, "nothing to do");
+		}
+		write!(
+			f,
+			"compacted {} records from {} files, removed {} files",
+			self.records, self.inputs.len(), self.removed.len(),
+		)?;
+		if self.expired != 0
+		{
+			write!(f, ", expired {} records", self.expired)?;
+		}
+		if self.deleted != 0
+		{
+			write!(f, ", deleted {} records", self.deleted)?;
+		}
+		if self.rolled_up != 0
+		{
+			write!(f, ", rolled up {} records", self.rolled_up)?;
+		}
+		if self.copied_segments != 0
+		{
+			write!(f, ", copied {} segments", self.copied_segments)?;
+		}
+		if let Some(v) = self.commit.as_ref().and_then(|c| c.verified)
+		{
+			write!(f, ", verified {} records (digest {:016x})", v.records, v.digest)?;
+		}
+		write!(f, " ({:.3}s)", self.elapsed.as_secs_f64())
+	}
+}
+
+/// How far along a compaction is.
+#[derive(Debug,Clone,Default)]
+pub struct CompactionProgress
+{
+	/// The number of keys read so far
+	pub keys: u64,
+	/// The number of records read so far
+	pub records: u64,
+	/// Roughly how many bytes of the input files have been read
+	pub bytes_read: u64,
+	/// The number of bytes written to the new file
+	pub bytes_written: u64,
+	/// The size of the input files
+	pub total_bytes: u64,
+	/// How long it's been running
+	pub elapsed: Duration,
+}
+
+impl CompactionProgress
+{
+	/// Estimate how much longer it'll take, from how much
+	/// of the input files have been read.
+	pub fn eta(&self) -> Option<Duration>
+	{
+		if self.bytes_read == 0
+			{ return None; }
+		let left = self.total_bytes.saturating_sub(self.bytes_read);
+		Some(self.elapsed.mul_f64(left as f64 / self.bytes_read as f64))
+	}
+}
+
+impl std::fmt::Display for CompactionProgress
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		let percent =
+			if self.total_bytes == 0
+				{ 100.0 }
+			else
+				{ self.bytes_read as f64 * 100.0 / self.total_bytes as f64 };
+		write!(
+			f,
+			"{:.1}%: {} keys, {} records, read {} of {} bytes, wrote {} bytes",
+			percent, self.keys, self.records,
+			self.bytes_read, self.total_bytes, self.bytes_written,
+		)?;
+		if let Some(eta) = self.eta()
+		{
+			write!(f, ", {}s left", eta.as_secs())?;
+		}
+		Ok(())
+	}
+}
+
+impl Compaction
+{
+	/// Merge all of the transaction files in `dir` into one.
+	pub fn minor(dir: &Path) -> Compaction
+	{
+		Self::new(dir, false)
+	}
+
+	/// Merge all of the transaction files in `dir` and `main`
+	/// into a new `main`.
+	pub fn major(dir: &Path) -> Compaction
+	{
+		Self::new(dir, true)
+	}
+
+	fn new(dir: &Path, major: bool) -> Compaction
+	{
+		Compaction
+		{
+			dir: dir.to_owned(),
+			major,
+			gegnum: None,
+			gegnum_format: GegnumFormat::Text,
+			timestamp_format: "%FT%T".to_string(),
+			nocheck: false,
+			selection: TransactionSelection::All,
+			incremental: false,
+			retention: None,
+			rollups: None,
+			transforms: Transforms::new(),
+			progress: None,
+			max_bytes_per_second: None,
+			verify: false,
+			wait: LockWait::Forever,
+		}
+	}
+
+	/// How long to wait if another compaction is running
+	/// (default: until it's done).
+	///
+	/// If it's still running, this fails with an error that
+	/// says what holds the [lock](../lock/index.html).
+	pub fn wait(mut self, wait: LockWait) -> Compaction
+	{
+		self.wait = wait;
+		self
+	}
+
+	/// Read the new file back before committing it, and check
+	/// that it has the same number of records and the same hash
+	/// of their contents as what was merged. If it doesn't, the
+	/// compaction fails and no files are replaced or deleted.
+	///
+	/// [`CompactionReport::commit`] says what was verified.
+	pub fn verify(mut self, verify: bool) -> Compaction
+	{
+		self.verify = verify;
+		self
+	}
+
+	/// Call `callback` with the compaction's progress
+	/// every `interval`, and once at the end.
+	///
+	/// It's called from the thread that reads the records,
+	/// so it should be quick.
+	pub fn progress<F>(mut self, interval: Duration, callback: F) -> Compaction
+	where
+		F: Fn(&CompactionProgress) + Send + Sync + 'static
+	{
+		self.progress = Some((interval, ProgressCallback(Arc::new(callback))));
+		self
+	}
+
+	/// Slow down so that the bytes read and written
+	/// together average no more than this per second.
+	pub fn max_bytes_per_second(mut self, limit: u64) -> Compaction
+	{
+		self.max_bytes_per_second = Some(limit);
+		self
+	}
+
+	/// Use these retention rules instead of the ones in
+	/// the database's retention file. Only major
+	/// compactions apply them.
+	pub fn retention(mut self, retention: Retention) -> Compaction
+	{
+		self.retention = Some(retention);
+		self
+	}
+
+	/// Use these rollup rules instead of the ones in
+	/// the database's rollup file.
+	///
+	/// Minor compactions never roll anything up.
+	pub fn rollups(mut self, rollups: Rollups) -> Compaction
+	{
+		self.rollups = Some(rollups);
+		self
+	}
+
+	/// Delete or rename keys.
+	///
+	/// This is only for major compactions that aren't incremental,
+	/// and is done before the records go through
+	/// [`gegnum`](#method.gegnum).
+	pub fn transforms(mut self, transforms: Transforms) -> Compaction
+	{
+		self.transforms = transforms;
+		self
+	}
+
+	/// With a major compaction, copy the segments of `main` whose
+	/// keys aren't in any transaction as they are, and only rewrite
+	/// the others. This is much faster when the transactions
+	/// are small compared to `main`.
+	///
+	/// The result is the same, except for how the keys are
+	/// divided into segments. It can't be used with
+	/// [`gegnum`](#method.gegnum).
+	pub fn incremental(mut self, incremental: bool) -> Compaction
+	{
+		self.incremental = incremental;
+		self
+	}
+
+	/// Only merge some of the transactions (default: all of them).
+	///
+	/// This is only for minor compactions.
+	pub fn select(mut self, selection: TransactionSelection) -> Compaction
+	{
+		self.selection = selection;
+		self
+	}
+
+	/// Filter the records through this shell command.
+	///
+	/// The command is run by `/bin/sh`, the records are written
+	/// into its stdin as if by `sonnerie read` and its stdout is read
+	/// as if by `sonnerie add`, with a format column. This is
+	/// useful for removing or modifying data. Choose a lossless
+	/// format with [`gegnum_format`](#method.gegnum_format).
+	pub fn gegnum(mut self, command: &OsStr) -> Compaction
+	{
+		self.gegnum = Some(command.to_owned());
+		self
+	}
+
+	/// With [`gegnum`](#method.gegnum), how to write and read
+	/// the records (default [`GegnumFormat::Text`])
+	pub fn gegnum_format(mut self, format: GegnumFormat) -> Compaction
+	{
+		self.gegnum_format = format;
+		self
+	}
+
+	/// With [`gegnum`](#method.gegnum), format and parse
+	/// the timestamps with this strftime format (default `%FT%T`)
+	pub fn timestamp_format(mut self, format: &str) -> Compaction
+	{
+		self.timestamp_format = format.to_owned();
+		self
+	}
+
+	/// With [`gegnum`](#method.gegnum), don't check that each
+	/// key's format stays the same (which is much faster)
+	pub fn nocheck(mut self, nocheck: bool) -> Compaction
+	{
+		self.nocheck = nocheck;
+		self
+	}
+
+	/// Do the compaction.
+	///
+	/// This blocks while another compaction is running,
+	/// unless told otherwise with [`wait`](#method.wait).
+	///
+	/// After the merged data is committed, the transaction
+	/// files that went into it are deleted.
+	pub fn run(&self) -> Result<CompactionReport, WriteFailure>
+	{
+		let started = Instant::now();
+		let dir = &self.dir;
+
+		if self.major && self.selection != TransactionSelection::All
+		{
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				"a major compaction can't select transactions",
+			).into());
+		}
+		if self.incremental && (!self.major || self.gegnum.is_some())
+		{
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				"only a major compaction without gegnum can be incremental",
+			).into());
+		}
+		if !self.transforms.is_empty() && (!self.major || self.incremental)
+		{
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				"only a major compaction that isn't incremental can transform keys",
+			).into());
+		}
+
+		crate::database::check(dir)?;
+		let kind = if self.major { "major compaction" } else { "minor compaction" };
+		let _lock = Lock::acquire(dir, kind, self.wait)?;
+
+		// a minor compaction that dropped a transaction's record
+		// would reveal the older one it replaces in `main`
+		let retention;
+		if !self.major
+			{ retention = Retention::new(); }
+		else if let Some(r) = self.retention.as_ref()
+			{ retention = r.clone(); }
+		else
+			{ retention = Retention::load(dir)?; }
+
+		let rollups;
+		if !self.major
+			{ rollups = Rollups::new(); }
+		else if let Some(r) = self.rollups.as_ref()
+			{ rollups = r.clone(); }
+		else
+			{ rollups = Rollups::load(dir)?; }
+		let previous =
+			if self.major { RolledUp::load(dir)? }
+			else { RolledUp::default() };
+
+		let db;
+		let output;
+		if self.major
+		{
+			db = DatabaseReader::new(dir)?;
+			output = dir.join("main");
+		}
+		else
+		{
+			let selected = self.selection.choose(
+				crate::database_reader::transaction_files(dir)?
+			)?;
+			// a single transaction has nothing to be merged with
+			let worthwhile = selected.len() > 1
+				|| (selected.len() == 1 && self.gegnum.is_some());
+			if !worthwhile
+			{
+				return Ok(CompactionReport
+				{
+					major: false,
+					inputs: vec!(),
+					removed: vec!(),
+					records: 0,
+					copied_segments: 0,
+					expired: 0,
+					rolled_up: 0,
+					deleted: 0,
+					commit: None,
+					elapsed: started.elapsed(),
+				});
+			}
+			// replacing the newest input keeps this file's
+			// place among the transactions
+			output = selected.last().unwrap().clone();
+			db = DatabaseReader::with_transactions(dir, selected)?;
+		}
+		let db = Arc::new(db);
+
+		let mut report = CompactionReport
+		{
+			major: self.major,
+			inputs: db.transaction_paths(),
+			removed: vec!(),
+			records: 0,
+			copied_segments: 0,
+			expired: 0,
+			rolled_up: 0,
+			deleted: 0,
+			commit: None,
+			elapsed: Duration::default(),
+		};
+
+		let nothing_to_do = report.inputs.iter()
+			.all(|p| p.file_name().expect("filename") == "main");
+		if nothing_to_do && self.gegnum.is_none()
+			&& retention.keeps_everything() && rollups.is_empty()
+			&& self.transforms.is_empty()
+		{
+			report.elapsed = started.elapsed();
+			return Ok(report);
+		}
+
+		// create the new transaction after opening the database reader
+		let mut compacted = CreateTx::new(dir)?;
+		if self.verify
+			{ compacted.verify_on_commit(); }
+		let monitor = self.monitor(&db, &compacted);
+
+		let rolled_up_to;
+		if let Some(gegnum) = self.gegnum.as_ref()
+		{
+			let (expired, rolled_up, deleted, to) =
+				self.run_gegnum(gegnum, &db, retention, (rollups, previous), monitor, &mut compacted)?;
+			rolled_up_to = to;
+			report.expired = expired;
+			report.rolled_up = rolled_up;
+			report.deleted = deleted;
+		}
+		else if self.incremental
+		{
+			let mut filter = Filter::new(retention, rollups, previous, db.clone(), monitor);
+			report.copied_segments =
+				self.run_incremental(&report.inputs, &mut filter, &mut compacted)?;
+			filter.monitor.finish();
+			rolled_up_to = filter.rollups.rolled_up_to();
+			report.expired = filter.retention.expired;
+			report.rolled_up = filter.rollups.rolled_up;
+		}
+		else
+		{
+			let mut filter = Filter::new(retention, rollups, previous, db.clone(), monitor);
+			let mut records = self.transforms.apply(&db);
+			for record in &mut records
+			{
+				filter.monitor.record(record.original_key());
+				filter.add(
+					record.key(),
+					record.record.format(),
+					record.record.value(),
+					&mut |k, f, v| compacted.add_record(k, f, v),
+				)?;
+			}
+			filter.rollups.flush(None, &mut |k, f, v| compacted.add_record(k, f, v))?;
+			filter.monitor.finish();
+			rolled_up_to = filter.rollups.rolled_up_to();
+			report.expired = filter.retention.expired;
+			report.rolled_up = filter.rollups.rolled_up;
+			report.deleted = records.deleted;
+		}
+
+		let summary = compacted.commit_to(&output)?;
+		report.records = summary.records;
+		if self.major
+			{ rolled_up_to.save(dir)?; }
+
+		for txfile in &report.inputs
+		{
+			if txfile.file_name().expect("filename in txfile") == "main"
+				|| *txfile == output
+				{ continue; }
+			if let Err(e) = std::fs::remove_file(txfile)
+			{
+				eprintln!("warning: failed to remove {:?}: {}", txfile, e);
+			}
+			else
+			{
+				report.removed.push(txfile.clone());
+			}
+		}
+
+		report.commit = Some(summary);
+		report.elapsed = started.elapsed();
+		Ok(report)
+	}
+
+	// copy the segments of `main` that have no keys in any
+	// transaction and can't have expired or rolled up records, and merge the rest. Returns the number
+	// of segments copied.
+	//
+	// The writer names a segment's first key as the one after
+	// the actual first key, so a segment's keys are only known
+	// to be between the previous segment's last key and its own.
+	fn run_incremental(
+		&self,
+		inputs: &[PathBuf],
+		filter: &mut Filter,
+		compacted: &mut CreateTx,
+	) -> Result<u64, WriteFailure>
+	{
+		let transactions = inputs.iter()
+			.filter(|p| p.file_name().expect("filename") != "main")
+			.cloned()
+			.collect();
+		let txdb = DatabaseReader::with_transactions(&self.dir, transactions)?;
+
+		// the keys that are in the transactions, sorted
+		let mut changed: Vec<String> = vec!();
+		for record in txdb.get_range(..)
+		{
+			if changed.last().map(|k| &k[..]) != Some(record.key())
+			{
+				changed.push(record.key().to_owned());
+			}
+		}
+
+		let mut tx_records = txdb.get_range(..).peekable();
+
+		let mut main = std::fs::File::open(self.dir.join("main"))?;
+		if main.metadata()?.len() == 0
+		{
+			add_records_before(&mut tx_records, filter, compacted, None)?;
+			filter.rollups.flush(None, &mut |k, f, v| compacted.add_record(k, f, v))?;
+			return Ok(0);
+		}
+		let segments = crate::segment_reader::SegmentReader::open(&mut main)?;
+
+		let mut copied = 0;
+		let mut previous_last: Option<&str> = None;
+		let mut segment = segments.first();
+		while let Some(s) = segment.take()
+		{
+			let first = std::str::from_utf8(s.first_key)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+			let last = std::str::from_utf8(s.last_key)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+			let from = previous_last
+				.map(|k| changed.partition_point(|c| &c[..] < k))
+				.unwrap_or(0);
+			let dirty = changed.get(from)
+				.map(|k| &k[..] <= last)
+				.unwrap_or(false)
+				|| filter.might_change(previous_last.unwrap_or(first), last);
+
+			if !dirty
+			{
+				add_records_before(&mut tx_records, filter, compacted, Some((last, 0)))?;
+				filter.rollups.flush(
+					Some(previous_last.unwrap_or(first)),
+					&mut |k, f, v| compacted.add_record(k, f, v),
+				)?;
+				compacted.add_compressed_segment(
+					previous_last.unwrap_or(first),
+					first,
+					last,
+					s.payload,
+				)?;
+				copied += 1;
+				filter.monitor.copied(last);
+			}
+			else
+			{
+				let mut decoded = vec!();
+				crate::segment::decompress(s.payload, &mut decoded)?;
+				crate::segment::for_each_record(
+					&decoded,
+					|key, format, value| -> Result<(), WriteFailure>
+					{
+						let ts = BigEndian::read_u64(value);
+						filter.monitor.record(key);
+						add_records_before(&mut tx_records, filter, compacted, Some((key, ts)))?;
+						// the transaction's record replaces this one
+						let replaced = tx_records.peek()
+							.map(|r| r.key() == key && BigEndian::read_u64(r.value()) == ts)
+							.unwrap_or(false);
+						if !replaced
+						{
+							filter.add(
+								key, format, value,
+								&mut |k, f, v| compacted.add_record(k, f, v),
+							)?;
+						}
+						Ok(())
+					}
+				)?;
+			}
+
+			previous_last = Some(last);
+			segment = segments.segment_after(&s);
+		}
+
+		add_records_before(&mut tx_records, filter, compacted, None)?;
+		filter.rollups.flush(None, &mut |k, f, v| compacted.add_record(k, f, v))?;
+		Ok(copied)
+	}
+
+	fn run_gegnum(
+		&self,
+		gegnum: &OsStr,
+		db: &Arc<DatabaseReader>,
+		retention: Retention,
+		(rollups, previous): (Rollups, RolledUp),
+		monitor: Monitor,
+		compacted: &mut CreateTx,
+	) -> Result<(u64, u64, u64, RolledUp), WriteFailure>
+	{
+		let mut child = std::process::Command::new("/bin/sh")
+			.arg("-c")
+			.arg(gegnum)
+			.stdin(std::process::Stdio::piped())
+			.stdout(std::process::Stdio::piped())
+			.spawn()?;
+
+		let childinput = child.stdin.take().expect("process had no stdin");
+		let mut childinput = std::io::BufWriter::new(childinput);
+
+		let ts_format_copy = self.timestamp_format.clone();
+		let gegnum_format = self.gegnum_format;
+		// a thread that reads from "db" and writes to the child
+		let reader_db = db.clone();
+		let transforms = self.transforms.clone();
+		let reader_thread = std::thread::spawn(
+			move || -> std::io::Result<(u64, u64, u64, RolledUp)>
+			{
+				let timestamp_format = formatted::PrintTimestamp::FormatString(&ts_format_copy);
+				let mut filter = Filter::new(retention, rollups, previous, reader_db.clone(), monitor);
+				let mut print = |key: &str, format: &str, value: &[u8]|
+				{
+					match gegnum_format
+					{
+						GegnumFormat::Text =>
+							formatted::print_row(
+								key, format, value, &mut childinput,
+								timestamp_format,
+								formatted::PrintRecordFormat::Yes,
+							)?,
+						GegnumFormat::Exact =>
+							formatted::print_row_exact(key, format, value, &mut childinput)?,
+						GegnumFormat::Binary =>
+							return formatted::print_row_binary(key, format, value, &mut childinput),
+					}
+					writeln!(&mut childinput)
+				};
+				let mut reader = transforms.apply(&reader_db);
+				for record in &mut reader
+				{
+					filter.monitor.record(record.original_key());
+					filter.add(
+						record.key(),
+						record.record.format(),
+						record.record.value(),
+						&mut print,
+					)?;
+				}
+				filter.rollups.flush(None, &mut print)?;
+				filter.monitor.finish();
+				Ok((
+					filter.retention.expired,
+					filter.rollups.rolled_up,
+					reader.deleted,
+					filter.rollups.rolled_up_to(),
+				))
+			}
+		);
+
+		let childoutput = child.stdout.take().expect("process had no stdout");
+		let mut childoutput = std::io::BufReader::new(childoutput);
+		let added = match self.gegnum_format
+		{
+			GegnumFormat::Text =>
+				formatted::add_from_stream_with_fmt(
+					compacted, db, &mut childoutput,
+					Some(&self.timestamp_format),
+					self.nocheck,
+				),
+			GegnumFormat::Exact =>
+				formatted::add_from_stream_with_fmt(
+					compacted, db, &mut childoutput,
+					None,
+					self.nocheck,
+				),
+			GegnumFormat::Binary =>
+				formatted::add_from_binary_stream(
+					compacted, db, &mut childoutput,
+					self.nocheck,
+				),
+		};
+		// if adding failed, let the child die of a broken pipe
+		drop(childoutput);
+
+		let written = reader_thread.join()
+			.expect("failed to join subprocess writing thread");
+		let result = child.wait()?;
+		added?;
+		let counts = written?;
+		if !result.success()
+		{
+			return Err(std::io::Error::other(
+				format!("--gegnum process failed ({}): cancelling compact", result)
+			).into());
+		}
+		Ok(counts)
+	}
+
+	fn monitor(&self, db: &Arc<DatabaseReader>, compacted: &CreateTx) -> Monitor
+	{
+		let total_bytes = db.transaction_paths().iter()
+			.filter_map(|p| std::fs::metadata(p).ok())
+			.map(|m| m.len())
+			.sum();
+		let db = db.clone();
+		Monitor
+		{
+			progress: CompactionProgress
+			{
+				total_bytes,
+				..Default::default()
+			},
+			started: Instant::now(),
+			next_report: Instant::now(),
+			callback: self.progress.clone(),
+			max_bytes_per_second: self.max_bytes_per_second,
+			read: Box::new(move |key| db.bytes_before(key)),
+			written: Box::new(compacted.written()),
+			key: String::new(),
+			unchecked: 0,
+		}
+	}
+}
+
+// reports the progress and limits the bandwidth,
+// from the thread that reads the records
+struct Monitor
+{
+	progress: CompactionProgress,
+	started: Instant,
+	next_report: Instant,
+	callback: Option<(Duration, ProgressCallback)>,
+	max_bytes_per_second: Option<u64>,
+	// roughly how many bytes of the input come before a key
+	read: Box<dyn Fn(&str) -> u64 + Send>,
+	written: Box<dyn Fn() -> u64 + Send>,
+	key: String,
+	// records since the last check
+	unchecked: u32,
+}
+
+impl Monitor
+{
+	fn active(&self) -> bool
+	{
+		self.callback.is_some() || self.max_bytes_per_second.is_some()
+	}
+
+	// a record of `key` was read
+	fn record(&mut self, key: &str)
+	{
+		if !self.active()
+			{ return; }
+		self.progress.records += 1;
+		if key != self.key
+		{
+			self.progress.keys += 1;
+			self.key.replace_range(.., key);
+		}
+		self.unchecked += 1;
+		if self.unchecked >= 1024
+		{
+			self.unchecked = 0;
+			self.check(key);
+		}
+	}
+
+	// a segment up to `last_key` was copied
+	fn copied(&mut self, last_key: &str)
+	{
+		if self.active()
+			{ self.check(last_key); }
+	}
+
+	fn check(&mut self, key: &str)
+	{
+		self.progress.bytes_read = (self.read)(key).min(self.progress.total_bytes);
+		self.progress.bytes_written = (self.written)();
+		self.progress.elapsed = self.started.elapsed();
+
+		if let Some(limit) = self.max_bytes_per_second
+		{
+			let bytes = self.progress.bytes_read + self.progress.bytes_written;
+			let should_take = Duration::from_secs_f64(bytes as f64 / limit.max(1) as f64);
+			if should_take > self.progress.elapsed
+			{
+				std::thread::sleep(should_take - self.progress.elapsed);
+				self.progress.elapsed = self.started.elapsed();
+			}
+		}
+
+		if let Some((interval, callback)) = self.callback.as_ref()
+		{
+			let now = Instant::now();
+			if now >= self.next_report
+			{
+				(callback.0)(&self.progress);
+				self.next_report = now + *interval;
+			}
+		}
+	}
+
+	// all of the records were read
+	fn finish(&mut self)
+	{
+		if let Some((_, callback)) = self.callback.as_ref()
+		{
+			self.progress.bytes_read = self.progress.total_bytes;
+			self.progress.bytes_written = (self.written)();
+			self.progress.elapsed = self.started.elapsed();
+			(callback.0)(&self.progress);
+		}
+	}
+}
+
+// drops the expired records and rolls up the old ones,
+// for records sorted by key
+struct Filter
+{
+	retention: RetentionFilter,
+	rollups: RollupFilter,
+	monitor: Monitor,
+}
+
+impl Filter
+{
+	fn new(
+		retention: Retention,
+		rollups: Rollups,
+		previous: RolledUp,
+		db: Arc<DatabaseReader>,
+		monitor: Monitor,
+	) -> Filter
+	{
+		Filter
+		{
+			retention: RetentionFilter::new(retention),
+			rollups: RollupFilter::new(rollups, previous, db),
+			monitor,
+		}
+	}
+
+	fn add<E: From<std::io::Error>>(
+		&mut self,
+		key: &str,
+		format: &str,
+		value: &[u8],
+		out: &mut crate::rollup::Output<E>,
+	) -> Result<(), E>
+	{
+		if !self.retention.keep(key, BigEndian::read_u64(value))
+			{ return Ok(()); }
+		self.rollups.add(key, format, value, out)
+	}
+
+	// could any key from `lowest` to `last` be changed
+	fn might_change(&self, lowest: &str, last: &str) -> bool
+	{
+		self.retention.retention().might_expire(lowest, last)
+			|| self.rollups.rollups().might_roll_up(lowest, last)
+	}
+}
+
+// write the records from `records` that come before `key`
+fn add_records_before<I>(
+	records: &mut std::iter::Peekable<I>,
+	filter: &mut Filter,
+	compacted: &mut CreateTx,
+	key: Option<(&str, u64)>,
+) -> Result<(), WriteFailure>
+where
+	I: Iterator<Item=crate::record::OwnedRecord>
+{
+	while let Some(r) = records.peek()
+	{
+		if let Some(key) = key
+		{
+			if (r.key(), BigEndian::read_u64(r.value())) >= key
+				{ break; }
+		}
+		filter.monitor.record(r.key());
+		filter.add(
+			r.key(), r.format(), r.value(),
+			&mut |k, f, v| compacted.add_record(k, f, v),
+		)?;
+		records.next();
+	}
+	Ok(())
+}
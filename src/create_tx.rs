@@ -1,13 +1,17 @@
 //! Add data by means of a new transaction.
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash,Hasher};
 use std::path::{PathBuf,Path};
 use crate::write::Writer;
 use std::io::{Write,Seek};
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::time::{Duration,Instant};
 
 /// Create a transaction file in the specified db directory.
 ///
 /// Add new records with [`new_record`]. They must be
-/// in sorted order.
+/// in sorted order, unless the transaction is [`unsorted`](#method.unsorted).
 ///
 /// After adding records, call [`commit`] which ensures
 /// the transaction is on disk. Not calling commit will
@@ -17,19 +21,108 @@ pub struct CreateTx
 	writer: Option<Writer<std::fs::File>>,
 	tmp: tempfile_fast::PersistableTempFile,
 	dir: PathBuf,
+	started: Instant,
+	digest: Option<RecordDigest>,
+	sort: Option<crate::sort::ExternalSort>,
+}
+
+// a hash of records, in order
+#[derive(Default)]
+struct RecordDigest
+{
+	records: u64,
+	hasher: DefaultHasher,
+}
+
+impl RecordDigest
+{
+	fn add(&mut self, key: &str, format: &str, value: &[u8])
+	{
+		self.records += 1;
+		key.hash(&mut self.hasher);
+		format.hash(&mut self.hasher);
+		value.hash(&mut self.hasher);
+	}
+
+	fn verification(&self) -> Verification
+	{
+		Verification
+		{
+			records: self.records,
+			digest: self.hasher.finish(),
+		}
+	}
+}
+
+/// What was checked after writing a transaction and
+/// reading it back, before committing it.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Verification
+{
+	/// The number of records
+	pub records: u64,
+	/// A hash of all of the records' keys, formats and values
+	pub digest: u64,
+}
+
+/// What a committed transaction contains.
+///
+/// Returned by [`CreateTx::commit`](struct.CreateTx.html#method.commit).
+#[derive(Debug,Clone)]
+pub struct CommitSummary
+{
+	/// The file the transaction was committed to, or `None`
+	/// if it had no records, so no file was created.
+	pub path: Option<PathBuf>,
+	/// The number of distinct keys
+	pub keys: u64,
+	/// The number of records
+	pub records: u64,
+	/// The number of segments in the file
+	pub segments: u64,
+	/// The size of the records before compression
+	pub uncompressed_bytes: u64,
+	/// The size of the file
+	pub compressed_bytes: u64,
+	/// How long the transaction took, from its creation to being on disk
+	pub elapsed: Duration,
+	/// What was verified before committing, if anything
+	pub verified: Option<Verification>,
+}
+
+impl std::fmt::Display for CommitSummary
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self.path.as_ref()
+		{
+			None => write!(f, "committed nothing")?,
+			Some(path) =>
+				write!(
+					f,
+					"committed {} keys, {} records in {} segments to {}: \
+					{} bytes, {} compressed",
+					self.keys, self.records, self.segments, path.display(),
+					self.uncompressed_bytes, self.compressed_bytes,
+				)?,
+		}
+		write!(f, " ({:.3}s)", self.elapsed.as_secs_f64())
+	}
 }
 
 impl CreateTx
 {
 	/// Open a transaction file inside this specific directory.
 	///
-	/// The transaction is named "tx.XXX.tmp" where XXX is an
-	/// increasing value basedo on timestamp.
+	/// The transaction is written to an anonymous temporary file.
 	///
-	/// On commit, the file is renamed to not have the ".tmp"
-	/// suffix.
+	/// On commit, the file is renamed to "tx.XXX" where XXX is an
+	/// increasing value based on the current time in nanoseconds
+	/// (and the process id), so that commits are never delayed by
+	/// one another.
 	pub fn new(dir: &Path) -> std::io::Result<CreateTx>
 	{
+		crate::database::check_dir(dir)?;
 		let tmp = tempfile_fast::PersistableTempFile::new_in(dir)?;
 		let f = tmp.try_clone()?;
 
@@ -40,23 +133,85 @@ impl CreateTx
 			writer: Some(writer),
 			tmp,
 			dir: dir.to_owned(),
+			started: Instant::now(),
+			digest: None,
+			sort: None,
 		};
 		Ok(tx)
 	}
 
+	/// Accept records in any order, sorting them with `sort`
+	/// when the transaction is committed.
+	///
+	/// If the same key and timestamp is added more than once,
+	/// the last one wins.
+	pub fn unsorted(mut self, sort: crate::sort::ExternalSort) -> CreateTx
+	{
+		self.sort = Some(sort);
+		self
+	}
+
 	/// Add a record with the given key, format, and payload.
 	///
 	/// The data must match the format (otherwise you can corrupt
 	/// the database). The data also encodes the timestamp.
 	///
 	/// Each successive call to this function must have greater
-	/// or equal values for key and timestamp.
+	/// or equal values for key and timestamp, unless the
+	/// transaction is [`unsorted`](#method.unsorted).
 	///
 	/// Encode the data with [`row_format`].
 	pub fn add_record(&mut self, key: &str, format: &str, data: &[u8])
 		-> std::result::Result<(), crate::write::WriteFailure>
 	{
-		self.writer.as_mut().unwrap().add_record(key, format, data)
+		if let Some(sort) = self.sort.as_mut()
+		{
+			return sort.add_record(key, format, data);
+		}
+		self.writer.as_mut().unwrap().add_record(key, format, data)?;
+		if let Some(digest) = self.digest.as_mut()
+		{
+			digest.add(key, format, data);
+		}
+		Ok(())
+	}
+
+	/// Before committing, read the file back and check that
+	/// it has exactly the records that were added.
+	///
+	/// The commit fails if it doesn't.
+	pub(crate) fn verify_on_commit(&mut self)
+	{
+		self.digest = Some(RecordDigest::default());
+	}
+
+	/// Returns a function that says how many bytes have been written so far
+	pub(crate) fn written(&self) -> impl Fn() -> u64 + Send + Sync + 'static
+	{
+		self.writer.as_ref().unwrap().written()
+	}
+
+	/// Copy a compressed segment from another file as is.
+	pub(crate) fn add_compressed_segment(
+		&mut self,
+		lowest_key: &str,
+		first_key: &str,
+		last_key: &str,
+		compressed: &[u8],
+	) -> std::result::Result<(), crate::write::WriteFailure>
+	{
+		let mut decoded = vec!();
+		crate::segment::decompress(compressed, &mut decoded)?;
+		self.writer.as_mut().unwrap()
+			.add_compressed_segment(lowest_key, first_key, last_key, compressed, &decoded)?;
+		if let Some(digest) = self.digest.as_mut()
+		{
+			crate::segment::for_each_record(
+				&decoded,
+				|k, f, v| -> std::io::Result<()> { digest.add(k, f, v); Ok(()) },
+			)?;
+		}
+		Ok(())
 	}
 
 	/// Commit the transaction, but give it a specific name.
@@ -64,49 +219,97 @@ impl CreateTx
 	/// This function is necessary for compacting, normally
 	/// you would just call the basic [`commit`].
 	pub fn commit_to(mut self, final_name: &Path)
-		-> std::io::Result<()>
+		-> std::io::Result<CommitSummary>
 	{
+		if let Some(sort) = self.sort.take()
+		{
+			sort.finish(|key, format, data| self.add_record(key, format, data))
+				.map_err(
+					|e| match e
+					{
+						crate::WriteFailure::IOError(e) => e,
+						e => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e)),
+					}
+				)?;
+		}
+
 		let writer = self.writer.take().unwrap();
-		let mut file = writer.finish()?;
+		let (mut file, stats) = writer.finish()?;
 		file.flush()?;
 		let len = file.seek(std::io::SeekFrom::End(0))? as usize;
+
+		let mut summary = CommitSummary
+		{
+			path: None,
+			keys: stats.keys,
+			records: stats.records,
+			segments: stats.segments,
+			uncompressed_bytes: stats.uncompressed_bytes,
+			compressed_bytes: len as u64,
+			elapsed: Duration::default(),
+			verified: None,
+		};
+
 		if len == 0
 		{
+			if let Some(digest) = self.digest.as_ref()
+			{
+				let empty = RecordDigest::default().verification();
+				if digest.verification() != empty
+				{
+					return Err(std::io::Error::new(
+						std::io::ErrorKind::InvalidData,
+						format!("verification failed: wrote {} records but nothing", digest.records),
+					));
+				}
+				summary.verified = Some(empty);
+			}
 			// don't create an empty transaction file
 			drop(file);
 			if final_name.file_name().map(|n| n == "main") != Some(true)
-				{ let _ = std::fs::remove_file(&final_name); }
-			return Ok(());
+				{ let _ = std::fs::remove_file(final_name); }
+			summary.elapsed = self.started.elapsed();
+			return Ok(summary);
 		}
 		file.sync_all()?;
+		if let Some(digest) = self.digest.as_ref()
+		{
+			summary.verified = Some(verify(&mut file, digest.verification())?);
+		}
 		drop(file);
-		let named = self.tmp.persist_by_rename(&final_name)
+		self.tmp.persist_by_rename(final_name)
 			.map_err(|e| e.error)?;
 		if let Some(umask) = get_umask()
 		{
 			use std::os::unix::fs::PermissionsExt;
-			let p = std::fs::Permissions::from_mode((0o444 & !umask) as u32);
+			let p = std::fs::Permissions::from_mode(0o444 & !umask);
 			let _ =std::fs::set_permissions(final_name, p);
 		}
-		Ok(named)
+		summary.path = Some(final_name.to_owned());
+		summary.elapsed = self.started.elapsed();
+		Ok(summary)
 	}
 
 	/// Commit the transaction.
 	///
 	/// On successful completion, the data is on disk (fsync is called)
 	/// and the filename is renamed to lose its ".tmp" suffix.
+	///
+	/// Returns a summary of what was written.
 	pub fn commit(self)
-		-> std::io::Result<()>
+		-> std::io::Result<CommitSummary>
 	{
 		{ // maybe we can just replace `main`
 			let mainpath = self.dir.join("main");
 			let maininfo = std::fs::metadata(&mainpath)?;
 			if maininfo.len() == 0
 			{
-				use fs2::FileExt;
 				// ok, try again, this time having locked the db
-				let lock = std::fs::File::create(self.dir.join(".compact"))?;
-				lock.lock_exclusive()?;
+				let _lock = crate::lock::Lock::acquire(
+					&self.dir,
+					"commit",
+					crate::lock::LockWait::Forever,
+				)?;
 				let maininfo = std::fs::metadata(&mainpath)?;
 				if maininfo.len() == 0
 				{
@@ -119,13 +322,7 @@ impl CreateTx
 
 		for attempt in 0..
 		{
-			let timestamp = std::time::SystemTime::now()
-				.duration_since(std::time::SystemTime::UNIX_EPOCH)
-				.expect("duration_since epoch")
-				.as_secs();
-
-			let n = format!("tx.{:016x}", timestamp);
-			let final_name = self.dir.join(n);
+			let final_name = self.dir.join(transaction_name());
 
 			let f = std::fs::OpenOptions::new()
 				.write(true)
@@ -134,20 +331,20 @@ impl CreateTx
 			match f
 			{
 				Ok(_) =>
-					if let Err(e) = self.commit_to(&final_name)
+					match self.commit_to(&final_name)
 					{
-						eprintln!("failure committing {:?}", final_name);
-						return Err(e);
-					}
-					else
-					{
-						return Ok(());
+						Err(e) =>
+						{
+							eprintln!("failure committing {:?}", final_name);
+							return Err(e);
+						},
+						Ok(summary) => return Ok(summary),
 					},
 				Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists =>
 					{
+						// a reused pid, just take the next name
 						if attempt == 1000
 							{ return Err(e); }
-						std::thread::sleep(std::time::Duration::from_millis(100));
 						continue;
 					}
 				Err(e) => return Err(e),
@@ -157,15 +354,77 @@ impl CreateTx
 	}
 }
 
+// read all of `file`, failing if it doesn't have the records of `expected`
+fn verify(file: &mut std::fs::File, expected: Verification)
+	-> std::io::Result<Verification>
+{
+	let segments = crate::segment_reader::SegmentReader::open(file)?;
+	let mut digest = RecordDigest::default();
+	let mut decoded = vec!();
+	let mut segment = segments.first();
+	while let Some(s) = segment.take()
+	{
+		crate::segment::decompress(s.payload, &mut decoded)?;
+		crate::segment::for_each_record(
+			&decoded,
+			|k, f, v| -> std::io::Result<()> { digest.add(k, f, v); Ok(()) },
+		)?;
+		segment = segments.segment_after(&s);
+	}
+
+	let found = digest.verification();
+	if found != expected
+	{
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!(
+				"verification failed: wrote {} records (digest {:016x}) \
+					but read back {} (digest {:016x})",
+				expected.records, expected.digest, found.records, found.digest,
+			),
+		));
+	}
+	Ok(found)
+}
+
+static LAST_TRANSACTION_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Choose the file name for a new transaction.
+///
+/// The name is "tx." followed by the time in nanoseconds since
+/// the epoch and this process's id, both in fixed-width hex.
+/// Within a process, the time in each successive name always
+/// increases, even if two commits happen in the same nanosecond or
+/// the clock goes backwards, so a later commit always sorts
+/// lexically after an earlier one. The pid keeps concurrent
+/// processes from choosing the same name.
+///
+/// Older versions named transactions with the time in seconds, which
+/// always sorts before these names.
+pub(crate) fn transaction_name() -> String
+{
+	let now = std::time::SystemTime::now()
+		.duration_since(std::time::SystemTime::UNIX_EPOCH)
+		.expect("duration_since epoch")
+		.as_nanos() as u64;
+
+	let prev = LAST_TRANSACTION_TIME.fetch_update(
+		Ordering::SeqCst, Ordering::SeqCst,
+		|last| Some(std::cmp::max(now, last+1))
+	).unwrap();
+	let time = std::cmp::max(now, prev+1);
+
+	format!("tx.{:016x}.{:08x}", time, std::process::id())
+}
+
 fn get_umask() -> Option<libc::mode_t>
 {
 	let s = std::fs::read_to_string("/proc/self/status").ok()?;
 	for line in s.split("\n")
 	{
-		if line.starts_with("Umask:")
+		if let Some(line) = line.strip_prefix("Umask:")
 		{
-			let line = &line["Umask:".len() ..];
-			return libc::mode_t::from_str_radix(&line, 8).ok();
+			return libc::mode_t::from_str_radix(line, 8).ok();
 		}
 	}
 	None
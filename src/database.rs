@@ -0,0 +1,218 @@
+//! Create a database, and check that a directory is one.
+//!
+//! A database is a directory with a file named `main` in it, which
+//! is empty until the first transaction is committed. [`Database::create`]
+//! lays one out, along with a file named `meta` that says which
+//! version of the format it's in:
+//!
+//! ```text
+//! format 1
+//! created 1600000000
+//! ```
+//!
+//! Databases created before there was a `meta` file are in format 1.
+
+use std::path::{Path,PathBuf};
+use std::time::{Duration,SystemTime};
+
+use crate::create_tx::CreateTx;
+use crate::database_reader::DatabaseReader;
+
+/// The name of the metadata file in the database directory
+pub const META_FILE: &str = "meta";
+
+/// The newest format this version can read and write
+pub const FORMAT_VERSION: u32 = 1;
+
+/// What the `meta` file says about a database.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Metadata
+{
+	/// The version of the file format
+	pub format: u32,
+	/// When the database was created, if known
+	pub created: Option<SystemTime>,
+}
+
+impl Metadata
+{
+	fn parse(text: &str) -> Result<Metadata, String>
+	{
+		let mut format = None;
+		let mut created = None;
+		for (number, line) in text.lines().enumerate()
+		{
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#')
+				{ continue; }
+			let (name, value) = line.split_once(' ')
+				.ok_or_else(|| format!("line {}: expected a name and a value", number+1))?;
+			match name
+			{
+				"format" => format = Some(
+					value.trim().parse()
+						.map_err(|_| format!("line {}: invalid format \"{}\"", number+1, value))?
+				),
+				"created" => created = value.trim().parse().ok()
+					.map(|s| SystemTime::UNIX_EPOCH + Duration::from_secs(s)),
+				_ => {},
+			}
+		}
+		Ok(Metadata
+		{
+			format: format.ok_or("no format")?,
+			created,
+		})
+	}
+
+	fn load(dir: &Path) -> std::io::Result<Metadata>
+	{
+		let path = dir.join(META_FILE);
+		let text = match std::fs::read_to_string(&path)
+		{
+			Ok(t) => t,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+				return Ok(Metadata { format: 1, created: None }),
+			Err(e) => return Err(e),
+		};
+		Self::parse(&text)
+			.map_err(
+				|e| std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					format!("{}: {}", path.display(), e),
+				)
+			)
+	}
+}
+
+/// A database directory.
+///
+/// Create one with [`create`](#method.create), or check that a
+/// directory is one with [`open`](#method.open).
+#[derive(Debug,Clone)]
+pub struct Database
+{
+	dir: PathBuf,
+	metadata: Metadata,
+}
+
+impl Database
+{
+	/// Make a new, empty database at `dir`.
+	///
+	/// The directory is created if it doesn't exist. Fails with
+	/// `AlreadyExists` if there's a database there already.
+	pub fn create(dir: &Path) -> std::io::Result<Database>
+	{
+		std::fs::create_dir_all(dir)?;
+		if dir.join("main").exists()
+			{ return Err(already_exists(dir)); }
+
+		let created = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+		let metadata = Metadata
+		{
+			format: FORMAT_VERSION,
+			created: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(created)),
+		};
+		// written before `main`, so it's there once `main` is
+		std::fs::write(
+			dir.join(META_FILE),
+			format!("format {}\ncreated {}\n", metadata.format, created),
+		)?;
+
+		match std::fs::OpenOptions::new().write(true).create_new(true).open(dir.join("main"))
+		{
+			Ok(_) => {},
+			Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists =>
+				return Err(already_exists(dir)),
+			Err(e) => return Err(e),
+		}
+
+		Ok(Database { dir: dir.to_owned(), metadata })
+	}
+
+	/// Check that `dir` is a database that this version can read.
+	pub fn open(dir: &Path) -> std::io::Result<Database>
+	{
+		let metadata = check(dir)?;
+		Ok(Database { dir: dir.to_owned(), metadata })
+	}
+
+	/// The database's directory
+	pub fn dir(&self) -> &Path
+	{
+		&self.dir
+	}
+
+	/// What its `meta` file says
+	pub fn metadata(&self) -> &Metadata
+	{
+		&self.metadata
+	}
+
+	/// Read what's committed now
+	pub fn reader(&self) -> std::io::Result<DatabaseReader>
+	{
+		DatabaseReader::new(&self.dir)
+	}
+
+	/// Start a transaction
+	pub fn transaction(&self) -> std::io::Result<CreateTx>
+	{
+		CreateTx::new(&self.dir)
+	}
+}
+
+fn already_exists(dir: &Path) -> std::io::Error
+{
+	std::io::Error::new(
+		std::io::ErrorKind::AlreadyExists,
+		format!("{} is already a sonnerie database", dir.display()),
+	)
+}
+
+/// Fail clearly if `dir` doesn't exist.
+pub(crate) fn check_dir(dir: &Path) -> std::io::Result<()>
+{
+	if !dir.is_dir()
+	{
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::NotFound,
+			format!(
+				"{} is not a directory; create a database with `sonnerie init`",
+				dir.display(),
+			),
+		));
+	}
+	Ok(())
+}
+
+/// Fail clearly if `dir` isn't a database, or is in a newer format.
+pub(crate) fn check(dir: &Path) -> std::io::Result<Metadata>
+{
+	check_dir(dir)?;
+	if !dir.join("main").exists()
+	{
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::NotFound,
+			format!(
+				"{} is not a sonnerie database (it has no \"main\" file); create one with `sonnerie init`",
+				dir.display(),
+			),
+		));
+	}
+	let metadata = Metadata::load(dir)?;
+	if metadata.format > FORMAT_VERSION
+	{
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!(
+				"{} is in format {}, but this version only understands up to {}",
+				dir.display(), metadata.format, FORMAT_VERSION,
+			),
+		));
+	}
+	Ok(metadata)
+}
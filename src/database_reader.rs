@@ -9,8 +9,6 @@ use crate::record::OwnedRecord;
 use crate::key_reader::*;
 use crate::Wildcard;
 
-use byteorder::ByteOrder;
-
 /// Read a database in key-timestamp sorted format.
 ///
 /// Open a database with [`new`](#method.new) and then [`get`](#method.get),
@@ -45,26 +43,32 @@ impl DatabaseReader
 		Self::new_opts(dir, false)
 	}
 
-	fn new_opts(dir: &Path, include_main_db: bool)
+	/// Open only the given transaction files of the database at `dir`.
+	///
+	/// They are merged in the order given, so a record in a
+	/// later file replaces one in an earlier file.
+	/// This is only useful for compacting some of the transactions.
+	pub fn with_transactions(dir: &Path, paths: Vec<PathBuf>)
 		-> std::io::Result<DatabaseReader>
 	{
-		let dir_reader = std::fs::read_dir(dir)?;
-
-		let mut paths = vec!();
+		crate::database::check_dir(dir)?;
+		Self::open(dir, false, paths)
+	}
 
-		for entry in dir_reader
-		{
-			let entry = entry?;
-			if let Some(s) = entry.file_name().to_str()
-			{
-				if s.starts_with("tx.") && !s.ends_with(".tmp")
-				{
-					paths.push(entry.path());
-				}
-			}
-		}
+	fn new_opts(dir: &Path, include_main_db: bool)
+		-> std::io::Result<DatabaseReader>
+	{
+		if include_main_db
+			{ crate::database::check(dir)?; }
+		else
+			{ crate::database::check_dir(dir)?; }
+		let paths = transaction_files(dir)?;
+		Self::open(dir, include_main_db, paths)
+	}
 
-		paths.sort();
+	fn open(dir: &Path, include_main_db: bool, paths: Vec<PathBuf>)
+		-> std::io::Result<DatabaseReader>
+	{
 		let mut txes = Vec::with_capacity(paths.len());
 
 		if include_main_db
@@ -119,6 +123,18 @@ impl DatabaseReader
 			.collect()
 	}
 
+	/// Each file that was opened, oldest first
+	pub(crate) fn files(&self) -> impl Iterator<Item=(&Path, &Reader)>
+	{
+		self.txes.iter().map(|tx| (tx.0.as_path(), &tx.1))
+	}
+
+	/// Roughly how many bytes of the files come before `key`
+	pub(crate) fn bytes_before(&self, key: &str) -> u64
+	{
+		self.txes.iter().map(|tx| tx.1.bytes_before(key)).sum()
+	}
+
 	/// Get a reader for only a single key
 	///
 	/// Returns an object that will read all of the
@@ -148,18 +164,31 @@ impl DatabaseReader
 		{
 			readers.push( tx.1.get_range(range.clone()) );
 		}
-		let merge = Merge::new(
-			readers,
-			|a, b|
-			{
-				a.key().cmp(b.key())
-					.then_with(
-						||
-							byteorder::BigEndian::read_u64(a.value())
-								.cmp(&byteorder::BigEndian::read_u64(b.value()))
-					)
-			},
-		);
+		let merge = Merge::new(readers, record_order);
+
+		DatabaseKeyReader
+		{
+			_db: self,
+			merge: Box::new(merge),
+		}
+	}
+
+	/// Get a reader for the keys in a lexicographic range
+	/// that also match a wildcard filter.
+	///
+	/// Example: `rdr.get_range_filter("chimp-b" .., &Wildcard::new("chimp-%"))`
+	pub fn get_range_filter<'d, 'k, RB>(&'d self, range: RB, wildcard: &'k Wildcard)
+		-> DatabaseKeyReader<'d, 'k, (std::ops::Bound<&'k str>, std::ops::Bound<&'k str>)>
+	where
+		RB: std::ops::RangeBounds<&'k str> + Clone
+	{
+		let mut readers = Vec::with_capacity(self.txes.len());
+
+		for tx in &self.txes
+		{
+			readers.push( tx.1.get_range_filter(range.clone(), wildcard) );
+		}
+		let merge = Merge::new(readers, record_order);
 
 		DatabaseKeyReader
 		{
@@ -181,18 +210,7 @@ impl DatabaseReader
 		{
 			readers.push( tx.1.get_filter(wildcard) );
 		}
-		let merge = Merge::new(
-			readers,
-			|a, b|
-			{
-				a.key().cmp(b.key())
-					.then_with(
-						||
-							byteorder::BigEndian::read_u64(a.value())
-								.cmp(&byteorder::BigEndian::read_u64(b.value()))
-					)
-			},
-		);
+		let merge = Merge::new(readers, record_order);
 
 		DatabaseKeyReader
 		{
@@ -205,6 +223,39 @@ impl DatabaseReader
 
 
 
+/// The order of the records in a database: by key,
+/// then by timestamp.
+pub fn record_order(a: &OwnedRecord, b: &OwnedRecord) -> std::cmp::Ordering
+{
+	a.key().cmp(b.key())
+		.then_with(|| a.timestamp().cmp(&b.timestamp()))
+}
+
+/// The committed transaction files in `dir`, not including `main`,
+/// sorted from oldest to newest.
+pub(crate) fn transaction_files(dir: &Path)
+	-> std::io::Result<Vec<PathBuf>>
+{
+	let dir_reader = std::fs::read_dir(dir)?;
+
+	let mut paths = vec!();
+
+	for entry in dir_reader
+	{
+		let entry = entry?;
+		if let Some(s) = entry.file_name().to_str()
+		{
+			if s.starts_with("tx.") && !s.ends_with(".tmp")
+			{
+				paths.push(entry.path());
+			}
+		}
+	}
+
+	paths.sort();
+	Ok(paths)
+}
+
 /// An iterator over the filtered keys in a database.
 ///
 /// Yields an [`OwnedRecord`](record/struct.OwnedRecord.html)
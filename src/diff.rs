@@ -0,0 +1,180 @@
+//! Compare the records of two databases.
+//!
+//! [`diff`] walks two sequences of records, such as two
+//! [`DatabaseReader::get_range`](../struct.DatabaseReader.html#method.get_range)s,
+//! in the order of [`record_order`](../fn.record_order.html), and yields
+//! each record that's only in one of them, or that has a
+//! different format or value in each.
+
+use std::cmp::Ordering;
+use std::iter::Peekable;
+
+use crate::database_reader::record_order;
+use crate::record::OwnedRecord;
+
+/// A difference between two databases.
+#[derive(Debug,Clone)]
+pub enum Change
+{
+	/// Only the new database has this record
+	Added(OwnedRecord),
+	/// Only the old database has this record
+	Removed(OwnedRecord),
+	/// Both have a record with this key and timestamp,
+	/// the old and the new, but it's different
+	Changed(OwnedRecord, OwnedRecord),
+}
+
+impl Change
+{
+	/// The key of the record that changed
+	pub fn key(&self) -> &str
+	{
+		match self
+		{
+			Change::Added(r) | Change::Removed(r) | Change::Changed(r, _) => r.key(),
+		}
+	}
+}
+
+/// Compare the records of `old` and `new`, which must both be
+/// sorted as a database is.
+pub fn diff<O, N>(old: O, new: N) -> Diff<O::IntoIter, N::IntoIter>
+where
+	O: IntoIterator<Item=OwnedRecord>,
+	N: IntoIterator<Item=OwnedRecord>,
+{
+	Diff
+	{
+		old: old.into_iter().peekable(),
+		new: new.into_iter().peekable(),
+	}
+}
+
+/// The differences between two sequences of records, in order.
+///
+/// Made by [`diff`].
+pub struct Diff<O, N>
+where
+	O: Iterator<Item=OwnedRecord>,
+	N: Iterator<Item=OwnedRecord>,
+{
+	old: Peekable<O>,
+	new: Peekable<N>,
+}
+
+impl<O, N> Diff<O, N>
+where
+	O: Iterator<Item=OwnedRecord>,
+	N: Iterator<Item=OwnedRecord>,
+{
+	/// Count the changes to each key instead
+	pub fn by_key(self) -> KeyDiffs<O, N>
+	{
+		KeyDiffs
+		{
+			diff: self.peekable(),
+		}
+	}
+}
+
+impl<O, N> Iterator for Diff<O, N>
+where
+	O: Iterator<Item=OwnedRecord>,
+	N: Iterator<Item=OwnedRecord>,
+{
+	type Item = Change;
+
+	fn next(&mut self) -> Option<Change>
+	{
+		loop
+		{
+			let order = match (self.old.peek(), self.new.peek())
+			{
+				(None, None) => return None,
+				(Some(_), None) => Ordering::Less,
+				(None, Some(_)) => Ordering::Greater,
+				(Some(old), Some(new)) => record_order(old, new),
+			};
+			match order
+			{
+				Ordering::Less => return Some(Change::Removed(self.old.next().unwrap())),
+				Ordering::Greater => return Some(Change::Added(self.new.next().unwrap())),
+				Ordering::Equal =>
+				{
+					let old = self.old.next().unwrap();
+					let new = self.new.next().unwrap();
+					if old.format() != new.format() || old.value() != new.value()
+					{
+						return Some(Change::Changed(old, new));
+					}
+				},
+			}
+		}
+	}
+}
+
+/// How many of one key's records changed.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct KeyDiff
+{
+	/// The key
+	pub key: String,
+	/// How many records only the new database has
+	pub added: u64,
+	/// How many records only the old database has
+	pub removed: u64,
+	/// How many records are different in each
+	pub changed: u64,
+}
+
+impl std::fmt::Display for KeyDiff
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(
+			f,
+			"{}\t{} added\t{} removed\t{} changed",
+			escape_string::escape(&self.key), self.added, self.removed, self.changed,
+		)
+	}
+}
+
+/// The keys that changed, in order, with how many of their
+/// records changed.
+///
+/// Made by [`Diff::by_key`].
+pub struct KeyDiffs<O, N>
+where
+	O: Iterator<Item=OwnedRecord>,
+	N: Iterator<Item=OwnedRecord>,
+{
+	diff: Peekable<Diff<O, N>>,
+}
+
+impl<O, N> Iterator for KeyDiffs<O, N>
+where
+	O: Iterator<Item=OwnedRecord>,
+	N: Iterator<Item=OwnedRecord>,
+{
+	type Item = KeyDiff;
+
+	fn next(&mut self) -> Option<KeyDiff>
+	{
+		let mut key = KeyDiff
+		{
+			key: self.diff.peek()?.key().to_owned(),
+			..Default::default()
+		};
+		while let Some(change) = self.diff.next_if(|c| c.key() == key.key)
+		{
+			match change
+			{
+				Change::Added(_) => key.added += 1,
+				Change::Removed(_) => key.removed += 1,
+				Change::Changed(..) => key.changed += 1,
+			}
+		}
+		Some(key)
+	}
+}
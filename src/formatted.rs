@@ -4,18 +4,197 @@ use escape_string::split_one;
 use crate::row_format::*;
 use byteorder::ByteOrder;
 
+/// What to do with a line of input that can't be added.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum OnError
+{
+	/// Stop, returning the error
+	Abort,
+	/// Leave the line out
+	Skip,
+	/// Leave the line out, and print why on stderr
+	Log,
+}
+
+/// Decides what happens to the lines of input that can't be added,
+/// such as ones that can't be parsed or have the wrong format.
+///
+/// Errors reading the input or writing the transaction
+/// always stop.
+pub struct BadLines<'w>
+{
+	on_error: OnError,
+	reject: Option<&'w mut dyn std::io::Write>,
+	header: Option<String>,
+	skipped: u64,
+}
+
+impl<'w> BadLines<'w>
+{
+	/// Handle bad lines according to `on_error`
+	pub fn new(on_error: OnError) -> BadLines<'w>
+	{
+		BadLines
+		{
+			on_error,
+			reject: None,
+			header: None,
+			skipped: 0,
+		}
+	}
+
+	/// Also write each line that's left out to `out`,
+	/// so that it can be fixed and added again
+	pub fn reject_to(mut self, out: &'w mut dyn std::io::Write) -> BadLines<'w>
+	{
+		self.reject = Some(out);
+		self
+	}
+
+	/// How many lines were left out
+	pub fn skipped(&self) -> u64
+	{
+		self.skipped
+	}
+
+	// the first line of the reject file, such as a CSV header
+	fn header(&mut self, header: String)
+	{
+		self.header = Some(header);
+	}
+
+	fn handle(&mut self, number: u64, line: &str, error: crate::WriteFailure)
+		-> Result<(), crate::WriteFailure>
+	{
+		let error = match error
+		{
+			crate::WriteFailure::IOError(e) if e.kind() == std::io::ErrorKind::InvalidData =>
+				invalid(format!("line {}: {}: {:?}", number, e, line)),
+			crate::WriteFailure::IOError(e) => return Err(e.into()),
+			e => e,
+		};
+		if self.on_error == OnError::Abort
+			{ return Err(error); }
+
+		self.skipped += 1;
+		if self.on_error == OnError::Log
+		{
+			match &error
+			{
+				crate::WriteFailure::IOError(e) => eprintln!("{}", e),
+				e => eprintln!("line {}: {}: {:?}", number, e, line),
+			}
+		}
+		if let Some(out) = self.reject.as_mut()
+		{
+			if let Some(header) = self.header.take()
+			{
+				writeln!(out, "{}", header)?;
+			}
+			writeln!(out, "{}", line)?;
+		}
+		Ok(())
+	}
+}
+
+fn invalid<S: ToString>(what: S) -> crate::WriteFailure
+{
+	crate::WriteFailure::IOError(
+		std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string())
+	)
+}
+
+/// Parse a timestamp with the strftime-like `timestamp_format`,
+/// or as nanoseconds if it's `None`. If the format has only a
+/// date, the time is midnight.
+///
+/// Fails if it's before the epoch or too late to fit
+/// in 64 bits of nanoseconds.
+pub fn parse_timestamp(timestamp: &str, timestamp_format: Option<&str>)
+	-> Result<Timestamp, crate::WriteFailure>
+{
+	if let Some(f) = timestamp_format
+	{
+		let n = chrono::NaiveDateTime::parse_from_str(timestamp, f)
+			.or_else(
+				|e| chrono::NaiveDate::parse_from_str(timestamp, f)
+					.map(|d| d.and_time(chrono::NaiveTime::MIN))
+					.map_err(|_| e)
+			)
+			.map_err(|e| invalid(format!("the timestamp {:?} isn't like {:?}: {}", timestamp, f, e)))?;
+		n.and_utc().timestamp_nanos_opt()
+			.filter(|&n| n >= 0)
+			.map(|n| n as Timestamp)
+			.ok_or_else(|| invalid(format!("the timestamp {:?} is out of range", timestamp)))
+	}
+	else
+	{
+		timestamp.parse()
+			.map_err(|e| invalid(format!("the timestamp {:?} isn't nanoseconds: {}", timestamp, e)))
+	}
+}
+
+// parse `format`, reusing the last one if it's the same
+fn row_format<'f>(
+	format: &str,
+	name: &mut String,
+	parsed: &'f mut Option<Box<dyn RowFormat>>,
+) -> Result<&'f dyn RowFormat, crate::WriteFailure>
+{
+	if parsed.is_none() || name != format
+	{
+		if let Some(c) = format.chars().find(|c| !"iuIUfFs".contains(*c))
+		{
+			return Err(invalid(format!("the format {:?} has the invalid character {:?}", format, c)));
+		}
+		*parsed = Some(parse_row_format(format));
+		name.replace_range(.., format);
+	}
+	Ok(parsed.as_deref().unwrap())
+}
+
+// unless `nocheck`, check that `key` has `format` in `db`
+fn check_format(
+	db: &crate::DatabaseReader,
+	nocheck: bool,
+	key_format_identified: &mut String,
+	key: &str,
+	format: &str,
+) -> Result<(), crate::WriteFailure>
+{
+	if !nocheck && key_format_identified != key
+	{
+		if let Some(record) = db.get(key).next()
+		{
+			if record.format() != format
+			{
+				return Err(crate::WriteFailure::HeterogeneousFormats(
+					key.to_string(),
+					record.format().to_owned(),
+					format.to_owned()
+				));
+			}
+		}
+		key_format_identified.replace_range(.., key);
+	}
+	Ok(())
+}
+
 /// Read keys from a text stream and insert it into a transaction
 ///
 /// Parameters:
 /// * `tx` - a transaction to write into
 /// * `db` - the database that is type-checked against
 /// * `format` - the format of each row. If each row
-/// contains its own format, you can instead use [`add_from_stream_with_fmt`].
+///   contains its own format, you can instead use [`add_from_stream_with_fmt`].
 /// * `input` - a text stream to read from, the keys are formatted as
-/// `label timestamp value [value ...]`. Whitespace is escaped with a backslash.
+///   `label timestamp value [value ...]`. Whitespace is escaped with a backslash.
 /// * `timestamp` - the strftime-like format to parse timestamps as. If `None`, use
-/// epoch nanos.
+///   epoch nanos.
 /// * `nocheck` - turns off slow type checking (with `db`).
+///
+/// A line that can't be parsed is an error that says which line it
+/// was. To leave such lines out instead, use [`add_from_stream2`].
 pub fn add_from_stream<R: std::io::BufRead>(
 	tx: &mut crate::CreateTx,
 	db: &crate::DatabaseReader,
@@ -24,55 +203,24 @@ pub fn add_from_stream<R: std::io::BufRead>(
 	nocheck: bool,
 ) -> Result<(), crate::WriteFailure>
 {
-	let row_format = parse_row_format(format);
-
-	let mut line = String::new();
-	let mut row_data = vec!();
-	let mut key_format_identified = String::new();
-
-	while 0 != input.read_line(&mut line).unwrap()
-	{
-		let tail = line.trim_end();
-		if tail.is_empty() { continue; }
-		let (key, tail) = split_one(&tail).unwrap();
-		let (timestamp, tail) = split_one(&tail).unwrap();
-		let ts: Timestamp;
-		if let Some(f) = timestamp_format.as_ref()
-		{
-			let n = chrono::NaiveDateTime::parse_from_str(&timestamp, f)
-				.expect("parsing timestamp according to format");
-			ts = n.timestamp_nanos() as Timestamp;
-		}
-		else
-		{
-		 ts = timestamp.parse().expect("parsing timestamp");
-		}
-
-		row_format.to_stored_format(ts, &tail, &mut row_data)
-			.expect(&format!("parsing values \"{}\"", tail));
-
-		if !nocheck && key_format_identified != key
-		{
-			if let Some(record) = db.get(&key).next()
-			{
-				if record.format() != format
-				{
-					return Err(crate::WriteFailure::HeterogeneousFormats(
-						key.to_string(),
-						record.format().to_owned(),
-						format.to_owned()
-					));
-				}
-			}
-			key_format_identified = key.to_string();
-		}
-
-		tx.add_record(&key, format, &row_data)?;
-		row_data.clear();
-		line.clear();
-	}
+	add_from_stream2(
+		tx, db, format, input, timestamp_format, nocheck,
+		&mut BadLines::new(OnError::Abort),
+	)
+}
 
-	Ok(())
+/// Like [`add_from_stream`], but `bad_lines` decides what
+/// to do with the lines that can't be added.
+pub fn add_from_stream2<R: std::io::BufRead>(
+	tx: &mut crate::CreateTx,
+	db: &crate::DatabaseReader,
+	format: &str, input: &mut R,
+	timestamp_format: Option<&str>,
+	nocheck: bool,
+	bad_lines: &mut BadLines,
+) -> Result<(), crate::WriteFailure>
+{
+	add_text(tx, db, Some(format), input, timestamp_format, nocheck, bad_lines)
 }
 
 /// Reads from text, each record reports its own format.
@@ -87,57 +235,64 @@ pub fn add_from_stream_with_fmt<R: std::io::BufRead>(
 	nocheck: bool,
 ) -> Result<(), crate::WriteFailure>
 {
+	add_text(
+		tx, db, None, input, timestamp_format, nocheck,
+		&mut BadLines::new(OnError::Abort),
+	)
+}
 
+// lines of text, with their own formats if `format` is `None`
+fn add_text<R: std::io::BufRead>(
+	tx: &mut crate::CreateTx,
+	db: &crate::DatabaseReader,
+	format: Option<&str>,
+	input: &mut R,
+	timestamp_format: Option<&str>,
+	nocheck: bool,
+	bad_lines: &mut BadLines,
+) -> Result<(), crate::WriteFailure>
+{
 	let mut line = String::new();
+	let mut number = 0;
+	let mut row_format_name = String::new();
+	let mut parsed_format = None;
 	let mut row_data = vec!();
 	let mut key_format_identified = String::new();
 
-	while 0 != input.read_line(&mut line).unwrap()
+	loop
 	{
-		let tail = line.trim_end();
-		if tail.is_empty() { continue; }
-		let (key, tail) = split_one(&tail).unwrap();
-		let (timestamp, tail) = split_one(&tail).unwrap();
-		let ts: Timestamp;
-		if let Some(f) = timestamp_format.as_ref()
-		{
-			let n = chrono::NaiveDateTime::parse_from_str(&timestamp, f)
-				.expect("parsing timestamp according to format");
-			ts = n.timestamp_nanos() as Timestamp;
-		}
-		else
-		{
-		 ts = timestamp.parse().expect("parsing timestamp");
-		}
-
-		let (format, values) = split_one(&tail).unwrap();
-		let row_format = parse_row_format(&format);
-
-		row_format.to_stored_format(ts, &values, &mut row_data)
-			.unwrap();
+		line.clear();
+		if 0 == input.read_line(&mut line)?
+			{ return Ok(()); }
+		number += 1;
+		let text = line.trim_end();
+		if text.is_empty() { continue; }
 
-		if !nocheck && key_format_identified != key
+		let mut add = || -> Result<(), crate::WriteFailure>
 		{
-			if let Some(record) = db.get(&key).next()
+			let (key, tail) = split_one(text)
+				.ok_or_else(|| invalid("there's no key"))?;
+			let (timestamp, tail) = split_one(tail)
+				.ok_or_else(|| invalid("there's no timestamp"))?;
+			let ts = parse_timestamp(&timestamp, timestamp_format)?;
+			let (format, values) = match format
 			{
-				if record.format() != format
-				{
-					return Err(crate::WriteFailure::HeterogeneousFormats(
-						key.to_string(),
-						record.format().to_owned(),
-						format.to_string()
-					));
-				}
-			}
-			key_format_identified = key.to_string();
-		}
-
-		tx.add_record(&key, &format, &row_data)?;
+				Some(f) => (std::borrow::Cow::Borrowed(f), tail),
+				None => split_one(tail).ok_or_else(|| invalid("there's no format"))?,
+			};
+			row_format(&format, &mut row_format_name, &mut parsed_format)?
+				.to_stored_format(ts, values, &mut row_data)
+				.map_err(invalid)?;
+			check_format(db, nocheck, &mut key_format_identified, &key, &format)?;
+			tx.add_record(&key, &format, &row_data)
+		};
+		let r = add();
 		row_data.clear();
-		line.clear();
+		if let Err(e) = r
+		{
+			bad_lines.handle(number, text, e)?;
+		}
 	}
-
-	Ok(())
 }
 
 /// Write a formatted record to a stream
@@ -157,9 +312,7 @@ pub fn print_record<W: std::io::Write>(
 	let ts = &record.value()[0..8];
 	let value = &record.value()[8..];
 	let ts: u64 = byteorder::BigEndian::read_u64(ts);
-	let ts = chrono::NaiveDateTime::from_timestamp(
-		(ts/1_000_000_000) as i64, (ts%1_000_000_000) as u32
-	);
+	let ts = naive_time(ts);
 
 	write!(out, "{}\t{}\t", escape_string::escape(key), ts)?;
 
@@ -183,9 +336,7 @@ pub fn print_record_with_fmt<W: std::io::Write>(
 	let ts = &record.value()[0..8];
 	let value = &record.value()[8..];
 	let ts: u64 = byteorder::BigEndian::read_u64(ts);
-	let ts = chrono::NaiveDateTime::from_timestamp(
-		(ts/1_000_000_000) as i64, (ts%1_000_000_000) as u32
-	);
+	let ts = naive_time(ts);
 
 	write!(
 		out, "{}\t{}\t{}\t",
@@ -277,38 +428,644 @@ pub fn print_record2<W: std::io::Write>(
 	print_record_format: PrintRecordFormat,
 ) -> std::io::Result<()>
 {
-	let fmt_string = record.format();
+	print_row(
+		record.key(), record.format(), record.value(),
+		out, print_timestamp, print_record_format,
+	)
+}
+
+/// Like [`print_record2`], for a record that's in pieces
+pub(crate) fn print_row<W: std::io::Write>(
+	key: &str,
+	fmt_string: &str,
+	value: &[u8],
+	out: &mut W,
+	print_timestamp: PrintTimestamp<'_>,
+	print_record_format: PrintRecordFormat,
+) -> std::io::Result<()>
+{
 	let fmt = parse_row_format(fmt_string);
-	let key = record.key();
-	let ts = &record.value()[0..8];
-	let value = &record.value()[8..];
+	let ts = &value[0..8];
+	let value = &value[8..];
 	let ts: u64 = byteorder::BigEndian::read_u64(ts);
 
 	write!(out, "{}\t", escape_string::escape(key))?;
+	write_timestamp(out, ts, print_timestamp)?;
+	write!(out, "\t")?;
+	match print_record_format
+	{
+		PrintRecordFormat::Yes =>
+			write!(out, "{}\t", fmt_string)?,
+		PrintRecordFormat::No => {},
+	}
 
+	fmt.to_protocol_format(value, out)
+}
+
+// the UTC time of `ts`, which is always in chrono's range
+fn naive_time(ts: Timestamp) -> chrono::NaiveDateTime
+{
+	chrono::DateTime::from_timestamp((ts/1_000_000_000) as i64, (ts%1_000_000_000) as u32)
+		.expect("a timestamp is out of range")
+		.naive_utc()
+}
+
+fn write_timestamp<W: std::io::Write>(
+	out: &mut W,
+	ts: Timestamp,
+	print_timestamp: PrintTimestamp<'_>,
+) -> std::io::Result<()>
+{
 	match print_timestamp
 	{
 		PrintTimestamp::Nanos =>
-			write!(out, "{}", ts)?,
+			write!(out, "{}", ts),
 		PrintTimestamp::Seconds =>
-			write!(out, "{}", ts/1_000_000_000)?,
+			write!(out, "{}", ts/1_000_000_000),
 		PrintTimestamp::FormatString(strf) =>
 		{
-			let ts = chrono::NaiveDateTime::from_timestamp(
-				(ts/1_000_000_000) as i64, (ts%1_000_000_000) as u32
-			);
-			write!(out, "{}", ts.format(strf))?;
+			let ts = naive_time(ts);
+			write!(out, "{}", ts.format(strf))
 		}
 	}
+}
 
-	write!(out, "\t")?;
-	match print_record_format
+/// Like [`print_row`], with the timestamp in nanoseconds, the format,
+/// and floats written so that they parse back to exactly the same value
+pub(crate) fn print_row_exact<W: std::io::Write>(
+	key: &str,
+	fmt_string: &str,
+	value: &[u8],
+	out: &mut W,
+) -> std::io::Result<()>
+{
+	let fmt = parse_row_format(fmt_string);
+	let ts: u64 = byteorder::BigEndian::read_u64(&value[0..8]);
+	write!(out, "{}\t{}\t{}\t", escape_string::escape(key), ts, fmt_string)?;
+	fmt.to_exact_protocol_format(&value[8..], out)
+}
+
+/// Write a record to a stream exactly as it's stored, in
+/// the format that [`add_from_binary_stream`] accepts.
+///
+/// Each record is the key, the format and the value,
+/// each preceded by its length as a 32 bit big-endian
+/// integer. The value is the timestamp, in nanoseconds as
+/// a 64 bit big-endian integer, followed by the columns
+/// as they are stored.
+pub fn print_record_binary<W: std::io::Write>(
+	record: &crate::record::OwnedRecord,
+	out: &mut W,
+) -> std::io::Result<()>
+{
+	print_row_binary(record.key(), record.format(), record.value(), out)
+}
+
+/// Like [`print_record_binary`], for a record that's in pieces
+pub(crate) fn print_row_binary<W: std::io::Write>(
+	key: &str,
+	fmt_string: &str,
+	value: &[u8],
+	out: &mut W,
+) -> std::io::Result<()>
+{
+	use byteorder::{BigEndian, WriteBytesExt};
+	for part in &[key.as_bytes(), fmt_string.as_bytes(), value]
 	{
-		PrintRecordFormat::Yes =>
-			write!(out, "{}\t", fmt_string)?,
-		PrintRecordFormat::No => {},
+		out.write_u32::<BigEndian>(part.len() as u32)?;
+		out.write_all(part)?;
+	}
+	Ok(())
+}
+
+/// Read records written by [`print_record_binary`] from a stream
+/// and insert them into a transaction.
+///
+/// `db` and `nocheck` are as for [`add_from_stream`].
+pub fn add_from_binary_stream<R: std::io::Read>(
+	tx: &mut crate::CreateTx,
+	db: &crate::DatabaseReader,
+	input: &mut R,
+	nocheck: bool,
+) -> Result<(), crate::WriteFailure>
+{
+	use byteorder::{BigEndian, ReadBytesExt};
+	use std::io::Read;
+
+	let mut parts = [vec!(), vec!(), vec!()];
+	let mut key_format_identified = String::new();
+	let mut format_name = String::new();
+	let mut parsed_format = None;
+
+	loop
+	{
+		for (idx, part) in parts.iter_mut().enumerate()
+		{
+			let len = match input.read_u32::<BigEndian>()
+			{
+				Ok(len) => len as usize,
+				Err(e) if idx == 0 && e.kind() == std::io::ErrorKind::UnexpectedEof =>
+					return Ok(()),
+				Err(e) => return Err(e.into()),
+			};
+			// read with `take`, so a garbled length can't allocate
+			// more than the stream actually has
+			part.clear();
+			if input.by_ref().take(len as u64).read_to_end(part)? != len
+			{
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::UnexpectedEof,
+					"the stream ends in the middle of a record",
+				).into());
+			}
+		}
+
+		let key = std::str::from_utf8(&parts[0])
+			.map_err(|_| invalid("a key isn't UTF-8".to_string()))?;
+		let format = std::str::from_utf8(&parts[1])
+			.map_err(|_| invalid(format!("the format of {} isn't UTF-8", key)))?;
+		let value = &parts[2];
+		let size = row_format(format, &mut format_name, &mut parsed_format)?.row_size();
+		if value.len() < size
+		{
+			return Err(invalid(format!("a record of {} is too short for its format", key)));
+		}
+		if !format.contains('s') && value.len() != size
+		{
+			return Err(invalid(format!("a record of {} is too long for its format", key)));
+		}
+
+		check_format(db, nocheck, &mut key_format_identified, key, format)?;
+		tx.add_record(key, format, value)?;
 	}
+}
 
-	fmt.to_protocol_format(value, out)
+/// How to read and write CSV, for [`add_from_csv`] and [`CsvPrinter`].
+///
+/// The first row has the names of the columns. By default, the
+/// key is in the column named "key", the timestamp in "timestamp",
+/// and every other column is a value, one for each column of the
+/// record's format.
+#[derive(Debug,Clone)]
+pub struct CsvOptions
+{
+	delimiter: u8,
+	quote: u8,
+	quote_all: bool,
+	key_column: String,
+	timestamp_column: String,
+	format_column: Option<String>,
+	value_columns: Vec<String>,
+}
+
+/// Comma-separated, quoted with `"` when necessary
+impl Default for CsvOptions
+{
+	fn default() -> Self
+	{
+		CsvOptions
+		{
+			delimiter: b',',
+			quote: b'"',
+			quote_all: false,
+			key_column: "key".to_string(),
+			timestamp_column: "timestamp".to_string(),
+			format_column: None,
+			value_columns: vec!(),
+		}
+	}
 }
 
+impl CsvOptions
+{
+	/// The defaults
+	pub fn new() -> CsvOptions
+	{
+		Default::default()
+	}
+
+	/// Separate the columns with this instead of `,`
+	pub fn delimiter(mut self, delimiter: u8) -> CsvOptions
+	{
+		self.delimiter = delimiter;
+		self
+	}
+
+	/// Quote with this instead of `"`
+	pub fn quote(mut self, quote: u8) -> CsvOptions
+	{
+		self.quote = quote;
+		self
+	}
+
+	/// When writing, quote every column, not just the ones that need it
+	pub fn quote_all(mut self, quote_all: bool) -> CsvOptions
+	{
+		self.quote_all = quote_all;
+		self
+	}
+
+	/// The name of the column with the key
+	pub fn key_column(mut self, name: &str) -> CsvOptions
+	{
+		self.key_column = name.to_owned();
+		self
+	}
+
+	/// The name of the column with the timestamp
+	pub fn timestamp_column(mut self, name: &str) -> CsvOptions
+	{
+		self.timestamp_column = name.to_owned();
+		self
+	}
+
+	/// The name of the column with each record's format (like `ff`).
+	///
+	/// When writing, this column is written even without
+	/// [`PrintRecordFormat::Yes`].
+	pub fn format_column(mut self, name: &str) -> CsvOptions
+	{
+		self.format_column = Some(name.to_owned());
+		self
+	}
+
+	/// The names of the columns with the values, in the order
+	/// of the format's columns.
+	///
+	/// When reading, the other columns are ignored. When writing,
+	/// these are the names in the first row, instead of "value"
+	/// (or "value1", "value2" and so on).
+	pub fn value_columns<S: AsRef<str>>(mut self, names: &[S]) -> CsvOptions
+	{
+		self.value_columns = names.iter().map(|n| n.as_ref().to_owned()).collect();
+		self
+	}
+}
+
+/// Read records from CSV and insert them into a transaction.
+///
+/// The columns are named by the first row, and found according
+/// to `options`. Each record has `format`, or if it's `None`,
+/// the format in its [`format_column`](CsvOptions::format_column).
+/// `timestamp_format` and `nocheck` are as for [`add_from_stream`],
+/// and `bad_lines` as for [`add_from_stream2`]; rejected records are
+/// written as CSV, after the first row.
+#[allow(clippy::too_many_arguments)]
+pub fn add_from_csv<R: std::io::Read>(
+	tx: &mut crate::CreateTx,
+	db: &crate::DatabaseReader,
+	format: Option<&str>,
+	input: R,
+	timestamp_format: Option<&str>,
+	nocheck: bool,
+	options: &CsvOptions,
+	bad_lines: &mut BadLines,
+) -> Result<(), crate::WriteFailure>
+{
+	let mut reader = csv::ReaderBuilder::new()
+		.delimiter(options.delimiter)
+		.quote(options.quote)
+		.flexible(true)
+		.from_reader(input);
+
+	// a record as a line of CSV, for errors and the reject file
+	let to_line = |record: &csv::StringRecord| -> String
+	{
+		let mut writer = csv::WriterBuilder::new()
+			.delimiter(options.delimiter)
+			.quote(options.quote)
+			.flexible(true)
+			.from_writer(vec!());
+		let _ = writer.write_record(record);
+		let line = writer.into_inner().unwrap_or_default();
+		String::from_utf8_lossy(&line).trim_end_matches(&['\r', '\n'][..]).to_string()
+	};
+
+	let headers = reader.headers().map_err(std::io::Error::from)?.clone();
+	bad_lines.header(to_line(&headers));
+	let column = |name: &str|
+		headers.iter().position(|h| h == name)
+			.ok_or_else(|| invalid(format!("the CSV has no column named \"{}\"", name)));
+
+	let key_column = column(&options.key_column)?;
+	let timestamp_column = column(&options.timestamp_column)?;
+	let format_column = match options.format_column.as_ref()
+	{
+		Some(name) => Some(column(name)?),
+		None => None,
+	};
+	if format.is_none() && format_column.is_none()
+	{
+		return Err(invalid("a format or a format column is needed"));
+	}
+	let value_columns: Vec<usize> =
+		if options.value_columns.is_empty()
+		{
+			(0 .. headers.len())
+				.filter(|&c| c != key_column && c != timestamp_column && Some(c) != format_column)
+				.collect()
+		}
+		else
+		{
+			options.value_columns.iter()
+				.map(|name| column(name))
+				.collect::<Result<_, _>>()?
+		};
+
+	let mut row_format_name = String::new();
+	let mut parsed_format = None;
+	let mut values = String::new();
+	let mut row_data = vec!();
+	let mut key_format_identified = String::new();
+	let mut record = csv::StringRecord::new();
+
+	while reader.read_record(&mut record).map_err(std::io::Error::from)?
+	{
+		let mut add = || -> Result<(), crate::WriteFailure>
+		{
+			let field = |c: usize|
+				record.get(c)
+					.ok_or_else(|| invalid(format!("the column \"{}\" is missing", &headers[c])));
+
+			let key = field(key_column)?;
+			let ts = parse_timestamp(field(timestamp_column)?, timestamp_format)?;
+			let format = match format_column
+			{
+				Some(c) => field(c)?,
+				None => format.unwrap(),
+			};
+
+			values.clear();
+			for &c in &value_columns
+			{
+				if !values.is_empty()
+					{ values.push(' '); }
+				values.push_str(&escape_string::escape(field(c)?));
+			}
+			row_format(format, &mut row_format_name, &mut parsed_format)?
+				.to_stored_format(ts, &values, &mut row_data)
+				.map_err(invalid)?;
+			check_format(db, nocheck, &mut key_format_identified, key, format)?;
+			tx.add_record(key, format, &row_data)
+		};
+		let r = add();
+		row_data.clear();
+		if let Err(e) = r
+		{
+			let number = record.position().map(|p| p.line()).unwrap_or(0);
+			bad_lines.handle(number, &to_line(&record), e)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Write records as CSV, in the way that [`add_from_csv`] reads them.
+///
+/// The first row, with the names of the columns, is written
+/// with the first record, or by [`finish`](#method.finish) if
+/// there are none.
+pub struct CsvPrinter<'a, W: std::io::Write>
+{
+	writer: csv::Writer<W>,
+	options: CsvOptions,
+	print_timestamp: PrintTimestamp<'a>,
+	print_record_format: bool,
+	wrote_header: bool,
+	buffer: Vec<u8>,
+}
+
+impl<'a, W: std::io::Write> CsvPrinter<'a, W>
+{
+	/// Write to `out`.
+	///
+	/// The format is written in a column if `print_record_format`
+	/// is `Yes`, or the options have a format column.
+	pub fn new(
+		out: W,
+		options: &CsvOptions,
+		print_timestamp: PrintTimestamp<'a>,
+		print_record_format: PrintRecordFormat,
+	) -> CsvPrinter<'a, W>
+	{
+		let writer = csv::WriterBuilder::new()
+			.delimiter(options.delimiter)
+			.quote(options.quote)
+			.quote_style(
+				if options.quote_all
+					{ csv::QuoteStyle::Always }
+				else
+					{ csv::QuoteStyle::Necessary }
+			)
+			.flexible(true)
+			.from_writer(out);
+
+		CsvPrinter
+		{
+			writer,
+			options: options.clone(),
+			print_timestamp,
+			print_record_format: options.format_column.is_some()
+				|| matches!(print_record_format, PrintRecordFormat::Yes),
+			wrote_header: false,
+			buffer: vec!(),
+		}
+	}
+
+	fn write_header(&mut self, values: usize) -> std::io::Result<()>
+	{
+		self.wrote_header = true;
+		let mut header = vec!(
+			self.options.key_column.clone(),
+			self.options.timestamp_column.clone(),
+		);
+		if self.print_record_format
+		{
+			header.push(self.options.format_column.clone().unwrap_or_else(|| "format".to_string()));
+		}
+		if !self.options.value_columns.is_empty()
+			{ header.extend(self.options.value_columns.iter().cloned()); }
+		else if values == 1
+			{ header.push("value".to_string()); }
+		else
+			{ header.extend((1 ..= values).map(|n| format!("value{}", n))); }
+		self.writer.write_record(&header)?;
+		Ok(())
+	}
+
+	/// Write one record
+	pub fn print(&mut self, record: &crate::record::OwnedRecord) -> std::io::Result<()>
+	{
+		let fmt = parse_row_format(record.format());
+		let ts = byteorder::BigEndian::read_u64(&record.value()[0..8]);
+
+		let mut buffer = std::mem::take(&mut self.buffer);
+		buffer.clear();
+		fmt.to_protocol_format(&record.value()[8..], &mut buffer)?;
+		let values = std::str::from_utf8(&buffer)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+		let values = escape_string::split(values)
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "unparseable values"))?;
+
+		if !self.wrote_header
+			{ self.write_header(values.len())?; }
+
+		let mut timestamp = vec!();
+		write_timestamp(&mut timestamp, ts, self.print_timestamp)?;
+
+		self.writer.write_field(record.key())?;
+		self.writer.write_field(&timestamp)?;
+		if self.print_record_format
+			{ self.writer.write_field(record.format())?; }
+		for value in values.iter()
+		{
+			self.writer.write_field(value.as_bytes())?;
+		}
+		self.writer.write_record(None::<&[u8]>)?;
+		drop(values);
+		self.buffer = buffer;
+		Ok(())
+	}
+
+	/// Write the first row if there were no records, and flush
+	pub fn finish(mut self) -> std::io::Result<W>
+	{
+		if !self.wrote_header
+			{ self.write_header(1)?; }
+		self.writer.into_inner()
+			.map_err(|e| std::io::Error::new(e.error().kind(), e.to_string()))
+	}
+}
+
+/// Write a record as a line of JSON, in the way that [`add_from_jsonl`] reads it.
+///
+/// The record is an object like `{"key":"a","ts":1577836800000000000,"values":[1,"x"]}`,
+/// with the format in `"format"` if `print_record_format` is `Yes`. The
+/// timestamp is a number, unless it's printed with a format string. The
+/// newline isn't written.
+pub fn print_record_jsonl<W: std::io::Write>(
+	record: &crate::record::OwnedRecord,
+	out: &mut W,
+	print_timestamp: PrintTimestamp<'_>,
+	print_record_format: PrintRecordFormat,
+) -> std::io::Result<()>
+{
+	let fmt = parse_row_format(record.format());
+	let ts = byteorder::BigEndian::read_u64(&record.value()[0..8]);
+
+	write!(out, "{{\"key\":")?;
+	serde_json::to_writer(&mut *out, record.key())?;
+	write!(out, ",\"ts\":")?;
+	match print_timestamp
+	{
+		PrintTimestamp::FormatString(_) =>
+		{
+			let mut timestamp = vec!();
+			write_timestamp(&mut timestamp, ts, print_timestamp)?;
+			serde_json::to_writer(&mut *out, &String::from_utf8_lossy(&timestamp))?;
+		},
+		_ => write_timestamp(out, ts, print_timestamp)?,
+	}
+	if let PrintRecordFormat::Yes = print_record_format
+	{
+		write!(out, ",\"format\":")?;
+		serde_json::to_writer(&mut *out, record.format())?;
+	}
+	write!(out, ",\"values\":")?;
+	fmt.to_json_format(&record.value()[8..], out)?;
+	write!(out, "}}")
+}
+
+/// Read records from JSON lines, as written by [`print_record_jsonl`],
+/// and insert them into a transaction.
+///
+/// Each line is an object with `"key"`, `"ts"` and `"values"`.
+/// The timestamp is a number of nanoseconds, or a string in
+/// `timestamp_format`. The values are numbers or strings, one for each
+/// column of `format`, or if it's `None`, of the object's `"format"`.
+/// A `null` is read as NaN. `nocheck` is as for [`add_from_stream`],
+/// and `bad_lines` as for [`add_from_stream2`].
+pub fn add_from_jsonl<R: std::io::BufRead>(
+	tx: &mut crate::CreateTx,
+	db: &crate::DatabaseReader,
+	format: Option<&str>,
+	input: &mut R,
+	timestamp_format: Option<&str>,
+	nocheck: bool,
+	bad_lines: &mut BadLines,
+) -> Result<(), crate::WriteFailure>
+{
+	use serde_json::Value;
+
+	let mut line = String::new();
+	let mut number = 0;
+	let mut row_format_name = String::new();
+	let mut parsed_format = None;
+	let mut values = String::new();
+	let mut row_data = vec!();
+	let mut key_format_identified = String::new();
+
+	loop
+	{
+		line.clear();
+		if 0 == input.read_line(&mut line)?
+			{ return Ok(()); }
+		number += 1;
+		let text = line.trim_end();
+		if text.is_empty()
+			{ continue; }
+
+		let mut add = || -> Result<(), crate::WriteFailure>
+		{
+			let object: Value = serde_json::from_str(text)
+				.map_err(invalid)?;
+			let field = |name: &str|
+				object.get(name)
+					.ok_or_else(|| invalid(format!("there's no \"{}\"", name)));
+
+			let key = field("key")?.as_str()
+				.ok_or_else(|| invalid("\"key\" isn't a string"))?;
+			let ts: Timestamp = match field("ts")?
+			{
+				Value::Number(n) =>
+					n.as_u64()
+						.ok_or_else(|| invalid(format!("\"ts\" {} isn't a number of nanoseconds", n)))?,
+				Value::String(s) => parse_timestamp(s, timestamp_format)?,
+				_ => return Err(invalid("\"ts\" isn't a number or a string")),
+			};
+			let format = match format
+			{
+				Some(f) => f,
+				None => field("format")?.as_str()
+					.ok_or_else(|| invalid("\"format\" isn't a string"))?,
+			};
+
+			values.clear();
+			let array = field("values")?.as_array()
+				.ok_or_else(|| invalid("\"values\" isn't an array"))?;
+			for value in array
+			{
+				if !values.is_empty()
+					{ values.push(' '); }
+				match value
+				{
+					Value::Number(n) => values.push_str(&n.to_string()),
+					Value::String(s) => values.push_str(&escape_string::escape(s)),
+					Value::Null => values.push_str("NaN"),
+					v => return Err(invalid(format!("the value {} isn't a number or a string", v))),
+				}
+			}
+			row_format(format, &mut row_format_name, &mut parsed_format)?
+				.to_stored_format(ts, &values, &mut row_data)
+				.map_err(invalid)?;
+			check_format(db, nocheck, &mut key_format_identified, key, format)?;
+			tx.add_record(key, format, &row_data)
+		};
+		let r = add();
+		row_data.clear();
+		if let Err(e) = r
+		{
+			bad_lines.handle(number, text, e)?;
+		}
+	}
+}
@@ -0,0 +1,514 @@
+//! Check a database's files for corruption, and repair them.
+//!
+//! [`check_database`] walks every segment of every file from the
+//! start, instead of searching for them like a reader does, so
+//! that anything that isn't a valid segment gets noticed. It checks
+//! the segment headers, that the payloads decompress, that each
+//! key's records fit its format, and that the records are sorted,
+//! within each segment and from one segment to the next.
+//!
+//! [`repair_database`] rewrites each file that has a problem with
+//! the records of the segments that could be read, and keeps the
+//! original file as `corrupt.<name>`.
+
+use std::path::{Path,PathBuf};
+
+use byteorder::{ByteOrder,BigEndian};
+
+use crate::create_tx::CreateTx;
+use crate::database_reader::transaction_files;
+use crate::lock::{Lock,LockWait};
+use crate::segment::SEGMENT_INVOCATION;
+
+/// Something wrong with a file.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Problem
+{
+	/// The file
+	pub path: PathBuf,
+	/// Where in the file, usually the start of a segment
+	pub offset: u64,
+	/// What's wrong
+	pub description: String,
+}
+
+impl std::fmt::Display for Problem
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(f, "{}: at {}: {}", self.path.display(), self.offset, self.description)
+	}
+}
+
+/// What was found in one file.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct FileCheck
+{
+	/// The file
+	pub path: PathBuf,
+	/// How many segments it has
+	pub segments: u64,
+	/// How many records could be read
+	pub records: u64,
+	/// Everything that's wrong with it
+	pub problems: Vec<Problem>,
+}
+
+/// What was found in a database.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct CheckReport
+{
+	/// Each file, `main` first
+	pub files: Vec<FileCheck>,
+}
+
+impl CheckReport
+{
+	/// Returns true if nothing is wrong
+	pub fn is_ok(&self) -> bool
+	{
+		self.problems().next().is_none()
+	}
+
+	/// Everything that's wrong, file by file
+	pub fn problems(&self) -> impl Iterator<Item=&Problem>
+	{
+		self.files.iter().flat_map(|f| f.problems.iter())
+	}
+}
+
+impl std::fmt::Display for CheckReport
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(
+			f,
+			"checked {} files, {} segments, {} records: {} problems",
+			self.files.len(),
+			self.files.iter().map(|f| f.segments).sum::<u64>(),
+			self.files.iter().map(|f| f.records).sum::<u64>(),
+			self.problems().count(),
+		)
+	}
+}
+
+/// How a file was repaired.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Repaired
+{
+	/// The file that was rewritten
+	pub path: PathBuf,
+	/// Where the original is now
+	pub original: PathBuf,
+	/// How many records were kept
+	pub records: u64,
+	/// How many segments couldn't be read
+	pub dropped_segments: u64,
+	/// How many records were readable but out of order,
+	/// or didn't match their key's format
+	pub dropped_records: u64,
+}
+
+impl std::fmt::Display for Repaired
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(
+			f,
+			"repaired {}: kept {} records, dropped {} segments and {} records, \
+			the original is {}",
+			self.path.display(), self.records, self.dropped_segments,
+			self.dropped_records, self.original.display(),
+		)
+	}
+}
+
+/// Check every file of the database at `dir`.
+pub fn check_database(dir: &Path) -> std::io::Result<CheckReport>
+{
+	crate::database::check(dir)?;
+	let mut report = CheckReport::default();
+	for path in database_files(dir)?
+	{
+		report.files.push(check_file(&path)?);
+	}
+	Ok(report)
+}
+
+/// Check one file.
+pub fn check_file(path: &Path) -> std::io::Result<FileCheck>
+{
+	let mut check = FileCheck
+	{
+		path: path.to_owned(),
+		..Default::default()
+	};
+	let file = std::fs::File::open(path)?;
+	if file.metadata()?.len() == 0
+		{ return Ok(check); }
+	let map = unsafe { memmap::Mmap::map(&file)? };
+
+	let mut problems = vec!();
+	let mut last_header_key: Option<&[u8]> = None;
+	let mut last_payload_size = None;
+	let mut last_record: Option<(String, u64)> = None;
+	let mut decoded = vec!();
+
+	let garbage = walk(
+		&map,
+		|segment|
+		{
+			check.segments += 1;
+			let mut problem = |description: String| problems.push((segment.offset, description));
+
+			let first_key = std::str::from_utf8(segment.first_key);
+			let last_key = std::str::from_utf8(segment.last_key);
+			if first_key.is_err() || last_key.is_err()
+				{ problem("the segment header has a key that isn't UTF-8".to_string()); }
+			if segment.first_key > segment.last_key
+			{
+				problem(format!(
+					"the segment's first key {:?} is after its last key {:?}",
+					String::from_utf8_lossy(segment.first_key),
+					String::from_utf8_lossy(segment.last_key),
+				));
+			}
+			if let Some(previous) = last_header_key
+			{
+				if segment.last_key < previous
+				{
+					problem(format!(
+						"the segment's last key {:?} is before the previous segment's {:?}",
+						String::from_utf8_lossy(segment.last_key),
+						String::from_utf8_lossy(previous),
+					));
+				}
+			}
+			let expected_prev_size = last_payload_size.map(|s| s+32).unwrap_or(0);
+			if segment.prev_size != expected_prev_size
+			{
+				problem(format!(
+					"the segment header says the previous segment is {} bytes, not {}",
+					segment.prev_size, expected_prev_size,
+				));
+			}
+
+			let lowest = last_header_key;
+			last_header_key = Some(segment.last_key);
+			last_payload_size = Some(segment.payload.len());
+
+			if let Err(e) = crate::segment::decompress(segment.payload, &mut decoded)
+			{
+				problem(format!("the payload doesn't decompress: {}", e));
+				return;
+			}
+
+			let mut out_of_order = 0u64;
+			let mut first_out_of_order = None;
+			let r = crate::segment::for_each_key(
+				&decoded,
+				|key, format, rlen, data| -> std::io::Result<()>
+				{
+					if let Err(c) = check_format(format, rlen)
+					{
+						problem(format!("key {:?} has {}", key, c));
+					}
+					if key.as_bytes() > segment.last_key
+					{
+						problem(format!(
+							"key {:?} is after the segment's last key {:?}",
+							key, String::from_utf8_lossy(segment.last_key),
+						));
+					}
+					if lowest.map(|l| key.as_bytes() < l).unwrap_or(false)
+					{
+						problem(format!(
+							"key {:?} is before the previous segment's last key {:?}",
+							key, String::from_utf8_lossy(lowest.unwrap()),
+						));
+					}
+					for record in data.chunks(rlen)
+					{
+						let timestamp = BigEndian::read_u64(&record[0 .. 8]);
+						let in_order = match last_record.as_ref()
+						{
+							Some((k, ts)) => (&k[..], *ts) < (key, timestamp),
+							None => true,
+						};
+						if !in_order
+						{
+							out_of_order += 1;
+							if first_out_of_order.is_none()
+								{ first_out_of_order = Some((key.to_owned(), timestamp)); }
+						}
+						else if let Some((k, ts)) = last_record.as_mut()
+						{
+							if k != key
+								{ k.replace_range(.., key); }
+							*ts = timestamp;
+						}
+						else
+						{
+							last_record = Some((key.to_owned(), timestamp));
+						}
+						check.records += 1;
+					}
+					Ok(())
+				}
+			);
+			if let Err(e) = r
+			{
+				problem(format!("the payload is invalid: {}", e));
+			}
+			if let Some((key, timestamp)) = first_out_of_order
+			{
+				problem(format!(
+					"{} records are out of order, the first is key {:?} at {}",
+					out_of_order, key, timestamp,
+				));
+			}
+		},
+	);
+
+	problems.extend(garbage);
+	problems.sort_by_key(|p| p.0);
+	check.problems = problems.into_iter()
+		.map(
+			|(offset, description)| Problem
+			{
+				path: path.to_owned(),
+				offset: offset as u64,
+				description,
+			}
+		)
+		.collect();
+	Ok(check)
+}
+
+/// Rewrite the files of the database at `dir` that `report` found
+/// problems with.
+///
+/// Each is replaced by a file with the records of its segments
+/// that can be read, in order. The original is renamed `corrupt.<name>`,
+/// or `corrupt.<name>.1` and so on if that's taken by an earlier
+/// repair, which is never read. This holds the compaction lock.
+pub fn repair_database(dir: &Path, report: &CheckReport)
+	-> std::io::Result<Vec<Repaired>>
+{
+	let _lock = Lock::acquire(dir, "repair", LockWait::Forever)?;
+
+	let mut repaired = vec!();
+	for file in report.files.iter().filter(|f| !f.problems.is_empty())
+	{
+		repaired.push(repair_file(dir, &file.path)?);
+	}
+	Ok(repaired)
+}
+
+fn repair_file(dir: &Path, path: &Path) -> std::io::Result<Repaired>
+{
+	let name = path.file_name()
+		.ok_or_else(|| std::io::Error::other(format!("{} isn't a file", path.display())))?;
+	let mut original = dir.join(format!("corrupt.{}", name.to_string_lossy()));
+	for n in 1 ..
+	{
+		if !original.exists()
+			{ break; }
+		original = dir.join(format!("corrupt.{}.{}", name.to_string_lossy(), n));
+	}
+
+	let mut repaired = Repaired
+	{
+		path: path.to_owned(),
+		original: original.clone(),
+		records: 0,
+		dropped_segments: 0,
+		dropped_records: 0,
+	};
+
+	let file = std::fs::File::open(path)?;
+	let map = unsafe { memmap::Mmap::map(&file)? };
+
+	let mut tx = CreateTx::new(dir)?;
+	tx.verify_on_commit();
+	// the key, format, record length and timestamp last written
+	let mut last: Option<(String, String, usize, u64)> = None;
+	let mut decoded = vec!();
+	let mut error = None;
+
+	walk(
+		&map,
+		|segment|
+		{
+			if error.is_some()
+				{ return; }
+			// only a segment that's entirely readable is used
+			let readable = crate::segment::decompress(segment.payload, &mut decoded).is_ok()
+				&& crate::segment::for_each_key(
+					&decoded,
+					|_, format, rlen, _| check_format(format, rlen)
+						.map_err(std::io::Error::other)
+				).is_ok();
+			if !readable
+			{
+				repaired.dropped_segments += 1;
+				return;
+			}
+
+			let r = crate::segment::for_each_record(
+				&decoded,
+				|key, format, value| -> Result<(), crate::WriteFailure>
+				{
+					let timestamp = BigEndian::read_u64(&value[0 .. 8]);
+					let fits = match last.as_ref()
+					{
+						None => true,
+						Some((k, f, rlen, ts)) if k == key =>
+							f == format && *rlen == value.len() && *ts < timestamp,
+						Some((k, ..)) => &k[..] < key,
+					};
+					if !fits
+					{
+						repaired.dropped_records += 1;
+						return Ok(());
+					}
+					tx.add_record(key, format, value)?;
+					repaired.records += 1;
+					match last.as_mut()
+					{
+						Some((k, _, _, ts)) if k == key => *ts = timestamp,
+						_ => last = Some((key.to_owned(), format.to_owned(), value.len(), timestamp)),
+					}
+					Ok(())
+				}
+			);
+			if let Err(e) = r
+			{
+				error = Some(e);
+			}
+		},
+	);
+	if let Some(e) = error
+	{
+		return Err(std::io::Error::other(format!("rewriting {}: {:?}", path.display(), e)));
+	}
+	drop(map);
+
+	// the original keeps its data, even though `path` is replaced
+	std::fs::hard_link(path, &original)?;
+	tx.commit_to(path)?;
+	if repaired.records == 0 && name == "main"
+	{
+		// an empty `main` isn't written, so replace it with an empty
+		// file, by rename so that there's always a `main`
+		let empty = dir.join("main.empty.tmp");
+		std::fs::File::create(&empty)?.sync_all()?;
+		std::fs::rename(&empty, path)?;
+	}
+	Ok(repaired)
+}
+
+/// `main`, and then each transaction file
+fn database_files(dir: &Path) -> std::io::Result<Vec<PathBuf>>
+{
+	let mut files = vec!(dir.join("main"));
+	files.extend(transaction_files(dir)?);
+	Ok(files)
+}
+
+// a segment, as found by walking from the start of a file
+struct RawSegment<'d>
+{
+	offset: usize,
+	first_key: &'d [u8],
+	last_key: &'d [u8],
+	payload: &'d [u8],
+	prev_size: usize,
+}
+
+// call `segment` with each segment in `data`, returning
+// where there's anything that isn't a segment
+fn walk<'d, S>(data: &'d [u8], mut segment: S) -> Vec<(usize, String)>
+where
+	S: FnMut(RawSegment<'d>),
+{
+	let mut problems = vec!();
+	let mut problem = |pos, description| problems.push((pos, description));
+	let mut pos = 0;
+	while pos < data.len()
+	{
+		match twoway::find_bytes(&data[pos ..], SEGMENT_INVOCATION)
+		{
+			None =>
+			{
+				problem(pos, format!("{} bytes that aren't a segment at the end", data.len()-pos));
+				break;
+			},
+			Some(0) => {},
+			Some(skipped) =>
+			{
+				problem(pos, format!("{} bytes that aren't a segment", skipped));
+				pos += skipped;
+			},
+		}
+
+		let at = pos + SEGMENT_INVOCATION.len();
+		if data.len() - at < 16
+		{
+			problem(pos, "the segment header is truncated".to_string());
+			break;
+		}
+		let first_len = BigEndian::read_u32(&data[at .. at+4]) as usize;
+		let last_len = BigEndian::read_u32(&data[at+4 .. at+8]) as usize;
+		let payload_len = BigEndian::read_u32(&data[at+8 .. at+12]) as usize;
+		let prev_size = BigEndian::read_u32(&data[at+12 .. at+16]) as usize;
+		let at = at + 16;
+		if data.len() - at < first_len + last_len + payload_len
+		{
+			problem(
+				pos,
+				format!(
+					"the segment header's lengths ({}, {} and {} bytes) go past the end of the file",
+					first_len, last_len, payload_len,
+				),
+			);
+			// look for the next segment
+			pos = at;
+			continue;
+		}
+
+		segment(RawSegment
+		{
+			offset: pos,
+			first_key: &data[at .. at+first_len],
+			last_key: &data[at+first_len .. at+first_len+last_len],
+			payload: &data[at+first_len+last_len .. at+first_len+last_len+payload_len],
+			prev_size,
+		});
+		pos = at + first_len + last_len + payload_len;
+	}
+	problems
+}
+
+// check that `format` is valid and that its records
+// are `rlen` bytes long, if they're always the same size
+fn check_format(format: &str, rlen: usize) -> Result<(), String>
+{
+	let mut size = 8;
+	let mut fixed = true;
+	for c in format.chars()
+	{
+		match c
+		{
+			'i' | 'u' | 'f' => size += 4,
+			'I' | 'U' | 'F' => size += 8,
+			's' => fixed = false,
+			c => return Err(format!("the invalid format {:?} (with {:?})", format, c)),
+		}
+	}
+	if fixed && size != rlen
+	{
+		return Err(format!("{}-byte records, but its format {:?} has {}", rlen, format, size));
+	}
+	Ok(())
+}
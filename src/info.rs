@@ -0,0 +1,294 @@
+//! Statistics about a database's files, for `sonnerie info`.
+//!
+//! Getting them decompresses every segment of every file,
+//! so it takes about as long as reading the whole database.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::path::{Path,PathBuf};
+
+use crate::auto_compact::CompactionPolicy;
+use crate::database_reader::{DatabaseReader,transaction_files};
+use crate::segment::Segment;
+use crate::segment_reader::SegmentReader;
+
+/// Statistics about one file of a database.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct FileInfo
+{
+	/// The file
+	pub path: PathBuf,
+	/// Its size
+	pub bytes: u64,
+	/// The size of its segments' payloads, decompressed
+	pub uncompressed_bytes: u64,
+	/// How many segments it has
+	pub segments: u64,
+	/// How many keys it has
+	pub keys: u64,
+	/// How many records it has
+	pub records: u64,
+	/// The lowest key in it
+	pub first_key: Option<String>,
+	/// The highest key in it
+	pub last_key: Option<String>,
+}
+
+impl FileInfo
+{
+	/// How many times smaller the file is than its data
+	pub fn compression_ratio(&self) -> f64
+	{
+		if self.bytes == 0
+			{ 0.0 }
+		else
+			{ self.uncompressed_bytes as f64 / self.bytes as f64 }
+	}
+
+	fn write_json(&self, out: &mut String, path: bool)
+	{
+		out.push('{');
+		if path
+		{
+			write!(out, "\"path\":{},", json_string(&self.path.to_string_lossy())).unwrap();
+		}
+		write!(
+			out,
+			"\"bytes\":{},\"uncompressed_bytes\":{},\"compression_ratio\":{:.3},\
+			\"segments\":{},\"keys\":{},\"records\":{},\"first_key\":{},\"last_key\":{}",
+			self.bytes, self.uncompressed_bytes, self.compression_ratio(),
+			self.segments, self.keys, self.records,
+			self.first_key.as_deref().map(json_string).unwrap_or_else(|| "null".to_string()),
+			self.last_key.as_deref().map(json_string).unwrap_or_else(|| "null".to_string()),
+		).unwrap();
+		out.push('}');
+	}
+}
+
+impl std::fmt::Display for FileInfo
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(
+			f,
+			"{} bytes, {} segments, {} keys, {} records, {:.1}x compression",
+			self.bytes, self.segments, self.keys, self.records, self.compression_ratio(),
+		)?;
+		if let (Some(first), Some(last)) = (&self.first_key, &self.last_key)
+		{
+			write!(f, ", keys {:?} to {:?}", first, last)?;
+		}
+		Ok(())
+	}
+}
+
+/// Statistics about a database.
+///
+/// Get them with [`scan`](#method.scan).
+#[derive(Debug,Clone,PartialEq)]
+pub struct DatabaseInfo
+{
+	/// Each of the files that isn't empty, `main` first
+	pub files: Vec<FileInfo>,
+	/// The totals of `files`, except `keys`, which counts
+	/// each key once even if it's in more than one file
+	pub total: FileInfo,
+	/// How many transaction files there are
+	pub transactions: usize,
+	/// How many transaction files there can be before
+	/// a minor compaction is needed
+	pub max_transactions: usize,
+}
+
+impl DatabaseInfo
+{
+	/// Read all of the database at `dir`, comparing the
+	/// number of transactions to `policy`'s limit.
+	pub fn scan(dir: &Path, policy: &CompactionPolicy) -> std::io::Result<DatabaseInfo>
+	{
+		let transactions = transaction_files(dir)?.len();
+		let db = DatabaseReader::new(dir)?;
+
+		let mut files: Vec<FileKeys> = db.files()
+			.map(|(path, reader)| FileKeys::new(path, reader.segments()))
+			.collect();
+		let mut heads = vec!();
+		for file in files.iter_mut()
+		{
+			heads.push(file.next_key()?);
+		}
+
+		// merge the keys of each file to count each key once
+		let mut keys = 0;
+		while let Some(lowest) = heads.iter().flatten().min().cloned()
+		{
+			keys += 1;
+			for (head, file) in heads.iter_mut().zip(files.iter_mut())
+			{
+				if head.as_ref() == Some(&lowest)
+				{
+					*head = file.next_key()?;
+				}
+			}
+		}
+
+		let files: Vec<FileInfo> = files.into_iter().map(|f| f.info).collect();
+		let total = FileInfo
+		{
+			path: dir.to_owned(),
+			bytes: files.iter().map(|f| f.bytes).sum(),
+			uncompressed_bytes: files.iter().map(|f| f.uncompressed_bytes).sum(),
+			segments: files.iter().map(|f| f.segments).sum(),
+			keys,
+			records: files.iter().map(|f| f.records).sum(),
+			first_key: files.iter().filter_map(|f| f.first_key.clone()).min(),
+			last_key: files.iter().filter_map(|f| f.last_key.clone()).max(),
+		};
+
+		Ok(DatabaseInfo
+		{
+			files,
+			total,
+			transactions,
+			max_transactions: std::cmp::max(policy.max_transactions, 2),
+		})
+	}
+
+	/// Returns true if there are enough transactions
+	/// that a minor compaction is needed
+	pub fn too_many_transactions(&self) -> bool
+	{
+		self.transactions >= self.max_transactions
+	}
+
+	/// Format as a JSON object, for monitoring
+	pub fn to_json(&self) -> String
+	{
+		let mut out = String::new();
+		out.push_str("{\"files\":[");
+		for (idx, file) in self.files.iter().enumerate()
+		{
+			if idx != 0
+				{ out.push(','); }
+			file.write_json(&mut out, true);
+		}
+		out.push_str("],\"total\":");
+		self.total.write_json(&mut out, false);
+		write!(
+			out,
+			",\"transactions\":{},\"max_transactions\":{},\"too_many_transactions\":{}}}",
+			self.transactions, self.max_transactions, self.too_many_transactions(),
+		).unwrap();
+		out
+	}
+}
+
+impl std::fmt::Display for DatabaseInfo
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		for file in &self.files
+		{
+			let name = file.path.file_name()
+				.map(|n| n.to_string_lossy())
+				.unwrap_or_default();
+			writeln!(f, "{}: {}", name, file)?;
+		}
+		writeln!(f, "total: {} files, {}", self.files.len(), self.total)?;
+		write!(
+			f,
+			"transactions: {} of {}{}",
+			self.transactions, self.max_transactions,
+			if self.too_many_transactions() { ", a minor compaction is needed" } else { "" },
+		)
+	}
+}
+
+// the keys of one file, in order, counting as it goes
+struct FileKeys<'r>
+{
+	segments: &'r SegmentReader,
+	segment: Option<Segment<'r>>,
+	started: bool,
+	decoded: Vec<u8>,
+	keys: VecDeque<String>,
+	info: FileInfo,
+}
+
+impl<'r> FileKeys<'r>
+{
+	fn new(path: &Path, segments: &'r SegmentReader) -> FileKeys<'r>
+	{
+		FileKeys
+		{
+			segments,
+			segment: None,
+			started: false,
+			decoded: vec!(),
+			keys: VecDeque::new(),
+			info: FileInfo
+			{
+				path: path.to_owned(),
+				bytes: segments.len() as u64,
+				..Default::default()
+			},
+		}
+	}
+
+	fn next_key(&mut self) -> std::io::Result<Option<String>>
+	{
+		while self.keys.is_empty()
+		{
+			let next;
+			if !self.started
+			{
+				self.started = true;
+				next = self.segments.first();
+			}
+			else
+			{
+				match self.segment.as_ref()
+				{
+					Some(s) => next = self.segments.segment_after(s),
+					None => return Ok(None),
+				}
+			}
+			self.segment = next;
+			let segment = match self.segment.as_ref()
+			{
+				Some(s) => s,
+				None => return Ok(None),
+			};
+
+			crate::segment::decompress(segment.payload, &mut self.decoded)?;
+			self.info.segments += 1;
+			self.info.uncompressed_bytes += self.decoded.len() as u64;
+
+			let info = &mut self.info;
+			let keys = &mut self.keys;
+			crate::segment::for_each_key(
+				&self.decoded,
+				|key, _format, rlen, data| -> std::io::Result<()>
+				{
+					info.records += (data.len() / rlen) as u64;
+					// a key can continue from the previous segment
+					if info.last_key.as_deref() != Some(key)
+					{
+						info.keys += 1;
+						if info.first_key.is_none()
+							{ info.first_key = Some(key.to_owned()); }
+						info.last_key = Some(key.to_owned());
+						keys.push_back(key.to_owned());
+					}
+					Ok(())
+				}
+			)?;
+		}
+		Ok(self.keys.pop_front())
+	}
+}
+
+fn json_string(s: &str) -> String
+{
+	serde_json::to_string(s).unwrap()
+}
@@ -0,0 +1,441 @@
+//! Collect records from many writers and commit them together.
+//!
+//! Every transaction is its own file, and reading gets slow
+//! when there are many of them. An [`IngestBuffer`] accepts
+//! small batches of records from any number of producers and
+//! commits them as a single transaction once enough data
+//! has accumulated, or once the oldest batch has waited long enough.
+//!
+//! Each producer gets a [`Ticket`] that resolves once its
+//! data is on disk. A `Ticket` can be waited on by blocking
+//! or awaited as a `Future`.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context,Poll,Waker};
+use std::time::{Duration,Instant};
+
+use byteorder::{ByteOrder,BigEndian};
+use parking_lot::{Condvar,Mutex};
+
+use crate::create_tx::{CreateTx,CommitSummary};
+use crate::row_format::Timestamp;
+use crate::write::WriteFailure;
+
+/// When an [`IngestBuffer`] commits its records.
+///
+/// A commit happens as soon as any of the limits is reached.
+#[derive(Debug,Clone)]
+pub struct IngestOptions
+{
+	/// Commit when this many records are waiting
+	pub max_records: usize,
+	/// Commit when about this many bytes are waiting. Producers
+	/// are made to wait while the buffer is over this size.
+	pub max_bytes: usize,
+	/// Commit when the oldest waiting batch is this old
+	pub max_delay: Duration,
+}
+
+/// Up to a million records, 16MiB or one second
+impl Default for IngestOptions
+{
+	fn default() -> Self
+	{
+		IngestOptions
+		{
+			max_records: 1_000_000,
+			max_bytes: 16*1024*1024,
+			max_delay: Duration::from_secs(1),
+		}
+	}
+}
+
+/// A reason an ingested batch was not committed.
+#[derive(Debug,Clone)]
+pub enum IngestError
+{
+	/// A batch gave key `.0` the format `.2`, but `.1` was
+	/// already used for it.
+	HeterogeneousFormats(String, String, String),
+	/// Writing the transaction failed. Every producer whose
+	/// data was in it gets this error.
+	CommitFailed(String),
+	/// The buffer has been shut down.
+	Closed,
+}
+
+impl std::fmt::Display for IngestError
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			IngestError::HeterogeneousFormats(key, old, new) =>
+				write!(f, "key \"{}\" has format \"{}\", not \"{}\"", key, old, new),
+			IngestError::CommitFailed(e) =>
+				write!(f, "committing transaction: {}", e),
+			IngestError::Closed =>
+				write!(f, "the ingest buffer is closed"),
+		}
+	}
+}
+
+impl std::error::Error for IngestError { }
+
+struct Row
+{
+	format: String,
+	data: Vec<u8>,
+}
+
+/// Some records to be committed together.
+///
+/// Unlike with [`CreateTx`](../create_tx/struct.CreateTx.html),
+/// records don't need to be sorted. If the same key and timestamp
+/// is added more than once, the last one wins.
+#[derive(Default)]
+pub struct IngestBatch
+{
+	rows: Vec<(String, Row)>,
+	bytes: usize,
+}
+
+impl IngestBatch
+{
+	/// Create an empty batch
+	pub fn new() -> IngestBatch
+	{
+		Default::default()
+	}
+
+	/// Add a record with the given key, format, and payload.
+	///
+	/// The data must match the format and start with the
+	/// timestamp, as with [`CreateTx::add_record`](../create_tx/struct.CreateTx.html#method.add_record).
+	/// Fails if it's too short to have a timestamp.
+	pub fn add_record(&mut self, key: &str, format: &str, data: &[u8])
+		-> Result<(), WriteFailure>
+	{
+		crate::write::check_timestamp(key, data)?;
+		self.bytes += key.len() + format.len() + data.len();
+		self.rows.push(
+			(
+				key.to_owned(),
+				Row { format: format.to_owned(), data: data.to_owned() },
+			)
+		);
+		Ok(())
+	}
+
+	/// The number of records in this batch
+	pub fn len(&self) -> usize
+	{
+		self.rows.len()
+	}
+
+	/// Returns true if nothing was added to this batch
+	pub fn is_empty(&self) -> bool
+	{
+		self.rows.is_empty()
+	}
+
+	/// Approximately how much memory the records use
+	pub fn bytes(&self) -> usize
+	{
+		self.bytes
+	}
+}
+
+struct TicketState
+{
+	result: Option<Result<CommitSummary, IngestError>>,
+	wakers: Vec<Waker>,
+}
+
+struct TicketShared
+{
+	state: Mutex<TicketState>,
+	done: Condvar,
+}
+
+impl TicketShared
+{
+	fn new() -> Arc<TicketShared>
+	{
+		Arc::new(TicketShared
+		{
+			state: Mutex::new(TicketState { result: None, wakers: vec!() }),
+			done: Condvar::new(),
+		})
+	}
+
+	fn finish(&self, result: Result<CommitSummary, IngestError>)
+	{
+		let mut state = self.state.lock();
+		state.result = Some(result);
+		for w in state.wakers.drain(..)
+			{ w.wake(); }
+		self.done.notify_all();
+	}
+}
+
+/// Resolves once a submitted batch is committed.
+///
+/// Either call [`wait`](#method.wait) or `.await` it.
+pub struct Ticket
+{
+	shared: Arc<TicketShared>,
+}
+
+impl Ticket
+{
+	/// Block until the batch is committed, or failed to be.
+	///
+	/// The summary describes the whole transaction, which
+	/// may have had batches from other producers.
+	pub fn wait(self) -> Result<CommitSummary, IngestError>
+	{
+		let mut state = self.shared.state.lock();
+		while state.result.is_none()
+		{
+			self.shared.done.wait(&mut state);
+		}
+		state.result.clone().unwrap()
+	}
+}
+
+impl Future for Ticket
+{
+	type Output = Result<CommitSummary, IngestError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output>
+	{
+		let mut state = self.shared.state.lock();
+		if let Some(result) = state.result.as_ref()
+		{
+			return Poll::Ready(result.clone());
+		}
+		if !state.wakers.iter().any(|w| w.will_wake(cx.waker()))
+		{
+			state.wakers.push(cx.waker().clone());
+		}
+		Poll::Pending
+	}
+}
+
+struct Pending
+{
+	rows: BTreeMap<(String, Timestamp), Row>,
+	bytes: usize,
+	since: Option<Instant>,
+	ticket: Arc<TicketShared>,
+	closed: bool,
+}
+
+struct Shared
+{
+	pending: Mutex<Pending>,
+	// tells the committer there's something to do
+	wake: Condvar,
+	// tells producers that the buffer has room again
+	space: Condvar,
+	options: IngestOptions,
+}
+
+impl Shared
+{
+	fn is_full(&self, pending: &Pending) -> bool
+	{
+		pending.rows.len() >= self.options.max_records
+			|| pending.bytes >= self.options.max_bytes
+	}
+}
+
+/// Commits records from many producers as few transactions.
+///
+/// Create one with [`new`](#method.new) and share it between
+/// the producers, each of which calls [`submit`](#method.submit).
+///
+/// The records in one batch are always committed in the same
+/// transaction. Dropping the `IngestBuffer` commits whatever
+/// is still waiting.
+pub struct IngestBuffer
+{
+	shared: Arc<Shared>,
+	thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl IngestBuffer
+{
+	/// Start committing transactions into the database at `dir`.
+	pub fn new(dir: &Path, options: IngestOptions) -> IngestBuffer
+	{
+		let shared = Arc::new(Shared
+		{
+			pending: Mutex::new(Pending
+			{
+				rows: BTreeMap::new(),
+				bytes: 0,
+				since: None,
+				ticket: TicketShared::new(),
+				closed: false,
+			}),
+			wake: Condvar::new(),
+			space: Condvar::new(),
+			options,
+		});
+
+		let dir = dir.to_owned();
+		let committer_shared = shared.clone();
+		let thread = std::thread::Builder::new()
+			.name("sonnerie-ingest".to_string())
+			.spawn(move || committer(&dir, &committer_shared))
+			.expect("spawning ingest thread");
+
+		IngestBuffer
+		{
+			shared,
+			thread: Some(thread),
+		}
+	}
+
+	/// Queue a batch to be committed.
+	///
+	/// This blocks if the buffer is already over its
+	/// size limit. The returned `Ticket` resolves once
+	/// the batch is on disk.
+	///
+	/// The batch is rejected if it gives a key a different format
+	/// than one already waiting to be committed. That does not
+	/// check against the formats already in the database.
+	pub fn submit(&self, batch: IngestBatch)
+		-> Result<Ticket, IngestError>
+	{
+		let mut pending = self.shared.pending.lock();
+
+		while !pending.closed && !pending.rows.is_empty()
+			&& pending.bytes >= self.shared.options.max_bytes
+		{
+			self.shared.space.wait(&mut pending);
+		}
+		if pending.closed
+		{
+			return Err(IngestError::Closed);
+		}
+
+		// check the formats before changing anything, so that
+		// a batch is added completely or not at all
+		{
+			let mut formats: BTreeMap<&str, &str> = BTreeMap::new();
+			for (key, row) in &batch.rows
+			{
+				let existing = formats.get(&key[..]).cloned()
+					.or_else(
+						||
+						pending.rows
+							.range((key.clone(), 0) ..= (key.clone(), Timestamp::MAX))
+							.next()
+							.map(|(_, row)| &row.format[..])
+					);
+				match existing
+				{
+					Some(f) if f != row.format =>
+						return Err(IngestError::HeterogeneousFormats(
+							key.clone(), f.to_owned(), row.format.clone(),
+						)),
+					Some(_) => {},
+					None => { formats.insert(key, &row.format); },
+				}
+			}
+		}
+
+		pending.bytes += batch.bytes;
+		for (key, row) in batch.rows
+		{
+			let ts = BigEndian::read_u64(&row.data[0..8]);
+			let key_len = key.len();
+			// a row with the same key and timestamp replaces the waiting one
+			if let Some(old) = pending.rows.insert((key, ts), row)
+			{
+				pending.bytes -= key_len + old.format.len() + old.data.len();
+			}
+		}
+		if pending.since.is_none()
+		{
+			pending.since = Some(Instant::now());
+		}
+
+		let ticket = Ticket { shared: pending.ticket.clone() };
+		self.shared.wake.notify_all();
+		Ok(ticket)
+	}
+}
+
+impl Drop for IngestBuffer
+{
+	fn drop(&mut self)
+	{
+		self.shared.pending.lock().closed = true;
+		self.shared.wake.notify_all();
+		self.shared.space.notify_all();
+		if let Some(thread) = self.thread.take()
+		{
+			let _ = thread.join();
+		}
+	}
+}
+
+fn committer(dir: &Path, shared: &Shared)
+{
+	loop
+	{
+		let mut pending = shared.pending.lock();
+
+		while pending.rows.is_empty() && !pending.closed
+		{
+			shared.wake.wait(&mut pending);
+		}
+		if pending.rows.is_empty()
+		{
+			break; // closed
+		}
+
+		// wait for more data to come in
+		while !pending.closed && !shared.is_full(&pending)
+		{
+			let deadline = pending.since.unwrap() + shared.options.max_delay;
+			let now = Instant::now();
+			if now >= deadline { break; }
+			shared.wake.wait_for(&mut pending, deadline - now);
+		}
+
+		let rows = std::mem::take(&mut pending.rows);
+		let ticket = std::mem::replace(&mut pending.ticket, TicketShared::new());
+		pending.bytes = 0;
+		pending.since = None;
+		drop(pending);
+		shared.space.notify_all();
+
+		let result = commit_rows(dir, rows)
+			.map_err(IngestError::CommitFailed);
+		ticket.finish(result);
+	}
+}
+
+fn commit_rows(dir: &Path, rows: BTreeMap<(String, Timestamp), Row>)
+	-> Result<CommitSummary, String>
+{
+	let mut tx = CreateTx::new(dir)
+		.map_err(|e| format!("creating transaction: {}", e))?;
+	for ((key, _), row) in &rows
+	{
+		tx.add_record(key, &row.format, &row.data)
+			.map_err(|e| format!("adding {}: {:?}", key, e))?;
+	}
+	tx.commit()
+		.map_err(|e| format!("{}", e))
+}
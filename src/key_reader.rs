@@ -7,7 +7,7 @@ use byteorder::{ByteOrder,BigEndian};
 use std::io::Read;
 use std::ops::Bound;
 use std::ops::Bound::*;
-use std::rc::Rc;
+use std::sync::Arc;
 use crate::Wildcard;
 
 /// Read and filter keys from a single transaction file
@@ -33,6 +33,23 @@ impl Reader
 		)
 	}
 
+	/// Roughly how many bytes of the file come before `key`
+	pub(crate) fn bytes_before(&self, key: &str) -> u64
+	{
+		if self.segments.len() == 0
+			{ return 0; }
+		match self.segments.find(key.as_bytes())
+		{
+			Some(s) => s.pos as u64,
+			None => self.segments.len() as u64,
+		}
+	}
+
+	pub(crate) fn segments(&self) -> &SegmentReader
+	{
+		&self.segments
+	}
+
 	/// Get a reader for only a single key
 	///
 	/// Returns an object that will read all of the
@@ -57,28 +74,24 @@ impl Reader
 		RB: std::ops::RangeBounds<&'k str>
 	{
 		let mut data = vec!();
-		let segment;
-
-		match range.start_bound()
+		let segment = match range.start_bound()
 		{
 			Included(v) | Excluded(v) =>
-				segment = self.segments.find(v.as_bytes()),
+				self.segments.find(v.as_bytes()),
 			Unbounded =>
-				segment = self.segments.first(),
-		}
+				self.segments.first(),
+		};
 
 
 		if let Some(d) = segment.as_ref()
 		{
 			{
 				// don't do posix_fadvise if we're looking up a single key
-				let do_advise;
-				match (range.start_bound(), range.end_bound())
+				let do_advise = match (range.start_bound(), range.end_bound())
 				{
-					(Included(v1), Included(v2)) =>
-						do_advise = v1 != v2,
-					_ => do_advise = true,
-				}
+					(Included(v1), Included(v2)) => v1 != v2,
+					_ => true,
+				};
 				if do_advise
 				{
 					self.segments.advise(d);
@@ -95,9 +108,9 @@ impl Reader
 		{
 			reader: self,
 			range,
-			decoded: Rc::new(data),
+			decoded: Arc::new(data),
 			pos: 0,
-			segment: segment,
+			segment,
 			current_key_len: 0,
 			current_key_pos: 0,
 			current_fmt_len: 0,
@@ -123,6 +136,34 @@ impl Reader
 		filter
 	}
 
+	/// Get a reader for the keys in a lexicographic range
+	/// that also match a wildcard filter.
+	///
+	/// It's as efficient as the narrower of the two.
+	pub fn get_range_filter<'rdr, 'k, RB>(&'rdr self, range: RB, wildcard: &'k Wildcard)
+		-> StringKeyRangeReader<'rdr, 'k, (Bound<&'k str>, Bound<&'k str>)>
+	where
+		RB: std::ops::RangeBounds<&'k str>
+	{
+		let prefix = wildcard.prefix();
+		let start = match range.start_bound()
+		{
+			Included(&v) if v >= prefix => Included(v),
+			Excluded(&v) if v >= prefix => Excluded(v),
+			_ => Included(prefix),
+		};
+		let end = match range.end_bound()
+		{
+			Included(&v) => Included(v),
+			Excluded(&v) => Excluded(v),
+			Unbounded => Unbounded,
+		};
+		let mut filter = self.get_range((start, end));
+		filter.prefix = prefix;
+		filter.matcher = wildcard.as_regex();
+		filter
+	}
+
 	/// Print diagnostic information about this transaction file.
 	///
 	/// This function is for debugging only.
@@ -141,7 +182,7 @@ where
 {
 	reader: &'rdr Reader,
 	range: RB,
-	decoded: Rc<Vec<u8>>,
+	decoded: Arc<Vec<u8>>,
 	pos: usize,
 	current_key_pos: usize,
 	current_key_len: usize,
@@ -168,9 +209,9 @@ where
 
 		if let Some(s) = self.segment.as_ref()
 		{
-			let reuse_vec = std::mem::replace(&mut self.decoded, Rc::new(vec!()));
+			let reuse_vec = std::mem::replace(&mut self.decoded, Arc::new(vec!()));
 			let mut old_vec;
-			if let Ok(maybe_old_vec) = Rc::try_unwrap(reuse_vec)
+			if let Ok(maybe_old_vec) = Arc::try_unwrap(reuse_vec)
 				{ old_vec = maybe_old_vec; }
 			else
 				{ old_vec = vec!(); }
@@ -179,7 +220,7 @@ where
 				.expect("lz4 decoding");
 			decoder.read_to_end(&mut old_vec)
 				.expect("lz4 decoding 2");
-			self.decoded = Rc::new(old_vec);
+			self.decoded = Arc::new(old_vec);
 		}
 	}
 
@@ -196,10 +237,10 @@ where
 				let dlen = BigEndian::read_u32(&data[self.pos+12 .. self.pos+16]) as usize;
 
 				let key = &data[self.pos+16 .. self.pos+16+klen];
-				let key = std::str::from_utf8(&key)
+				let key = std::str::from_utf8(key)
 					.expect("input data is not utf8");
 				let fmt = &data[self.pos+16+klen .. self.pos+16+klen+flen];
-				let _fmt = std::str::from_utf8(&fmt)
+				let _fmt = std::str::from_utf8(fmt)
 					.expect("input data is not utf8");
 
 				self.current_key_pos = self.pos+16;
@@ -231,6 +272,14 @@ where
 					Unbounded => {},
 				}
 
+				// the keys after the ones with the prefix can't match
+				if !key.starts_with(self.prefix)
+				{
+					self.pos = data.len();
+					self.segment = None;
+					return false;
+				}
+
 				match self.range.end_bound()
 				{
 					Bound::Included(&v) =>
@@ -251,15 +300,7 @@ where
 							return false;
 						}
 					},
-					Unbounded =>
-					{
-						if !key.starts_with(self.prefix)
-						{
-							self.pos = data.len();
-							self.segment = None;
-							return false;
-						}
-					},
+					Unbounded => {},
 				}
 
 				if let Some(regex) = self.matcher.as_ref()
@@ -290,30 +331,28 @@ where
 	type Item = OwnedRecord;
 	fn next(&mut self) -> Option<Self::Item>
 	{
-		while self.segment.is_some()
+		self.segment.as_ref()?;
+		if self.pos == self.current_key_pos
+			+ self.current_key_data_len
+			+ self.current_key_len
+			+ self.current_fmt_len
+			&& !self.next_key()
 		{
-			if self.pos == self.current_key_pos
-				+ self.current_key_data_len
-				+ self.current_key_len
-				+ self.current_fmt_len
-			{
-				if !self.next_key() { return None; }
-			}
-
-			let r =
-				OwnedRecord
-				{
-					key_pos: self.current_key_pos,
-					key_len: self.current_key_len,
-					fmt_pos: self.current_fmt_pos,
-					fmt_len: self.current_fmt_len,
-					value_pos: self.pos,
-					value_len: self.current_key_record_len,
-					data: self.decoded.clone(),
-				};
-			self.pos += self.current_key_record_len;
-			return Some(r);
+			return None;
 		}
-		None
+
+		let r =
+			OwnedRecord
+			{
+				key_pos: self.current_key_pos,
+				key_len: self.current_key_len,
+				fmt_pos: self.current_fmt_pos,
+				fmt_len: self.current_fmt_len,
+				value_pos: self.pos,
+				value_len: self.current_key_record_len,
+				data: self.decoded.clone(),
+			};
+		self.pos += self.current_key_record_len;
+		Some(r)
 	}
 }
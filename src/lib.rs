@@ -9,6 +9,21 @@ pub mod row_format;
 pub(crate) mod merge;
 pub(crate) mod database_reader;
 pub mod wildcard;
+pub mod ingest;
+pub mod compact;
+pub mod auto_compact;
+pub mod retention;
+pub mod rollup;
+pub mod transform;
+pub mod lock;
+pub mod database;
+pub mod info;
+pub mod fsck;
+pub mod sort;
+pub mod snapshot;
+pub mod diff;
+#[cfg(feature="async")]
+pub mod nonblocking;
 
 pub use write::WriteFailure;
 
@@ -19,6 +34,19 @@ pub use formatted::*;
 pub use row_format::*;
 pub use wildcard::*;
 pub use database_reader::*;
+pub use ingest::*;
+pub use compact::*;
+pub use auto_compact::*;
+pub use retention::*;
+pub use rollup::*;
+pub use transform::*;
+pub use lock::*;
+pub use sort::*;
+pub use snapshot::*;
+pub use diff::*;
+pub use database::*;
+pub use info::*;
+pub use fsck::*;
 
 #[cfg(test)] mod tests;
 
@@ -0,0 +1,255 @@
+//! The lock that keeps compactions from running at the same time.
+//!
+//! A compaction, or a commit that replaces an empty `main`, holds an
+//! exclusive lock on the file `.compact` in the database directory.
+//! While it does, the file says who holds it:
+//!
+//! ```text
+//! pid 1234
+//! host db1
+//! started 1600000000
+//! kind major compaction
+//! ```
+//!
+//! The start time is in seconds since the epoch. Use [`lock_holder`]
+//! to find out if anything holds the lock.
+
+use std::fs::File;
+use std::io::{Read,Seek,Write};
+use std::path::Path;
+use std::time::{Duration,Instant,SystemTime};
+
+use fs2::FileExt;
+
+/// The name of the lock file in the database directory
+pub const LOCK_FILE: &str = ".compact";
+
+/// How long to wait for the lock if something else holds it.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LockWait
+{
+	/// Until it's released
+	Forever,
+	/// Not at all, fail immediately
+	No,
+	/// Fail if it's still held after this long
+	Timeout(Duration),
+}
+
+/// Wait forever
+impl Default for LockWait
+{
+	fn default() -> Self
+	{
+		LockWait::Forever
+	}
+}
+
+/// Who holds the lock.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct LockInfo
+{
+	/// The process id of the holder
+	pub pid: u32,
+	/// The host it's running on
+	pub host: String,
+	/// When it took the lock
+	pub started: SystemTime,
+	/// What it's doing, like "major compaction"
+	pub kind: String,
+}
+
+impl LockInfo
+{
+	fn current(kind: &str) -> LockInfo
+	{
+		LockInfo
+		{
+			pid: std::process::id(),
+			host: hostname(),
+			started: SystemTime::now(),
+			kind: kind.to_owned(),
+		}
+	}
+
+	fn parse(text: &str) -> Option<LockInfo>
+	{
+		let mut pid = None;
+		let mut host = None;
+		let mut started = None;
+		let mut kind = None;
+		for line in text.lines()
+		{
+			let (name, value) = line.split_once(' ')?;
+			match name
+			{
+				"pid" => pid = value.parse().ok(),
+				"host" => host = Some(value.to_owned()),
+				"started" => started = value.parse().ok()
+					.map(|s| SystemTime::UNIX_EPOCH + Duration::from_secs(s)),
+				"kind" => kind = Some(value.to_owned()),
+				_ => {},
+			}
+		}
+		Some(LockInfo
+		{
+			pid: pid?,
+			host: host?,
+			started: started?,
+			kind: kind?,
+		})
+	}
+
+	/// How long it's been held
+	pub fn age(&self) -> Duration
+	{
+		SystemTime::now().duration_since(self.started)
+			.unwrap_or_default()
+	}
+}
+
+impl std::fmt::Display for LockInfo
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		let started = self.started.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap_or_default();
+		let started = chrono::DateTime::from_timestamp(started.as_secs() as i64, 0)
+			.unwrap_or_default()
+			.naive_utc();
+		write!(
+			f,
+			"{} by pid {} on {} since {} ({}s)",
+			self.kind, self.pid, self.host,
+			started.format("%F %T"), self.age().as_secs(),
+		)
+	}
+}
+
+fn hostname() -> String
+{
+	let mut buf = [0u8; 256];
+	let r = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+	if r != 0
+		{ return "unknown".to_string(); }
+	let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+	String::from_utf8_lossy(&buf[.. len]).into_owned()
+}
+
+fn open(dir: &Path) -> std::io::Result<File>
+{
+	// not truncated, that would erase the holder's information
+	std::fs::OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(false)
+		.open(dir.join(LOCK_FILE))
+}
+
+/// Find out what holds the lock on the database at `dir`,
+/// `None` if nothing does.
+pub fn lock_holder(dir: &Path) -> std::io::Result<Option<LockInfo>>
+{
+	// only read, so that it works in a directory that isn't writable
+	let mut file = match File::open(dir.join(LOCK_FILE))
+	{
+		Ok(file) => file,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e),
+	};
+	if file.try_lock_shared().is_ok()
+	{
+		file.unlock()?;
+		return Ok(None);
+	}
+	let mut text = String::new();
+	file.read_to_string(&mut text)?;
+	Ok(Some(
+		LockInfo::parse(&text)
+			.unwrap_or_else(
+				// an older version, or it's still writing
+				|| LockInfo
+				{
+					pid: 0,
+					host: "unknown".to_string(),
+					started: SystemTime::UNIX_EPOCH,
+					kind: "unknown".to_string(),
+				}
+			)
+	))
+}
+
+/// Holds the lock until dropped.
+pub(crate) struct Lock
+{
+	file: File,
+}
+
+impl Lock
+{
+	/// Take the lock, to do `kind` of thing.
+	///
+	/// If the lock isn't available in time, fails with
+	/// `WouldBlock` (or `TimedOut`), saying who holds it.
+	pub(crate) fn acquire(dir: &Path, kind: &str, wait: LockWait)
+		-> std::io::Result<Lock>
+	{
+		let mut file = open(dir)?;
+		match wait
+		{
+			LockWait::Forever => file.lock_exclusive()?,
+			LockWait::No =>
+				if file.try_lock_exclusive().is_err()
+				{
+					return Err(held(dir, std::io::ErrorKind::WouldBlock));
+				},
+			LockWait::Timeout(timeout) =>
+			{
+				let started = Instant::now();
+				while file.try_lock_exclusive().is_err()
+				{
+					if started.elapsed() >= timeout
+					{
+						return Err(held(dir, std::io::ErrorKind::TimedOut));
+					}
+					std::thread::sleep(Duration::from_millis(100).min(timeout));
+				}
+			},
+		}
+
+		let info = LockInfo::current(kind);
+		let started = info.started.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap_or_default();
+		file.set_len(0)?;
+		file.seek(std::io::SeekFrom::Start(0))?;
+		write!(
+			file,
+			"pid {}\nhost {}\nstarted {}\nkind {}\n",
+			info.pid, info.host, started.as_secs(), info.kind,
+		)?;
+		Ok(Lock { file })
+	}
+}
+
+impl Drop for Lock
+{
+	fn drop(&mut self)
+	{
+		let _ = self.file.set_len(0);
+		let _ = self.file.unlock();
+	}
+}
+
+fn held(dir: &Path, kind: std::io::ErrorKind) -> std::io::Error
+{
+	let holder = match lock_holder(dir)
+	{
+		Ok(Some(info)) => info.to_string(),
+		_ => "another process".to_string(),
+	};
+	std::io::Error::new(
+		kind,
+		format!("the database is locked: {}", holder),
+	)
+}
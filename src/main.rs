@@ -1,6 +1,5 @@
 use sonnerie::formatted;
 use std::path::Path;
-use std::fs::File;
 use sonnerie::*;
 use std::io::Write;
 
@@ -16,7 +15,7 @@ fn main()
 			.arg(Arg::with_name("dir")
 				.long("dir")
 				.short("d")
-				.help("store data here in this directory. Create a \"main\" file here first.")
+				.help("store data here in this directory. Create it first with \"init\".")
 				.required(true)
 				.takes_value(true)
 			)
@@ -26,18 +25,68 @@ fn main()
 					.arg(Arg::with_name("format")
 						.short("f")
 						.long("format")
+						.help("the format of every record (for csv and jsonl, \
+							otherwise it's in each record)")
 						.takes_value(true)
-						.required(true)
+						.required_unless("input-format")
 					)
 					.arg(Arg::with_name("timestamp-format")
 						.long("timestamp-format")
 						.help("instead of nanoseconds since the epoch, use this strftime format")
 						.takes_value(true)
 					)
+					.arg(Arg::with_name("input-format")
+						.long("input-format")
+						.help("read lines of text (the default), CSV with a header row, \
+							or a JSON object on each line")
+						.takes_value(true)
+						.possible_values(&["text", "csv", "jsonl"])
+					)
 					.arg(Arg::with_name("unsafe-nocheck")
 						.long("unsafe-nocheck")
 						.help("suppress the format coherency check (makes insertions faster)")
 					)
+					.arg(Arg::with_name("verbose")
+						.long("verbose")
+						.short("v")
+						.help("print what was committed")
+					)
+					.arg(Arg::with_name("unsorted")
+						.long("unsorted")
+						.help("accept records in any order, sorting them before \
+							committing (if a key and timestamp is repeated, \
+							the last one wins)")
+					)
+					.arg(Arg::with_name("sort-memory")
+						.long("sort-memory")
+						.help("with --unsorted, sort up to this many bytes in memory \
+							before writing to temporary files (default 256MiB)")
+						.takes_value(true)
+						.requires("unsorted")
+					)
+					.arg(Arg::with_name("spill-dir")
+						.long("spill-dir")
+						.help("with --unsorted, write temporary files here \
+							instead of the system's temporary directory")
+						.takes_value(true)
+						.requires("unsorted")
+					)
+					.arg(Arg::with_name("on-error")
+						.long("on-error")
+						.help("what to do with a line that can't be added: stop \
+							without adding anything (the default), skip it, or \
+							skip it and say why on stderr")
+						.takes_value(true)
+						.possible_values(&["abort", "skip", "log"])
+					)
+					.arg(Arg::with_name("reject-file")
+						.long("reject-file")
+						.help("with --on-error skip or log, write the lines that \
+							are skipped to this file")
+						.takes_value(true)
+						.requires("on-error")
+					)
+					.args(&csv_args())
 			)
 			.subcommand(
 				SubCommand::with_name("compact")
@@ -47,6 +96,125 @@ fn main()
 						.long("major")
 						.help("compact everything into a new main database")
 					)
+					.arg(Arg::with_name("incremental")
+						.long("incremental")
+						.help("with --major, copy the parts of the main database \
+							that no transaction changes instead of rewriting them")
+						.requires("major")
+						.conflicts_with("gegnum")
+					)
+					.arg(Arg::with_name("auto")
+						.long("auto")
+						.help("run forever, doing minor and major compactions \
+							whenever they are needed")
+						.conflicts_with("major")
+						.conflicts_with("gegnum")
+					)
+					.arg(Arg::with_name("max-transactions")
+						.long("max-transactions")
+						.help("with --auto, do a minor compaction when there \
+							are this many transactions (default 16)")
+						.takes_value(true)
+						.requires("auto")
+					)
+					.arg(Arg::with_name("major-interval")
+						.long("major-interval")
+						.help("with --auto, do a major compaction when there \
+							hasn't been one for this many seconds (default 86400)")
+						.takes_value(true)
+						.requires("auto")
+					)
+					.arg(Arg::with_name("no-wait")
+						.long("no-wait")
+						.help("if another compaction is running, fail \
+							(with exit status 75) instead of waiting for it")
+						.conflicts_with("auto")
+					)
+					.arg(Arg::with_name("wait-timeout")
+						.long("wait-timeout")
+						.help("if another compaction is running, wait for it for \
+							up to this many seconds, then fail (with exit status 75)")
+						.takes_value(true)
+						.conflicts_with_all(&["auto", "no-wait"])
+					)
+					.arg(Arg::with_name("verify")
+						.long("verify")
+						.help("read the new file back and check it before \
+							deleting anything")
+					)
+					.arg(Arg::with_name("progress")
+						.long("progress")
+						.help("print the progress every few seconds")
+					)
+					.arg(Arg::with_name("max-bytes-per-second")
+						.long("max-bytes-per-second")
+						.help("slow down to read and write no more than this \
+							many bytes per second")
+						.takes_value(true)
+					)
+					.arg(Arg::with_name("oldest")
+						.long("oldest")
+						.help("only merge this many of the oldest transactions")
+						.takes_value(true)
+						.conflicts_with_all(&["major", "auto", "smaller-than", "transaction"])
+					)
+					.arg(Arg::with_name("smaller-than")
+						.long("smaller-than")
+						.help("only merge the longest run of consecutive \
+							transactions that are each smaller than this many bytes")
+						.takes_value(true)
+						.conflicts_with_all(&["major", "auto", "transaction"])
+					)
+					.arg(Arg::with_name("transaction")
+						.long("transaction")
+						.help("only merge this transaction file, can be repeated. \
+							The transactions must be consecutive.")
+						.takes_value(true)
+						.multiple(true)
+						.number_of_values(1)
+						.conflicts_with_all(&["major", "auto"])
+					)
+					.arg(Arg::with_name("delete")
+						.long("delete")
+						.help("with --major, delete the keys that match this wildcard, \
+							can be repeated")
+						.takes_value(true)
+						.multiple(true)
+						.number_of_values(1)
+						.requires("major")
+						.conflicts_with("incremental")
+					)
+					.arg(Arg::with_name("delete-range")
+						.long("delete-range")
+						.help("with --major, delete the records of the keys that match \
+							the wildcard from the first timestamp up to (but not including) \
+							the second, can be repeated")
+						.value_names(&["wildcard", "from", "to"])
+						.multiple(true)
+						.number_of_values(3)
+						.requires("major")
+						.conflicts_with("incremental")
+					)
+					.arg(Arg::with_name("keep")
+						.long("keep")
+						.help("with --major, delete the keys that don't match this \
+							wildcard (or any of them, if repeated)")
+						.takes_value(true)
+						.multiple(true)
+						.number_of_values(1)
+						.requires("major")
+						.conflicts_with("incremental")
+					)
+					.arg(Arg::with_name("rename")
+						.long("rename")
+						.help("with --major, rename the keys that start with \"old\" \
+							to start with \"new\" instead, can be repeated")
+						.value_name("old=new")
+						.multiple(true)
+						.number_of_values(1)
+						.requires("major")
+						.conflicts_with("incremental")
+					)
 					.arg(Arg::with_name("gegnum")
 						.long("gegnum")
 						.help("Run this command, writing compacted data as if by \"read\" \
@@ -57,11 +225,21 @@ fn main()
 							use this with --major to get the entire database.")
 						.takes_value(true)
 					)
-					.arg(Arg::with_name("timestamp-format")
-						.long("timestamp-format")
-						.help("with --gegnum, instead of nanoseconds since the epoch, use this strftime format")
+					.arg(Arg::with_name("gegnum-format")
+						.long("gegnum-format")
+						.help("with --gegnum, how to write and read the records: \
+							\"text\" (the default), \"exact\" (text with timestamps \
+							in nanoseconds and exact floats) or \"binary\" (each of \
+							the key, format and stored value preceded by its length \
+							as a 32 bit big-endian integer)")
 						.takes_value(true)
+						.possible_values(&["text", "exact", "binary"])
 						.requires("gegnum")
+					)
+					.arg(Arg::with_name("timestamp-format")
+						.long("timestamp-format")
+						.help("with --gegnum or --delete-range, use this strftime \
+							format for timestamps (default %FT%T)")
 						.takes_value(true)
 					)
 					.arg(Arg::with_name("unsafe-nocheck")
@@ -70,6 +248,79 @@ fn main()
 						.requires("gegnum")
 					)
 			)
+			.subcommand(
+				SubCommand::with_name("init")
+					.about("create an empty database (and its directory)")
+			)
+			.subcommand(
+				SubCommand::with_name("status")
+					.about("show the database's files and whether a compaction is running")
+			)
+			.subcommand(
+				SubCommand::with_name("info")
+					.about("show statistics about each file of the database (reads all of it)")
+					.arg(Arg::with_name("json")
+						.long("json")
+						.help("print the statistics as JSON")
+					)
+					.arg(Arg::with_name("max-transactions")
+						.long("max-transactions")
+						.help("compare the number of transactions to this (default 16, \
+							as for compact --auto)")
+						.takes_value(true)
+					)
+			)
+			.subcommand(
+				SubCommand::with_name("verify")
+					.about("check every file of the database for corruption (reads all of it)")
+					.arg(Arg::with_name("repair")
+						.long("repair")
+						.help("rewrite the corrupt files without what can't be read, \
+							keeping the originals as \"corrupt.<name>\", \
+							and exit with status 2")
+					)
+			)
+			.subcommand(
+				SubCommand::with_name("snapshot")
+					.about("make a consistent copy of the database, hard-linking \
+						its files where it can, while no compaction runs")
+					.arg(Arg::with_name("dest")
+						.help("the new directory to put the snapshot in")
+						.required(true)
+					)
+			)
+			.subcommand(
+				SubCommand::with_name("diff")
+					.about("compare the records of the database (the old one) to another \
+						(the new one): \"-\" is a removed record, \"+\" an added one, \
+						and \"<\" and \">\" the old and new versions of a changed one. \
+						Exits with 1 if they differ")
+					.arg(Arg::with_name("other")
+						.help("the other database's directory")
+						.required(true)
+					)
+					.arg(Arg::with_name("filter")
+						.help("only compare these keys, \"%\" is the wildcard")
+						.default_value("%")
+					)
+					.arg(Arg::with_name("summary")
+						.long("summary")
+						.help("print how many records of each key changed instead")
+					)
+					.arg(Arg::with_name("timestamp-nanos")
+						.long("timestamp-nanos")
+						.help("Print timestamps as nanoseconds since the unix epoch")
+					)
+			)
+			.subcommand(
+				SubCommand::with_name("restore")
+					.about("make the database (which must not exist yet) \
+						from a snapshot")
+					.arg(Arg::with_name("snapshot")
+						.help("the snapshot's directory")
+						.required(true)
+					)
+			)
 			.subcommand(
 				SubCommand::with_name("read")
 					.about("reads records")
@@ -77,7 +328,6 @@ fn main()
 						.help("select the keys to print out, \"%\" is the wildcard")
 						.takes_value(true)
 						.required_unless_one(&["before", "after"])
-
 					)
 					.arg(Arg::with_name("print-format")
 						.long("print-format")
@@ -103,40 +353,409 @@ fn main()
 						.long("before")
 						.help("read values before (but not including) this key")
 						.takes_value(true)
-						.conflicts_with("filter")
 					)
 					.arg(Arg::with_name("after")
 						.long("after")
 						.help("read values after (and including) this key")
 						.takes_value(true)
-						.conflicts_with("filter")
 					)
+					.arg(Arg::with_name("from")
+						.long("from")
+						.help("read values at (and after) this time, \
+							in --timestamp-format or nanoseconds")
+						.takes_value(true)
+					)
+					.arg(Arg::with_name("to")
+						.long("to")
+						.help("read values before (but not at) this time, \
+							in --timestamp-format or nanoseconds")
+						.takes_value(true)
+					)
+					.arg(Arg::with_name("output-format")
+						.long("output-format")
+						.help("write lines of text (the default), CSV with a header row, \
+							or a JSON object on each line")
+						.takes_value(true)
+						.possible_values(&["text", "csv", "jsonl"])
+					)
+					.arg(Arg::with_name("csv-quote-all")
+						.long("csv-quote-all")
+						.help("quote every CSV column, not just the ones that need it")
+					)
+					.args(&csv_args())
 			)
 			.get_matches();
 
 	let dir = matches.value_of_os("dir").expect("--dir");
 	let dir = std::path::Path::new(dir);
 
+	if matches.subcommand_matches("init").is_none()
+		&& matches.subcommand_matches("restore").is_none()
+	{
+		if let Err(e) = Database::open(dir)
+		{
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+	}
+
 	if let Some(matches) = matches.subcommand_matches("add")
 	{
-		let format = matches.value_of("format").unwrap();
+		let format = matches.value_of("format");
 		let nocheck = matches.is_present("unsafe-nocheck");
 		let ts_format = matches.value_of("timestamp-format");
-		add(&dir, format, ts_format, nocheck);
+		let verbose = matches.is_present("verbose");
+		let input = match matches.value_of("input-format")
+		{
+			Some("csv") => Input::Csv(csv_options(matches)),
+			Some("jsonl") => Input::Jsonl,
+			_ => Input::Text,
+		};
+		let mut sort = None;
+		if matches.is_present("unsorted")
+		{
+			let mut s = ExternalSort::new();
+			if let Some(bytes) = matches.value_of("sort-memory")
+			{
+				s = s.memory(bytes.parse().expect("--sort-memory must be a number"));
+			}
+			if let Some(path) = matches.value_of_os("spill-dir")
+			{
+				s = s.spill_dir(Path::new(path));
+			}
+			sort = Some(s);
+		}
+		let on_error = match matches.value_of("on-error")
+		{
+			Some("skip") => formatted::OnError::Skip,
+			Some("log") => formatted::OnError::Log,
+			_ => formatted::OnError::Abort,
+		};
+		if matches!(on_error, formatted::OnError::Abort) && matches.is_present("reject-file")
+		{
+			eprintln!("--reject-file needs --on-error skip or log");
+			std::process::exit(1);
+		}
+		let mut reject = match matches.value_of_os("reject-file")
+		{
+			Some(path) => Some(std::io::BufWriter::new(std::fs::File::create(path)?)),
+			None => None,
+		};
+		let mut bad_lines = formatted::BadLines::new(on_error);
+		if let Some(reject) = reject.as_mut()
+		{
+			bad_lines = bad_lines.reject_to(reject);
+		}
+
+		let summary = add(dir, format, ts_format, nocheck, input, sort, &mut bad_lines);
+		if bad_lines.skipped() != 0
+		{
+			eprintln!("skipped {} lines", bad_lines.skipped());
+		}
+		drop(bad_lines);
+		if let Some(mut reject) = reject
+		{
+			reject.flush()?;
+		}
+		if verbose
+		{
+			eprintln!("{}", summary);
+		}
 	}
 	else if let Some(matches) = matches.subcommand_matches("compact")
 	{
 		let gegnum = matches.value_of_os("gegnum");
 		let ts_format = matches.value_of("timestamp-format").unwrap_or("%FT%T");
 		let nocheck = matches.is_present("unsafe-nocheck");
+		let max_bytes_per_second = matches.value_of("max-bytes-per-second")
+			.map(|b| b.parse::<u64>().expect("--max-bytes-per-second must be a number"));
+
+		if matches.is_present("auto")
+		{
+			let mut policy = CompactionPolicy::default();
+			if let Some(n) = matches.value_of("max-transactions")
+			{
+				policy.max_transactions = n.parse()
+					.expect("--max-transactions must be a number");
+			}
+			if let Some(secs) = matches.value_of("major-interval")
+			{
+				let secs = secs.parse()
+					.expect("--major-interval must be a number");
+				policy.major_interval = Some(std::time::Duration::from_secs(secs));
+			}
+			policy.max_bytes_per_second = max_bytes_per_second;
+
+			AutoCompactor::spawn(
+				dir,
+				policy,
+				|result|
+					match result
+					{
+						Ok(report) => eprintln!("{}", report),
+						Err(e) => eprintln!("compaction failed: {:?}", e),
+					}
+			).join();
+			return Ok(());
+		}
+
+		let mut compaction;
+		if matches.is_present("major")
+			{ compaction = Compaction::major(dir); }
+		else
+			{ compaction = Compaction::minor(dir); }
+		compaction = compaction.incremental(matches.is_present("incremental"));
+		if let Some(n) = matches.value_of("oldest")
+		{
+			let n = n.parse().expect("--oldest must be a number");
+			compaction = compaction.select(TransactionSelection::Oldest(n));
+		}
+		else if let Some(bytes) = matches.value_of("smaller-than")
+		{
+			let bytes = bytes.parse().expect("--smaller-than must be a number");
+			compaction = compaction.select(TransactionSelection::SmallerThan(bytes));
+		}
+		else if let Some(paths) = matches.values_of_os("transaction")
+		{
+			let paths = paths.map(std::path::PathBuf::from).collect();
+			compaction = compaction.select(TransactionSelection::Paths(paths));
+		}
+
+		let mut transforms = Transforms::new();
+		for wildcard in matches.values_of("delete").into_iter().flatten()
+		{
+			transforms = transforms.delete(wildcard);
+		}
+		if let Some(values) = matches.values_of("delete-range")
+		{
+			let values: Vec<&str> = values.collect();
+			for range in values.chunks(3)
+			{
+				let parse = |t: &str|
+					formatted::parse_timestamp(t, Some(ts_format))
+						.expect("parsing --delete-range timestamp");
+				transforms = transforms.delete_range(
+					range[0],
+					parse(range[1]) .. parse(range[2]),
+				);
+			}
+		}
+		for wildcard in matches.values_of("keep").into_iter().flatten()
+		{
+			transforms = transforms.keep(wildcard);
+		}
+		for rename in matches.values_of("rename").into_iter().flatten()
+		{
+			let mut parts = rename.splitn(2, '=');
+			let from = parts.next().unwrap();
+			let to = parts.next().expect("--rename must look like old=new");
+			transforms = transforms.rename(from, to);
+		}
+		compaction = compaction.transforms(transforms);
+		compaction = compaction.verify(matches.is_present("verify"));
+		if matches.is_present("no-wait")
+		{
+			compaction = compaction.wait(LockWait::No);
+		}
+		else if let Some(secs) = matches.value_of("wait-timeout")
+		{
+			let secs = secs.parse().expect("--wait-timeout must be a number");
+			compaction = compaction.wait(LockWait::Timeout(std::time::Duration::from_secs(secs)));
+		}
+		if let Some(limit) = max_bytes_per_second
+		{
+			compaction = compaction.max_bytes_per_second(limit);
+		}
+		if matches.is_present("progress")
+		{
+			compaction = compaction.progress(
+				std::time::Duration::from_secs(5),
+				|progress| eprintln!("{}", progress),
+			);
+		}
+
+		if let Some(gegnum) = gegnum
+		{
+			let format = match matches.value_of("gegnum-format")
+			{
+				Some("exact") => GegnumFormat::Exact,
+				Some("binary") => GegnumFormat::Binary,
+				_ => GegnumFormat::Text,
+			};
+			compaction = compaction
+				.gegnum(gegnum)
+				.gegnum_format(format)
+				.timestamp_format(ts_format)
+				.nocheck(nocheck);
+		}
+
+		let report = match compaction.run()
+		{
+			Err(WriteFailure::IOError(e))
+				if e.kind() == std::io::ErrorKind::WouldBlock
+					|| e.kind() == std::io::ErrorKind::TimedOut =>
+			{
+				eprintln!("{}", e);
+				std::process::exit(75);
+			},
+			r => r.expect("compacting"),
+		};
+		eprintln!("{}", report);
+	}
+	else if matches.subcommand_matches("init").is_some()
+	{
+		if let Err(e) = Database::create(dir)
+		{
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+	}
+	else if matches.subcommand_matches("status").is_some()
+	{
+		let files = DatabaseFiles::scan(dir)?;
+		println!("main: {} bytes, replaced {}s ago", files.main_bytes, files.main_age.as_secs());
+		println!("transactions: {} ({} bytes)", files.transactions, files.transaction_bytes);
+		match lock_holder(dir)?
+		{
+			Some(holder) => println!("locked: {}", holder),
+			None => println!("locked: no"),
+		}
+	}
+	else if let Some(matches) = matches.subcommand_matches("info")
+	{
+		let mut policy = CompactionPolicy::default();
+		if let Some(n) = matches.value_of("max-transactions")
+		{
+			policy.max_transactions = n.parse()
+				.expect("--max-transactions must be a number");
+		}
+		let info = DatabaseInfo::scan(dir, &policy)?;
+		if matches.is_present("json")
+			{ println!("{}", info.to_json()); }
+		else
+			{ println!("{}", info); }
+	}
+	else if let Some(matches) = matches.subcommand_matches("verify")
+	{
+		let report = check_database(dir)?;
+		for problem in report.problems()
+		{
+			println!("{}", problem);
+		}
+		eprintln!("{}", report);
+		if !report.is_ok()
+		{
+			if !matches.is_present("repair")
+				{ std::process::exit(1); }
+			for repaired in repair_database(dir, &report)?
+			{
+				eprintln!("{}", repaired);
+			}
+			// repaired, but what couldn't be read is gone
+			std::process::exit(2);
+		}
+	}
+	else if let Some(matches) = matches.subcommand_matches("snapshot")
+	{
+		let dest = Path::new(matches.value_of_os("dest").unwrap());
+		match snapshot_database(dir, dest)
+		{
+			Ok(snapshot) => eprintln!("{}", snapshot),
+			Err(e) =>
+			{
+				eprintln!("{}", e);
+				std::process::exit(1);
+			},
+		}
+	}
+	else if let Some(matches) = matches.subcommand_matches("diff")
+	{
+		let other = Path::new(matches.value_of_os("other").unwrap());
+		if let Err(e) = Database::open(other)
+		{
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+		let wildcard = Wildcard::new(matches.value_of("filter").unwrap());
+		let print_timestamp =
+			if matches.is_present("timestamp-nanos")
+				{ formatted::PrintTimestamp::Nanos }
+			else
+				{ formatted::PrintTimestamp::FormatString("%F %T") };
 
-		compact(
-			&dir,
-			matches.is_present("major"),
-			gegnum,
-			ts_format,
-			nocheck,
-		).expect("compacting");
+		let old = DatabaseReader::new(dir)?;
+		let new = DatabaseReader::new(other)?;
+		let changes = diff(old.get_filter(&wildcard), new.get_filter(&wildcard));
+
+		let stdout = std::io::stdout();
+		let mut stdout = std::io::BufWriter::new(stdout.lock());
+		let mut total = KeyDiff::default();
+		if matches.is_present("summary")
+		{
+			for key in changes.by_key()
+			{
+				writeln!(&mut stdout, "{}", key)?;
+				total.added += key.added;
+				total.removed += key.removed;
+				total.changed += key.changed;
+			}
+		}
+		else
+		{
+			let mut print = |mark: &str, record: &record::OwnedRecord| -> std::io::Result<()>
+			{
+				write!(&mut stdout, "{}\t", mark)?;
+				formatted::print_record2(
+					record,
+					&mut stdout,
+					print_timestamp,
+					formatted::PrintRecordFormat::Yes,
+				)?;
+				writeln!(&mut stdout)
+			};
+			for change in changes
+			{
+				match change
+				{
+					Change::Added(new) =>
+					{
+						total.added += 1;
+						print("+", &new)?;
+					},
+					Change::Removed(old) =>
+					{
+						total.removed += 1;
+						print("-", &old)?;
+					},
+					Change::Changed(old, new) =>
+					{
+						total.changed += 1;
+						print("<", &old)?;
+						print(">", &new)?;
+					},
+				}
+			}
+		}
+		stdout.flush()?;
+		eprintln!(
+			"{} added, {} removed, {} changed",
+			total.added, total.removed, total.changed,
+		);
+		if total != KeyDiff::default()
+			{ std::process::exit(1); }
+	}
+	else if let Some(matches) = matches.subcommand_matches("restore")
+	{
+		let snapshot = Path::new(matches.value_of_os("snapshot").unwrap());
+		match restore_database(snapshot, dir)
+		{
+			Ok(restored) => eprintln!("{}", restored),
+			Err(e) =>
+			{
+				eprintln!("{}", e);
+				std::process::exit(1);
+			},
+		}
 	}
 	else if let Some(matches) = matches.subcommand_matches("read")
 	{
@@ -150,6 +769,22 @@ fn main()
 		let before = matches.value_of("before");
 		let filter = matches.value_of("filter");
 
+		let parse_time = |name: &str| -> Option<Timestamp>
+		{
+			let t = matches.value_of(name)?;
+			match formatted::parse_timestamp(t, matches.value_of("timestamp-format"))
+			{
+				Ok(ts) => Some(ts),
+				Err(e) =>
+				{
+					eprintln!("--{}: {}", name, e);
+					std::process::exit(1);
+				},
+			}
+		};
+		let from = parse_time("from").unwrap_or(0);
+		let to = parse_time("to").unwrap_or(Timestamp::MAX);
+
 		let stdout = std::io::stdout();
 		let mut stdout = std::io::BufWriter::new(stdout.lock());
 		let db = DatabaseReader::new(dir)?;
@@ -159,19 +794,66 @@ fn main()
 				{ formatted::PrintRecordFormat::Yes }
 			else
 				{ formatted::PrintRecordFormat::No };
+		let jsonl = matches.value_of("output-format") == Some("jsonl");
 		let print_timestamp =
 			if timestamp_nanos
+				|| (jsonl && !timestamp_seconds && !matches.is_present("timestamp-format"))
 				{ formatted::PrintTimestamp::Nanos }
 			else if timestamp_seconds
 				{ formatted::PrintTimestamp::Seconds }
 			else
 				{ formatted::PrintTimestamp::FormatString(timestamp_format) };
 
-		macro_rules! filter
+		let mut csv =
+			if matches.value_of("output-format") == Some("csv")
+			{
+				let options = csv_options(matches)
+					.quote_all(matches.is_present("csv-quote-all"));
+				Some(formatted::CsvPrinter::new(
+					std::io::stdout(),
+					&options,
+					print_timestamp,
+					print_record_format,
+				))
+			}
+			else
+				{ None };
+
+		use std::ops::Bound;
+		let wildcard = Wildcard::new(filter.unwrap_or("%"));
+		let lowest = after.map_or(Bound::Unbounded, Bound::Included);
+		let highest = before.map_or(Bound::Unbounded, Bound::Excluded);
+		// a key's records are in order of time, so once one is at
+		// `to`, the rest of them are skipped by starting again after it
+		let mut resume: Option<String> = None;
+		loop
 		{
-			($filter:expr) =>
+			let lowest = resume.as_deref().map_or(lowest, Bound::Excluded);
+			let mut past_to = None;
+			for record in db.get_range_filter((lowest, highest), &wildcard)
 			{
-				for record in $filter
+				if record.timestamp() >= to
+				{
+					past_to = Some(record.key().to_owned());
+					break;
+				}
+				if record.timestamp() < from
+					{ continue; }
+				if let Some(csv) = csv.as_mut()
+				{
+					csv.print(&record)?;
+					continue;
+				}
+				if jsonl
+				{
+					formatted::print_record_jsonl(
+						&record,
+						&mut stdout,
+						print_timestamp,
+						print_record_format
+					)?;
+				}
+				else
 				{
 					formatted::print_record2(
 						&record,
@@ -179,163 +861,147 @@ fn main()
 						print_timestamp,
 						print_record_format
 					)?;
-					writeln!(&mut stdout, "")?;
 				}
-			};
+				writeln!(&mut stdout)?;
+			}
+			match past_to
+			{
+				Some(key) => resume = Some(key),
+				None => break,
+			}
 		}
-
-		match (after, before, filter)
-		{
-			(Some(after), None, None) =>
-				filter!(db.get_range(after ..)),
-			(None, Some(before), None) =>
-				filter!(db.get_range( .. before)),
-			(Some(after), Some(before), None) =>
-				filter!(db.get_range(after .. before)),
-			(None, None, Some(filter)) =>
-				filter!(db.get_filter(&Wildcard::new(filter))),
-			_ =>
-				unreachable!(),
+		if let Some(csv) = csv
+		{
+			csv.finish()?;
 		}
 	}
 	else
 	{
-		eprintln!("A command must be specified (read, add, compact)");
+		eprintln!("A command must be specified (init, read, add, compact, status, info, verify, snapshot, restore, diff)");
 		std::process::exit(1);
 	}
 
 	Ok(())
 }
 
-fn add(dir: &Path, fmt: &str, ts_format: Option<&str>, nocheck: bool)
+enum Input
+{
+	Text,
+	Csv(formatted::CsvOptions),
+	Jsonl,
+}
+
+fn add(
+	dir: &Path,
+	fmt: Option<&str>,
+	ts_format: Option<&str>,
+	nocheck: bool,
+	input: Input,
+	sort: Option<ExternalSort>,
+	bad_lines: &mut formatted::BadLines,
+) -> CommitSummary
 {
 	let db = DatabaseReader::new(dir).expect("opening db");
 	let mut tx = CreateTx::new(dir).expect("creating tx");
+	if let Some(sort) = sort
+	{
+		tx = tx.unsorted(sort);
+	}
 
 	let stdin = std::io::stdin();
 	let mut stdin = stdin.lock();
 
-	formatted::add_from_stream(&mut tx, &db, fmt, &mut stdin, ts_format, nocheck)
-		.expect("adding value");
-	tx.commit().expect("failed to commit transaction");
+	let r = match input
+	{
+		Input::Text =>
+		{
+			let fmt = fmt.expect("--format is required");
+			formatted::add_from_stream2(&mut tx, &db, fmt, &mut stdin, ts_format, nocheck, bad_lines)
+		},
+		Input::Csv(csv) =>
+			formatted::add_from_csv(&mut tx, &db, fmt, &mut stdin, ts_format, nocheck, &csv, bad_lines),
+		Input::Jsonl =>
+			formatted::add_from_jsonl(&mut tx, &db, fmt, &mut stdin, ts_format, nocheck, bad_lines),
+	};
+	if let Err(e) = r
+	{
+		eprintln!("{}", e);
+		std::process::exit(1);
+	}
+	match tx.commit()
+	{
+		Ok(summary) => summary,
+		Err(e) =>
+		{
+			eprintln!("failed to commit transaction: {}", e);
+			std::process::exit(1);
+		},
+	}
 }
 
-fn compact(
-	dir: &Path, major: bool,
-	gegnum: Option<&std::ffi::OsStr>, ts_format: &str, nocheck: bool)
-	-> Result<(), crate::WriteFailure>
+fn csv_args<'a, 'b>() -> Vec<clap::Arg<'a, 'b>>
 {
-	use fs2::FileExt;
-
-	let lock = File::create(dir.join(".compact"))?;
-	lock.lock_exclusive()?;
-
-	let db;
-	if major
-		{ db = DatabaseReader::new(dir)?; }
-	else
-		{ db = DatabaseReader::without_main_db(dir)?; }
-	let db = std::sync::Arc::new(db);
-
-
-	let mut compacted = CreateTx::new(dir)?;
-
-	if let Some(gegnum) = gegnum
-	{
-		let mut child = std::process::Command::new("/bin/sh")
-			.arg("-c")
-			.arg(gegnum)
-			.stdin(std::process::Stdio::piped())
-			.stdout(std::process::Stdio::piped())
-			.spawn()
-			.expect("unable to run --gegnum process");
-
-		let childinput = child.stdin.take().expect("process had no stdin");
-		let mut childinput = std::io::BufWriter::new(childinput);
+	use clap::Arg;
+	vec!(
+		Arg::with_name("csv-delimiter")
+			.long("csv-delimiter")
+			.help("separate CSV columns with this character instead of \",\" (\"\\t\" is a tab)")
+			.takes_value(true),
+		Arg::with_name("csv-quote")
+			.long("csv-quote")
+			.help("quote CSV columns with this character instead of '\"'")
+			.takes_value(true),
+		Arg::with_name("csv-key-column")
+			.long("csv-key-column")
+			.help("the name of the CSV column with the key (default \"key\")")
+			.takes_value(true),
+		Arg::with_name("csv-timestamp-column")
+			.long("csv-timestamp-column")
+			.help("the name of the CSV column with the timestamp (default \"timestamp\")")
+			.takes_value(true),
+		Arg::with_name("csv-format-column")
+			.long("csv-format-column")
+			.help("the name of the CSV column with each record's format")
+			.takes_value(true),
+		Arg::with_name("csv-value-columns")
+			.long("csv-value-columns")
+			.help("the names of the CSV columns with the values, separated by commas \
+				(default: all of the other columns)")
+			.takes_value(true),
+	)
+}
 
-		let ts_format_copy = ts_format.to_owned();
-		// a thread that reads from "db" and writes to the child
-		let reader_db = db.clone();
-		let reader_thread = std::thread::spawn(
-			move || -> std::io::Result<()>
+fn csv_options(matches: &clap::ArgMatches) -> formatted::CsvOptions
+{
+	let character = |name: &str|
+		matches.value_of(name).map(
+			|c| match c
 			{
-				let timestamp_format = formatted::PrintTimestamp::FormatString(&ts_format_copy);
-				let reader = reader_db.get_range(..);
-				for record in reader
+				"\\t" => b'\t',
+				c if c.len() == 1 => c.as_bytes()[0],
+				_ =>
 				{
-					formatted::print_record2(
-						&record, &mut childinput,
-						timestamp_format,
-						formatted::PrintRecordFormat::Yes,
-					)?;
-					writeln!(&mut childinput, "")?;
-				}
-				Ok(())
+					eprintln!("--{} must be one character", name);
+					std::process::exit(1);
+				},
 			}
 		);
 
-		let childoutput = child.stdout.take().expect("process had no stdout");
-		let mut childoutput = std::io::BufReader::new(childoutput);
-		formatted::add_from_stream_with_fmt(
-			&mut compacted, &db, &mut childoutput,
-			Some(ts_format),
-			nocheck,
-		)?;
-
-		reader_thread.join()
-			.expect("failed to join subprocess writing thread")
-			.expect("child writer failed");
-		let result = child.wait()?;
-		if !result.success()
-		{
-			panic!("child process failed: cancelling compact");
-		}
-	}
-	else
-	{
-		{
-			let ps = db.transaction_paths();
-			if ps.len() == 1 && ps[0].file_name().expect("filename") == "main"
-			{
-				eprintln!("nothing to do");
-				return Ok(());
-			}
-		}
-		// create the new transaction after opening the database reader
-		let reader = db.get_range(..);
-		let mut n=0u64;
-		for record in reader
-		{
-			compacted.add_record(
-				record.key(),
-				record.format(),
-				record.value(),
-			)?;
-			n+= 1;
-		}
-		eprintln!("compacted {} records", n);
-	}
-
-	if major
+	let mut options = formatted::CsvOptions::new();
+	if let Some(c) = character("csv-delimiter")
+		{ options = options.delimiter(c); }
+	if let Some(c) = character("csv-quote")
+		{ options = options.quote(c); }
+	if let Some(name) = matches.value_of("csv-key-column")
+		{ options = options.key_column(name); }
+	if let Some(name) = matches.value_of("csv-timestamp-column")
+		{ options = options.timestamp_column(name); }
+	if let Some(name) = matches.value_of("csv-format-column")
+		{ options = options.format_column(name); }
+	if let Some(names) = matches.value_of("csv-value-columns")
 	{
-		compacted.commit_to(&dir.join("main"))
-			.expect("failed to replace main database");
+		let names: Vec<&str> = names.split(',').collect();
+		options = options.value_columns(&names);
 	}
-	else
-	{
-		compacted.commit()
-			.expect("failed to commit compacted database");
-	}
-
-	for txfile in db.transaction_paths()
-	{
-		if txfile.file_name().expect("filename in txfile") == "main"
-			{ continue; }
-		if let Err(e) = std::fs::remove_file(&txfile)
-		{
-			eprintln!("warning: failed to remove {:?}: {}", txfile, e);
-		}
-	}
-
-	Ok(())
+	options
 }
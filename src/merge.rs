@@ -9,11 +9,13 @@ struct Next<Source, Record>
 	current_record: Option<Rc<Record>>,
 }
 
+type Comparator<Record> = dyn Fn(&Record, &Record)->Ordering;
+
 struct NextKey<Record>
 {
 	current_record: Rc<Record>,
 	source_index: usize,
-	compare_record: Box<dyn Fn(&Record, &Record)->Ordering>,
+	compare_record: Box<Comparator<Record>>,
 }
 
 impl<Record> Ord for NextKey<Record>
@@ -182,7 +184,7 @@ where Source: Iterator<Item=Record>,
 			let cur = source.current_record
 				.take()
 				.map(|item| Rc::try_unwrap(item).unwrap());
-			self.discard_repetitions(&cur.as_ref().unwrap());
+			self.discard_repetitions(cur.as_ref().unwrap());
 			cur
 		}
 	}
@@ -0,0 +1,333 @@
+//! Use sonnerie from inside of a tokio runtime.
+//!
+//! Requires the `async` feature.
+//!
+//! The blocking parts of writing and reading are done with
+//! `tokio::task::spawn_blocking`, so none of these functions
+//! will stall the runtime's worker threads.
+
+use std::path::{Path,PathBuf};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::pin::Pin;
+use std::task::{Context,Poll};
+
+use futures::sink::SinkExt;
+use futures::stream::Stream;
+
+use crate::create_tx::{CreateTx,CommitSummary};
+use crate::database_reader::DatabaseReader;
+use crate::record::OwnedRecord;
+use crate::write::WriteFailure;
+use crate::Wildcard;
+
+// add_record only fills a buffer until it's this big,
+// then hands it to the transaction on a blocking thread
+const PENDING_BYTES_GOAL: usize = 1024*256;
+
+// number of records sent over the channel at once by a `RecordStream`
+const STREAM_BATCH: usize = 256;
+
+struct PendingRecord
+{
+	key: String,
+	format: String,
+	data: Vec<u8>,
+}
+
+/// Like [`CreateTx`](../create_tx/struct.CreateTx.html), but for async code.
+///
+/// Records added with [`add_record`](#method.add_record)
+/// are buffered and periodically written to the transaction
+/// on a blocking thread. Because of this, an error such as an
+/// ordering violation may be reported by a later call
+/// to `add_record` or by [`commit`](#method.commit).
+///
+/// Dropping an `AsyncCreateTx` without committing it
+/// rolls back the transaction.
+pub struct AsyncCreateTx
+{
+	tx: Option<CreateTx>,
+	pending: Vec<PendingRecord>,
+	pending_bytes: usize,
+}
+
+impl AsyncCreateTx
+{
+	/// Open a transaction file inside this specific directory.
+	///
+	/// See [`CreateTx::new`](../create_tx/struct.CreateTx.html#method.new).
+	pub async fn new(dir: &Path) -> std::io::Result<AsyncCreateTx>
+	{
+		let dir = dir.to_owned();
+		let tx = run_blocking(move || CreateTx::new(&dir)).await??;
+		Ok(AsyncCreateTx
+		{
+			tx: Some(tx),
+			pending: vec!(),
+			pending_bytes: 0,
+		})
+	}
+
+	/// Add a record with the given key, format, and payload.
+	///
+	/// The same rules as [`CreateTx::add_record`](../create_tx/struct.CreateTx.html#method.add_record)
+	/// apply.
+	pub async fn add_record(&mut self, key: &str, format: &str, data: &[u8])
+		-> Result<(), WriteFailure>
+	{
+		self.pending_bytes += key.len() + format.len() + data.len();
+		self.pending.push(
+			PendingRecord
+			{
+				key: key.to_owned(),
+				format: format.to_owned(),
+				data: data.to_owned(),
+			}
+		);
+
+		if self.pending_bytes >= PENDING_BYTES_GOAL
+		{
+			self.flush().await?;
+		}
+		Ok(())
+	}
+
+	/// Commit the transaction.
+	///
+	/// See [`CreateTx::commit`](../create_tx/struct.CreateTx.html#method.commit).
+	pub async fn commit(mut self)
+		-> Result<CommitSummary, WriteFailure>
+	{
+		self.flush().await?;
+		let tx = self.take_tx()?;
+		Ok(run_blocking(move || tx.commit()).await??)
+	}
+
+	/// Commit the transaction, but give it a specific name.
+	///
+	/// See [`CreateTx::commit_to`](../create_tx/struct.CreateTx.html#method.commit_to).
+	pub async fn commit_to(mut self, final_name: &Path)
+		-> Result<CommitSummary, WriteFailure>
+	{
+		self.flush().await?;
+		let tx = self.take_tx()?;
+		let final_name: PathBuf = final_name.to_owned();
+		Ok(run_blocking(move || tx.commit_to(&final_name)).await??)
+	}
+
+	async fn flush(&mut self)
+		-> Result<(), WriteFailure>
+	{
+		if self.pending.is_empty() { return Ok(()); }
+
+		let mut tx = self.take_tx()?;
+		let pending = std::mem::take(&mut self.pending);
+		self.pending_bytes = 0;
+
+		let (tx, pending, result) = run_blocking(
+			move ||
+			{
+				let mut pending = pending;
+				let mut result = Ok(());
+				for r in pending.drain(..)
+				{
+					result = tx.add_record(&r.key, &r.format, &r.data);
+					if result.is_err() { break; }
+				}
+				(tx, pending, result)
+			}
+		).await?;
+
+		// reuse the allocation for the next batch
+		self.pending = pending;
+		self.tx = Some(tx);
+		result
+	}
+
+	fn take_tx(&mut self) -> std::io::Result<CreateTx>
+	{
+		self.tx.take()
+			.ok_or_else(
+				|| std::io::Error::other(
+					"transaction was lost by a previous failure"
+				)
+			)
+	}
+}
+
+async fn run_blocking<F, T>(f: F) -> std::io::Result<T>
+where
+	F: FnOnce() -> T + Send + 'static,
+	T: Send + 'static,
+{
+	tokio::task::spawn_blocking(f)
+		.await
+		.map_err(std::io::Error::other)
+}
+
+/// A `Stream` of the records in a database.
+///
+/// Yields an [`OwnedRecord`](../record/struct.OwnedRecord.html)
+/// for each row, sorted by key and timestamp, just like
+/// [`DatabaseKeyReader`](../database_reader/struct.DatabaseKeyReader.html).
+///
+/// Create one with [`stream_range`], [`stream_filter`] or [`stream_key`].
+pub struct RecordStream
+{
+	recv: futures::channel::mpsc::Receiver<Vec<OwnedRecord>>,
+	current: std::vec::IntoIter<OwnedRecord>,
+}
+
+impl Stream for RecordStream
+{
+	type Item = OwnedRecord;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+		-> Poll<Option<Self::Item>>
+	{
+		loop
+		{
+			if let Some(record) = self.current.next()
+			{
+				return Poll::Ready(Some(record));
+			}
+
+			match Pin::new(&mut self.recv).poll_next(cx)
+			{
+				Poll::Ready(Some(batch)) =>
+					self.current = batch.into_iter(),
+				Poll::Ready(None) =>
+					return Poll::Ready(None),
+				Poll::Pending =>
+					return Poll::Pending,
+			}
+		}
+	}
+}
+
+impl RecordStream
+{
+	// run `reader` on a blocking thread. It calls `emit` with each
+	// record, which returns false if the stream was dropped
+	fn spawn<F>(db: Arc<DatabaseReader>, reader: F) -> RecordStream
+	where
+		F: FnOnce(&DatabaseReader, &mut dyn FnMut(OwnedRecord) -> bool)
+			+ Send + 'static,
+	{
+		let (mut send, recv) = futures::channel::mpsc::channel(4);
+
+		tokio::task::spawn_blocking(
+			move ||
+			{
+				let mut batch = Vec::with_capacity(STREAM_BATCH);
+				reader(
+					&db,
+					&mut |record|
+					{
+						batch.push(record);
+						if batch.len() < STREAM_BATCH
+							{ return true; }
+						let full = std::mem::replace(
+							&mut batch,
+							Vec::with_capacity(STREAM_BATCH)
+						);
+						futures::executor::block_on(send.send(full)).is_ok()
+					},
+				);
+				if !batch.is_empty()
+				{
+					let _ = futures::executor::block_on(send.send(batch));
+				}
+			}
+		);
+
+		RecordStream
+		{
+			recv,
+			current: vec!().into_iter(),
+		}
+	}
+}
+
+/// Stream the records for a lexicographic range of keys.
+///
+/// The async version of
+/// [`DatabaseReader::get_range`](../database_reader/struct.DatabaseReader.html#method.get_range).
+pub fn stream_range(
+	db: Arc<DatabaseReader>,
+	start: Bound<String>,
+	end: Bound<String>,
+) -> RecordStream
+{
+	RecordStream::spawn(
+		db,
+		move |db, emit|
+		{
+			let range = (as_str_bound(&start), as_str_bound(&end));
+			for record in db.get_range(range)
+			{
+				if !emit(record) { break; }
+			}
+		}
+	)
+}
+
+/// Stream the records whose keys match a wildcard.
+///
+/// The async version of
+/// [`DatabaseReader::get_filter`](../database_reader/struct.DatabaseReader.html#method.get_filter).
+pub fn stream_filter(
+	db: Arc<DatabaseReader>,
+	wildcard: Wildcard,
+) -> RecordStream
+{
+	if wildcard.is_exact()
+	{
+		// don't do an fadvise when reading a single key
+		let key = wildcard.prefix().to_owned();
+		return stream_key(db, key);
+	}
+
+	RecordStream::spawn(
+		db,
+		move |db, emit|
+		{
+			for record in db.get_filter(&wildcard)
+			{
+				if !emit(record) { break; }
+			}
+		}
+	)
+}
+
+/// Stream the records for a single key.
+///
+/// The async version of
+/// [`DatabaseReader::get`](../database_reader/struct.DatabaseReader.html#method.get).
+pub fn stream_key(
+	db: Arc<DatabaseReader>,
+	key: String,
+) -> RecordStream
+{
+	RecordStream::spawn(
+		db,
+		move |db, emit|
+		{
+			for record in db.get(&key)
+			{
+				if !emit(record) { break; }
+			}
+		}
+	)
+}
+
+fn as_str_bound(b: &Bound<String>) -> Bound<&str>
+{
+	match b
+	{
+		Bound::Included(s) => Bound::Included(s),
+		Bound::Excluded(s) => Bound::Excluded(s),
+		Bound::Unbounded => Bound::Unbounded,
+	}
+}
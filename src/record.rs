@@ -1,11 +1,13 @@
 //! Stores a single row.
 
-use std::rc::Rc;
+use std::sync::Arc;
+use byteorder::{ByteOrder,BigEndian};
 
 /// Store the data for a record.
 ///
 /// This object is cheaply copied because it is
 /// internally reference counted.
+#[derive(Clone)]
 pub struct OwnedRecord
 {
 	pub(crate) key_pos: usize,
@@ -14,7 +16,7 @@ pub struct OwnedRecord
 	pub(crate) fmt_len: usize,
 	pub(crate) value_pos: usize,
 	pub(crate) value_len: usize,
-	pub(crate) data: Rc<Vec<u8>>,
+	pub(crate) data: Arc<Vec<u8>>,
 }
 
 impl OwnedRecord
@@ -25,7 +27,7 @@ impl OwnedRecord
 		let d = &self.data[self.key_pos .. self.key_pos+self.key_len];
 		unsafe
 		{
-			std::str::from_utf8_unchecked(&d)
+			std::str::from_utf8_unchecked(d)
 		}
 	}
 
@@ -35,10 +37,16 @@ impl OwnedRecord
 		let d = &self.data[self.fmt_pos .. self.fmt_pos+self.fmt_len];
 		unsafe
 		{
-			std::str::from_utf8_unchecked(&d)
+			std::str::from_utf8_unchecked(d)
 		}
 	}
 
+	/// The timestamp of this record, in nanoseconds since the epoch.
+	pub fn timestamp(&self) -> crate::Timestamp
+	{
+		BigEndian::read_u64(self.value())
+	}
+
 	/// The encoded payload of this data. Use [`row_format`](../row_format/)
 	/// to decode it.
 	pub fn value(&self) -> &[u8]
@@ -0,0 +1,231 @@
+//! Delete old data when compacting.
+//!
+//! The file `retention` in the database directory says how
+//! long to keep the records of the keys that match a wildcard:
+//!
+//! ```text
+//! # wildcard   how long
+//! debug.%      7d
+//! billing.%    forever
+//! %            1y
+//! ```
+//!
+//! The first rule that matches a key applies, and keys that match
+//! no rule are kept forever. The ages can be in seconds (`s`), minutes
+//! (`m`), hours (`h`), days (`d`), weeks (`w`) or years of
+//! 365 days (`y`), compared to the records' timestamps.
+//!
+//! Major compactions drop the expired records. Minor compactions
+//! don't, because a transaction's record may replace an older one in
+//! `main`, which dropping it would bring back.
+
+use std::path::Path;
+use std::time::Duration;
+
+use crate::row_format::Timestamp;
+use crate::Wildcard;
+
+/// The name of the retention file in the database directory
+pub const RETENTION_FILE: &str = "retention";
+
+#[derive(Debug,Clone)]
+struct RetentionRule
+{
+	wildcard: Wildcard,
+	regex: Option<regex::Regex>,
+	max_age: Option<Duration>,
+}
+
+impl RetentionRule
+{
+	fn matches(&self, key: &str) -> bool
+	{
+		match self.regex.as_ref()
+		{
+			Some(re) => re.is_match(key),
+			None => key.starts_with(self.wildcard.prefix()),
+		}
+	}
+}
+
+/// How long to keep records, by key.
+#[derive(Debug,Clone,Default)]
+pub struct Retention
+{
+	rules: Vec<RetentionRule>,
+}
+
+impl Retention
+{
+	/// Keep everything
+	pub fn new() -> Retention
+	{
+		Default::default()
+	}
+
+	/// Keep the records of keys matching `wildcard` for `max_age`,
+	/// or forever if it's `None`.
+	///
+	/// Rules added earlier take precedence.
+	pub fn rule(mut self, wildcard: &str, max_age: Option<Duration>) -> Retention
+	{
+		let wildcard = Wildcard::new(wildcard);
+		self.rules.push(RetentionRule
+		{
+			regex: wildcard.as_regex(),
+			wildcard,
+			max_age,
+		});
+		self
+	}
+
+	/// Read the rules from the retention file in `dir`.
+	///
+	/// If there's no such file, everything is kept.
+	pub fn load(dir: &Path) -> std::io::Result<Retention>
+	{
+		let path = dir.join(RETENTION_FILE);
+		let text = match std::fs::read_to_string(&path)
+		{
+			Ok(t) => t,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+				return Ok(Retention::new()),
+			Err(e) => return Err(e),
+		};
+		Self::parse(&text)
+			.map_err(
+				|e| std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					format!("{}: {}", path.display(), e),
+				)
+			)
+	}
+
+	/// Parse the rules in the format of the retention file.
+	pub fn parse(text: &str) -> Result<Retention, String>
+	{
+		let mut retention = Retention::new();
+		for (number, line) in text.lines().enumerate()
+		{
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#')
+				{ continue; }
+
+			let mut words = line.split_whitespace();
+			let wildcard = words.next().unwrap();
+			let age = words.next()
+				.ok_or_else(|| format!("line {}: no age for \"{}\"", number+1, wildcard))?;
+			if words.next().is_some()
+			{
+				return Err(format!("line {}: too many words", number+1));
+			}
+			let max_age = parse_age(age)
+				.ok_or_else(|| format!("line {}: invalid age \"{}\"", number+1, age))?;
+			retention = retention.rule(wildcard, max_age);
+		}
+		Ok(retention)
+	}
+
+	/// Returns true if nothing is ever dropped
+	pub fn keeps_everything(&self) -> bool
+	{
+		self.rules.iter().all(|r| r.max_age.is_none())
+	}
+
+	/// How long to keep the records of `key`, `None` for forever
+	pub fn max_age(&self, key: &str) -> Option<Duration>
+	{
+		self.rules.iter()
+			.find(|r| r.matches(key))
+			.and_then(|r| r.max_age)
+	}
+
+	/// Returns true if any key from `lowest` to `last` could
+	/// have records that expire.
+	pub(crate) fn might_expire(&self, lowest: &str, last: &str) -> bool
+	{
+		self.rules.iter()
+			.filter(|r| r.max_age.is_some())
+			.any(
+				|r|
+				{
+					let prefix = r.wildcard.prefix();
+					last >= prefix
+						&& (lowest <= prefix || lowest.starts_with(prefix))
+				}
+			)
+	}
+}
+
+pub(crate) fn parse_age(age: &str) -> Option<Option<Duration>>
+{
+	if age == "forever"
+		{ return Some(None); }
+
+	let split = age.find(|c: char| !c.is_ascii_digit())?;
+	let (number, unit) = age.split_at(split);
+	let number: u64 = number.parse().ok()?;
+	let seconds = match unit
+	{
+		"s" => 1,
+		"m" => 60,
+		"h" => 3600,
+		"d" => 86400,
+		"w" => 7*86400,
+		"y" => 365*86400,
+		_ => return None,
+	};
+	Some(Some(Duration::from_secs(number.checked_mul(seconds)?)))
+}
+
+/// Decides which records to drop, for records sorted by key.
+pub(crate) struct RetentionFilter
+{
+	retention: Retention,
+	now: Timestamp,
+	key: Option<String>,
+	cutoff: Option<Timestamp>,
+	/// The number of records dropped
+	pub(crate) expired: u64,
+}
+
+impl RetentionFilter
+{
+	pub(crate) fn new(retention: Retention) -> RetentionFilter
+	{
+		let now = std::time::SystemTime::now()
+			.duration_since(std::time::SystemTime::UNIX_EPOCH)
+			.expect("duration_since epoch")
+			.as_nanos() as Timestamp;
+		RetentionFilter
+		{
+			retention,
+			now,
+			key: None,
+			cutoff: None,
+			expired: 0,
+		}
+	}
+
+	pub(crate) fn retention(&self) -> &Retention
+	{
+		&self.retention
+	}
+
+	/// Returns false if this record has expired
+	pub(crate) fn keep(&mut self, key: &str, timestamp: Timestamp) -> bool
+	{
+		if self.key.as_deref() != Some(key)
+		{
+			self.cutoff = self.retention.max_age(key)
+				.map(|age| self.now.saturating_sub(age.as_nanos() as Timestamp));
+			self.key = Some(key.to_owned());
+		}
+		if self.cutoff.map(|c| timestamp < c).unwrap_or(false)
+		{
+			self.expired += 1;
+			return false;
+		}
+		true
+	}
+}
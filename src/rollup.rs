@@ -0,0 +1,729 @@
+//! Replace old data with aggregates when compacting.
+//!
+//! The file `rollup` in the database directory says which
+//! keys to downsample, once their records are how old:
+//!
+//! ```text
+//! # wildcard   after   interval   aggregates     same-key
+//! metrics.%    30d     5m         mean,min,max
+//! sensors.%    1y      1h         mean           same-key
+//! ```
+//!
+//! The records of a key that are older than `after` are grouped
+//! into periods of `interval` (counted from the epoch), and each
+//! period's records are replaced by one record per aggregate,
+//! timestamped with the start of the period. Each aggregate goes
+//! into its own key, named after the original followed by a "." and
+//! the aggregate, such as `metrics.cpu.mean`; the rule that made
+//! those keys doesn't apply to them. With `same-key`, there can only
+//! be one aggregate and it replaces the records in their own key.
+//!
+//! The aggregates are `mean`, `min`, `max`, `sum`, `first`, `last`
+//! and `count`. `mean` and `sum` are 64-bit floats (one `F` per column),
+//! `count` is a single `U` and the rest have the key's own format.
+//! Only keys with only numeric columns are rolled up, and the numbers
+//! are converted through 64-bit floats. The first rule
+//! that matches a key applies. Ages are written as for
+//! [retention](../retention/index.html).
+//!
+//! Only major compactions do rollups, because a minor compaction
+//! doesn't see all of a period's records. A record that's added to a
+//! period after it was rolled up is kept as it is, because the
+//! aggregates can't be recalculated without the records they replaced.
+//! An aggregate in the same key looks like any other record, so the
+//! file `rolled-up` says how far each `same-key` rule has rolled up.
+
+use std::collections::{BTreeMap,BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use byteorder::{ByteOrder,BigEndian};
+
+use crate::row_format::{parse_row_format,RowFormat,Timestamp};
+use crate::{DatabaseReader,Wildcard};
+
+/// The name of the rollup file in the database directory
+pub const ROLLUP_FILE: &str = "rollup";
+
+// where a rollup writes each record
+pub(crate) type Output<'o, E> = dyn FnMut(&str, &str, &[u8]) -> Result<(), E> + 'o;
+
+/// A way to summarize the records in a period.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Aggregate
+{
+	/// The average of each column
+	Mean,
+	/// The least value of each column
+	Min,
+	/// The greatest value of each column
+	Max,
+	/// The total of each column
+	Sum,
+	/// The earliest record
+	First,
+	/// The latest record
+	Last,
+	/// The number of records
+	Count,
+}
+
+impl Aggregate
+{
+	/// The name of this aggregate, which is also the end
+	/// of the keys it's written to.
+	pub fn name(&self) -> &'static str
+	{
+		match self
+		{
+			Aggregate::Mean => "mean",
+			Aggregate::Min => "min",
+			Aggregate::Max => "max",
+			Aggregate::Sum => "sum",
+			Aggregate::First => "first",
+			Aggregate::Last => "last",
+			Aggregate::Count => "count",
+		}
+	}
+
+	/// Look up an aggregate by its name
+	pub fn from_name(name: &str) -> Option<Aggregate>
+	{
+		let a = match name
+		{
+			"mean" => Aggregate::Mean,
+			"min" => Aggregate::Min,
+			"max" => Aggregate::Max,
+			"sum" => Aggregate::Sum,
+			"first" => Aggregate::First,
+			"last" => Aggregate::Last,
+			"count" => Aggregate::Count,
+			_ => return None,
+		};
+		Some(a)
+	}
+}
+
+/// Which keys to roll up, and how.
+#[derive(Debug,Clone)]
+pub struct RollupRule
+{
+	/// The keys to roll up
+	pub wildcard: String,
+	/// Roll up the records that are older than this
+	pub after: Duration,
+	/// The length of the periods, each of which becomes
+	/// one record per aggregate
+	pub interval: Duration,
+	/// What to keep of each period
+	pub aggregates: Vec<Aggregate>,
+	/// Replace the records in their own key, rather than
+	/// writing each aggregate to a new key
+	pub same_key: bool,
+}
+
+#[derive(Debug,Clone)]
+struct CompiledRule
+{
+	rule: RollupRule,
+	wildcard: Wildcard,
+	regex: Option<regex::Regex>,
+}
+
+impl CompiledRule
+{
+	fn matches(&self, key: &str) -> bool
+	{
+		match self.regex.as_ref()
+		{
+			Some(re) => re.is_match(key),
+			None => key.starts_with(self.wildcard.prefix()),
+		}
+	}
+
+	// is this one of the keys this rule writes to?
+	fn made(&self, key: &str) -> bool
+	{
+		!self.rule.same_key
+			&& self.rule.aggregates.iter()
+				.any(
+					|a|
+						key.ends_with(a.name())
+						&& key[.. key.len()-a.name().len()].ends_with('.')
+				)
+	}
+}
+
+/// A set of [`RollupRule`]s.
+#[derive(Debug,Clone,Default)]
+pub struct Rollups
+{
+	rules: Vec<CompiledRule>,
+}
+
+impl Rollups
+{
+	/// Roll up nothing
+	pub fn new() -> Rollups
+	{
+		Default::default()
+	}
+
+	/// Add a rule. Rules added earlier take precedence.
+	///
+	/// Fails if the rule doesn't make sense, such as
+	/// `same_key` with more than one aggregate.
+	pub fn rule(mut self, rule: RollupRule) -> Result<Rollups, String>
+	{
+		if rule.interval.as_nanos() == 0
+			{ return Err("the interval must not be zero".to_string()); }
+		if rule.aggregates.is_empty()
+			{ return Err("there are no aggregates".to_string()); }
+		if rule.same_key
+			&& (rule.aggregates.len() != 1 || rule.aggregates[0] == Aggregate::Count)
+		{
+			return Err("same-key needs a single aggregate, other than count".to_string());
+		}
+
+		let wildcard = Wildcard::new(&rule.wildcard);
+		self.rules.push(CompiledRule
+		{
+			regex: wildcard.as_regex(),
+			wildcard,
+			rule,
+		});
+		Ok(self)
+	}
+
+	/// Read the rules from the rollup file in `dir`.
+	///
+	/// If there's no such file, nothing is rolled up.
+	pub fn load(dir: &Path) -> std::io::Result<Rollups>
+	{
+		let path = dir.join(ROLLUP_FILE);
+		let text = match std::fs::read_to_string(&path)
+		{
+			Ok(t) => t,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+				return Ok(Rollups::new()),
+			Err(e) => return Err(e),
+		};
+		Self::parse(&text)
+			.map_err(
+				|e| std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					format!("{}: {}", path.display(), e),
+				)
+			)
+	}
+
+	/// Parse the rules in the format of the rollup file.
+	pub fn parse(text: &str) -> Result<Rollups, String>
+	{
+		let mut rollups = Rollups::new();
+		for (number, line) in text.lines().enumerate()
+		{
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#')
+				{ continue; }
+
+			let words: Vec<&str> = line.split_whitespace().collect();
+			if words.len() != 4 && !(words.len() == 5 && words[4] == "same-key")
+			{
+				return Err(format!(
+					"line {}: expected a wildcard, an age, an interval, \
+					aggregates and maybe \"same-key\"",
+					number+1,
+				));
+			}
+
+			let duration = |w: &str|
+				crate::retention::parse_age(w)
+					.and_then(|d| d)
+					.ok_or_else(|| format!("line {}: invalid duration \"{}\"", number+1, w));
+
+			let mut aggregates = vec!();
+			for name in words[3].split(',')
+			{
+				aggregates.push(
+					Aggregate::from_name(name)
+						.ok_or_else(|| format!("line {}: unknown aggregate \"{}\"", number+1, name))?
+				);
+			}
+
+			rollups = rollups.rule(
+				RollupRule
+				{
+					wildcard: words[0].to_string(),
+					after: duration(words[1])?,
+					interval: duration(words[2])?,
+					aggregates,
+					same_key: words.len() == 5,
+				}
+			)
+				.map_err(|e| format!("line {}: {}", number+1, e))?;
+		}
+		Ok(rollups)
+	}
+
+	/// Returns true if there are no rules
+	pub fn is_empty(&self) -> bool
+	{
+		self.rules.is_empty()
+	}
+
+	/// Returns true if any key from `lowest` to `last`
+	/// could be rolled up.
+	pub(crate) fn might_roll_up(&self, lowest: &str, last: &str) -> bool
+	{
+		self.rules.iter()
+			.any(
+				|r|
+				{
+					let prefix = r.wildcard.prefix();
+					last >= prefix
+						&& (lowest <= prefix || lowest.starts_with(prefix))
+				}
+			)
+	}
+
+	fn rule_for(&self, key: &str) -> Option<&CompiledRule>
+	{
+		self.rules.iter()
+			.find(|r| r.matches(key))
+			.filter(|r| !r.made(key))
+	}
+}
+
+/// The name of the file that says how far the same-key
+/// rules have rolled up
+pub const ROLLED_UP_FILE: &str = "rolled-up";
+
+// how far each same-key rule rolled up in the last major compaction,
+// because its aggregates can't otherwise be told from the records
+// they replaced
+#[derive(Debug,Clone,Default)]
+pub(crate) struct RolledUp
+{
+	// by wildcard, interval and aggregate
+	cutoffs: BTreeMap<(String, u128, &'static str), Timestamp>,
+}
+
+impl RolledUp
+{
+	pub(crate) fn load(dir: &Path) -> std::io::Result<RolledUp>
+	{
+		let path = dir.join(ROLLED_UP_FILE);
+		let text = match std::fs::read_to_string(&path)
+		{
+			Ok(t) => t,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+				return Ok(RolledUp::default()),
+			Err(e) => return Err(e),
+		};
+
+		let mut rolled_up = RolledUp::default();
+		for (number, line) in text.lines().enumerate()
+		{
+			let words: Vec<&str> = line.split_whitespace().collect();
+			let parsed = match words[..]
+			{
+				[wildcard, interval, aggregate, cutoff] =>
+					interval.parse().ok()
+						.zip(Aggregate::from_name(aggregate))
+						.zip(cutoff.parse().ok())
+						.map(|((i, a), c)| ((wildcard.to_string(), i, a.name()), c)),
+				_ => None,
+			};
+			let (rule, cutoff) = parsed.ok_or_else(
+				|| std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					format!("{}: line {} is invalid", path.display(), number+1),
+				)
+			)?;
+			rolled_up.cutoffs.insert(rule, cutoff);
+		}
+		Ok(rolled_up)
+	}
+
+	pub(crate) fn save(&self, dir: &Path) -> std::io::Result<()>
+	{
+		let path = dir.join(ROLLED_UP_FILE);
+		if self.cutoffs.is_empty()
+		{
+			return match std::fs::remove_file(&path)
+			{
+				Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+				_ => Ok(()),
+			};
+		}
+
+		let mut text = String::new();
+		for ((wildcard, interval, aggregate), cutoff) in &self.cutoffs
+		{
+			text += &format!("{} {} {} {}\n", wildcard, interval, aggregate, cutoff);
+		}
+		let tmp = dir.join(format!("{}.tmp", ROLLED_UP_FILE));
+		std::fs::write(&tmp, text)?;
+		std::fs::rename(&tmp, &path)
+	}
+
+	fn id(rule: &RollupRule) -> (String, u128, &'static str)
+	{
+		(rule.wildcard.clone(), rule.interval.as_nanos(), rule.aggregates[0].name())
+	}
+
+	// periods before this were rolled up by the rule
+	fn cutoff(&self, rule: &RollupRule) -> Option<Timestamp>
+	{
+		self.cutoffs.get(&Self::id(rule)).copied()
+	}
+}
+
+struct Period
+{
+	start: Timestamp,
+	records: u64,
+	sum: Vec<f64>,
+	min: Vec<f64>,
+	max: Vec<f64>,
+	first: Vec<f64>,
+	last: Vec<f64>,
+	// the only record, if there's only one
+	only: Vec<u8>,
+}
+
+// the rule that applies to the key being read
+struct Current
+{
+	rule: RollupRule,
+	row_format: Box<dyn RowFormat>,
+	cutoff: Timestamp,
+	// the periods that were already rolled up
+	rolled_up: BTreeSet<Timestamp>,
+}
+
+/// Rolls up records that are sorted by key.
+///
+/// The records, rolled up or not, are passed to `out` in order,
+/// including those of the keys the aggregates are written to.
+pub(crate) struct RollupFilter
+{
+	rollups: Rollups,
+	// the database being compacted, to find existing aggregates
+	db: Arc<DatabaseReader>,
+	previous: RolledUp,
+	now: Timestamp,
+	key: Option<String>,
+	format: String,
+	current: Option<Current>,
+	period: Option<Period>,
+	// records for other keys, waiting for their turn
+	pending: BTreeMap<(String, Timestamp), (String, Vec<u8>)>,
+	numbers: Vec<f64>,
+	/// The number of records replaced by aggregates
+	pub(crate) rolled_up: u64,
+}
+
+impl RollupFilter
+{
+	pub(crate) fn new(
+		rollups: Rollups,
+		previous: RolledUp,
+		db: Arc<DatabaseReader>,
+	) -> RollupFilter
+	{
+		let now = std::time::SystemTime::now()
+			.duration_since(std::time::SystemTime::UNIX_EPOCH)
+			.expect("duration_since epoch")
+			.as_nanos() as Timestamp;
+		RollupFilter
+		{
+			rollups,
+			db,
+			previous,
+			now,
+			key: None,
+			format: String::new(),
+			current: None,
+			period: None,
+			pending: BTreeMap::new(),
+			numbers: vec!(),
+			rolled_up: 0,
+		}
+	}
+
+	pub(crate) fn rollups(&self) -> &Rollups
+	{
+		&self.rollups
+	}
+
+	/// How far the same-key rules have rolled up, once this is done
+	pub(crate) fn rolled_up_to(&self) -> RolledUp
+	{
+		let mut cutoffs = BTreeMap::new();
+		for rule in self.rollups.rules.iter().map(|r| &r.rule).filter(|r| r.same_key)
+		{
+			let id = RolledUp::id(rule);
+			let cutoff = self.cutoff(rule).max(self.previous.cutoff(rule).unwrap_or(0));
+			cutoffs.insert(id, cutoff);
+		}
+		RolledUp { cutoffs }
+	}
+
+	fn cutoff(&self, rule: &RollupRule) -> Timestamp
+	{
+		let interval = rule.interval.as_nanos() as Timestamp;
+		let cutoff = self.now.saturating_sub(rule.after.as_nanos() as Timestamp);
+		// only whole periods are rolled up
+		cutoff - cutoff % interval
+	}
+
+	/// Add the next record
+	pub(crate) fn add<E: From<std::io::Error>>(
+		&mut self,
+		key: &str,
+		format: &str,
+		value: &[u8],
+		out: &mut Output<E>,
+	) -> Result<(), E>
+	{
+		if self.key.as_deref() != Some(key)
+		{
+			self.close_period(out)?;
+			self.start_key(key, format);
+		}
+
+		let ts = BigEndian::read_u64(value);
+		let mut period_start = None;
+		if let Some(current) = self.current.as_ref()
+		{
+			self.numbers.clear();
+			let numeric = format == self.format
+				&& current.row_format.to_numbers(&value[8..], &mut self.numbers).is_ok();
+			let interval = current.rule.interval.as_nanos() as Timestamp;
+			let start = ts - ts % interval;
+			if ts < current.cutoff && numeric && !current.rolled_up.contains(&start)
+			{
+				period_start = Some(start);
+			}
+		}
+
+		if let Some(start) = period_start
+		{
+			if self.period.as_ref().map(|p| p.start) != Some(start)
+			{
+				self.close_period(out)?;
+			}
+			self.add_to_period(start, value);
+			return Ok(());
+		}
+
+		self.close_period(out)?;
+		self.emit(key, format, value, out)
+	}
+
+	/// Write everything still waiting whose key is before
+	/// `before`, or everything if it's `None`.
+	pub(crate) fn flush<E: From<std::io::Error>>(
+		&mut self,
+		before: Option<&str>,
+		out: &mut Output<E>,
+	) -> Result<(), E>
+	{
+		self.close_period(out)?;
+		while let Some(((k, _), _)) = self.pending.first_key_value()
+		{
+			if let Some(before) = before
+			{
+				if &k[..] >= before { break; }
+			}
+			let ((k, _), (f, v)) = self.pending.pop_first().unwrap();
+			out(&k, &f, &v)?;
+		}
+		Ok(())
+	}
+
+	fn start_key(&mut self, key: &str, format: &str)
+	{
+		self.key = Some(key.to_owned());
+		self.format.replace_range(.., format);
+		self.current = None;
+
+		let numeric = format.bytes().all(|c| b"iuIUfF".contains(&c));
+		if !numeric { return; }
+
+		if let Some(rule) = self.rollups.rule_for(key)
+		{
+			let interval = rule.rule.interval.as_nanos() as Timestamp;
+			let cutoff = self.cutoff(&rule.rule);
+
+			let mut rolled_up = BTreeSet::new();
+			if rule.rule.same_key
+			{
+				// an aggregate is at the start of its period in `main`,
+				// while a period that's new to `main` was backfilled
+				let previous = self.previous.cutoff(&rule.rule).unwrap_or(0).min(cutoff);
+				let main = self.db.files()
+					.find(|(path, _)| path.file_name() == Some("main".as_ref()));
+				if let Some((_, main)) = main
+				{
+					rolled_up.extend(
+						main.get(key)
+							.map(|r| r.timestamp())
+							.take_while(|&ts| ts < previous)
+							.filter(|&ts| ts % interval == 0)
+					);
+				}
+			}
+			else
+			{
+				for aggregate in &rule.rule.aggregates
+				{
+					let made = format!("{}.{}", key, aggregate.name());
+					rolled_up.extend(
+						self.db.get(&made)
+							.map(|r| r.timestamp())
+							.take_while(|&ts| ts < cutoff)
+					);
+				}
+			}
+
+			self.current = Some(Current
+			{
+				rule: rule.rule.clone(),
+				row_format: parse_row_format(format),
+				cutoff,
+				rolled_up,
+			});
+		}
+	}
+
+	fn add_to_period(&mut self, start: Timestamp, value: &[u8])
+	{
+		let numbers = &self.numbers;
+		let period = self.period.get_or_insert_with(
+			|| Period
+			{
+				start,
+				records: 0,
+				sum: vec![0.0; numbers.len()],
+				min: numbers.clone(),
+				max: numbers.clone(),
+				first: numbers.clone(),
+				last: vec!(),
+				only: value.to_owned(),
+			}
+		);
+		period.records += 1;
+		for (i, n) in numbers.iter().enumerate()
+		{
+			period.sum[i] += n;
+			period.min[i] = period.min[i].min(*n);
+			period.max[i] = period.max[i].max(*n);
+		}
+		period.last.clone_from(numbers);
+	}
+
+	fn close_period<E: From<std::io::Error>>(
+		&mut self,
+		out: &mut Output<E>,
+	) -> Result<(), E>
+	{
+		let period = match self.period.take()
+		{
+			Some(p) => p,
+			None => return Ok(()),
+		};
+		let key = self.key.clone().expect("period without a key");
+		let format = self.format.clone();
+		let current = self.current.as_ref().expect("period without a rule");
+		let encoding_failed = |e: String|
+			std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("rolling up {}: {}", key, e),
+			);
+
+		let values = |aggregate: &Aggregate| -> Vec<f64>
+		{
+			match aggregate
+			{
+				Aggregate::Mean =>
+					period.sum.iter().map(|s| s / period.records as f64).collect(),
+				Aggregate::Min => period.min.clone(),
+				Aggregate::Max => period.max.clone(),
+				Aggregate::Sum => period.sum.clone(),
+				Aggregate::First => period.first.clone(),
+				Aggregate::Last => period.last.clone(),
+				Aggregate::Count => vec![period.records as f64],
+			}
+		};
+
+		if current.rule.same_key
+		{
+			// already rolled up
+			let row =
+				if period.records == 1 && BigEndian::read_u64(&period.only) == period.start
+				{
+					period.only.clone()
+				}
+				else
+				{
+					self.rolled_up += period.records;
+					let mut r = vec!();
+					current.row_format
+						.numbers_to_stored_format(
+							period.start,
+							&values(&current.rule.aggregates[0]),
+							&mut r,
+						)
+						.map_err(encoding_failed)?;
+					r
+				};
+			return self.emit(&key, &format, &row, out);
+		}
+
+		self.rolled_up += period.records;
+		for aggregate in &current.rule.aggregates
+		{
+			let agg_format = match aggregate
+			{
+				Aggregate::Mean | Aggregate::Sum => "F".repeat(format.len()),
+				Aggregate::Count => "U".to_string(),
+				_ => format.clone(),
+			};
+			let mut row = vec!();
+			parse_row_format(&agg_format)
+				.numbers_to_stored_format(period.start, &values(aggregate), &mut row)
+				.map_err(encoding_failed)?;
+			self.pending.insert(
+				(format!("{}.{}", key, aggregate.name()), period.start),
+				(agg_format, row),
+			);
+		}
+		Ok(())
+	}
+
+	// write a record, after any pending ones that come before it
+	fn emit<E>(
+		&mut self,
+		key: &str,
+		format: &str,
+		value: &[u8],
+		out: &mut Output<E>,
+	) -> Result<(), E>
+	{
+		let ts = BigEndian::read_u64(value);
+		while let Some(((k, t), _)) = self.pending.first_key_value()
+		{
+			if (&k[..], *t) > (key, ts) { break; }
+			let replaces = (&k[..], *t) == (key, ts);
+			let ((k, _), (f, v)) = self.pending.pop_first().unwrap();
+			out(&k, &f, &v)?;
+			// a new aggregate replaces the old one
+			if replaces { return Ok(()); }
+		}
+		out(key, format, value)
+	}
+}
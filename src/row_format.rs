@@ -14,9 +14,58 @@ pub trait RowFormat
 	/// Decode the data into something human readable
 	fn to_protocol_format(&self, from: &[u8], dest: &mut dyn ::std::io::Write)
 		-> ::std::io::Result<()>;
+	/// Like [`to_protocol_format`](#tymethod.to_protocol_format), but
+	/// floats are written so that they parse back to exactly the same value.
+	///
+	/// By default, the same as `to_protocol_format`.
+	fn to_exact_protocol_format(&self, from: &[u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<()>
+	{
+		self.to_protocol_format(from, dest)
+	}
+	/// Write the data as a JSON array, with a number for each numeric
+	/// column and a string for each string. Floats that aren't
+	/// finite are `null`.
+	///
+	/// By default, the numbers from [`to_numbers`](#method.to_numbers),
+	/// or else the human readable format as a single string.
+	fn to_json_format(&self, from: &[u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<()>
+	{
+		let mut numbers = vec!();
+		if self.to_numbers(from, &mut numbers).is_ok()
+		{
+			serde_json::to_writer(dest, &numbers)?;
+		}
+		else
+		{
+			let mut text = vec!();
+			self.to_protocol_format(from, &mut text)?;
+			serde_json::to_writer(dest, &[String::from_utf8_lossy(&text)])?;
+		}
+		Ok(())
+	}
 	/// The minimum size in bytes of a row payload, including its timestamp
 	/// (Exceeded in rows with string data)
 	fn row_size(&self) -> usize;
+	/// Decode the data (after its timestamp) as one number per column.
+	///
+	/// Fails if a column isn't numeric, and by default.
+	fn to_numbers(&self, _from: &[u8], _dest: &mut Vec<f64>)
+		-> Result<(), String>
+	{
+		Err("not a numeric format".to_string())
+	}
+	/// Encode one number per column into `dest`, like
+	/// [`to_stored_format`](#tymethod.to_stored_format). Integer
+	/// columns are rounded.
+	///
+	/// Fails by default.
+	fn numbers_to_stored_format(&self, _ts: Timestamp, _from: &[f64], _dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		Err("not a numeric format".to_string())
+	}
 }
 
 
@@ -59,10 +108,65 @@ impl RowFormat for RowFormatImpl
 		}
 		Ok(())
 	}
+	fn to_exact_protocol_format(&self, mut from: &[u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<()>
+	{
+		let mut first = true;
+
+		for e in self.elements.iter()
+		{
+			if !first
+			{
+				write!(dest, " ")?;
+			}
+			first = false;
+			from = e.to_exact_protocol_format(from, dest)?;
+		}
+		Ok(())
+	}
+	fn to_json_format(&self, mut from: &[u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<()>
+	{
+		write!(dest, "[")?;
+		for (idx, e) in self.elements.iter().enumerate()
+		{
+			if idx != 0
+			{
+				write!(dest, ",")?;
+			}
+			from = e.to_json_format(from, dest)?;
+		}
+		write!(dest, "]")
+	}
 	fn row_size(&self) -> usize
 	{
 		self.size+8
 	}
+	fn to_numbers(&self, mut from: &[u8], dest: &mut Vec<f64>)
+		-> Result<(), String>
+	{
+		for e in self.elements.iter()
+		{
+			let (v, rest) = e.to_number(from)?;
+			dest.push(v);
+			from = rest;
+		}
+		Ok(())
+	}
+	fn numbers_to_stored_format(&self, ts: Timestamp, from: &[f64], dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		if from.len() != self.elements.len()
+			{ return Err("wrong number of columns".to_string()); }
+		let at = dest.len();
+		dest.resize(at+8, 0);
+		BigEndian::write_u64(&mut dest[at..], ts);
+		for (e, v) in self.elements.iter().zip(from)
+		{
+			e.number_to_stored_format(*v, dest)?;
+		}
+		Ok(())
+	}
 
 }
 
@@ -82,6 +186,7 @@ impl RowFormat for RowFormatImpl
 /// Potential future types:
 /// * decimal
 /// * large integers, floats (128 bit, 256 bit)
+///
 /// to indicate "typical size"). The typical size is useful
 /// for knowing how big to make the blocks
 pub fn parse_row_format(human: &str) -> Box<dyn RowFormat>
@@ -89,8 +194,7 @@ pub fn parse_row_format(human: &str) -> Box<dyn RowFormat>
 	let human = human.as_bytes();
 
 	let mut size = 0usize;
-	let mut elements: Vec<Box<dyn Element>> = vec!();
-	elements.reserve(human.len());
+	let mut elements: Vec<Box<dyn Element>> = Vec::with_capacity(human.len());
 
 	for t in human
 	{
@@ -141,8 +245,8 @@ pub fn parse_row_format(human: &str) -> Box<dyn RowFormat>
 	Box::new(
 		RowFormatImpl
 		{
-			size: size,
-			elements: elements,
+			size,
+			elements,
 		}
 	)
 }
@@ -154,6 +258,19 @@ trait Element
 		-> Result<&'s str, String>;
 	fn to_protocol_format<'a>(&self, from: &'a [u8], dest: &mut dyn ::std::io::Write)
 		-> ::std::io::Result<&'a [u8]>;
+	fn to_exact_protocol_format<'a>(&self, from: &'a [u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<&'a [u8]>
+	{
+		self.to_protocol_format(from, dest)
+	}
+	fn to_json_format<'a>(&self, from: &'a [u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<&'a [u8]>
+	{
+		self.to_exact_protocol_format(from, dest)
+	}
+	fn to_number<'a>(&self, from: &'a [u8]) -> Result<(f64, &'a [u8]), String>;
+	fn number_to_stored_format(&self, v: f64, dest: &mut Vec<u8>)
+		-> Result<(), String>;
 }
 
 struct ElementI32;
@@ -164,13 +281,13 @@ impl Element for ElementI32
 	{
 		let at = dest.len();
 		dest.resize(at + 4, 0);
-		let mut dest = &mut dest[at..];
+		let dest = &mut dest[at..];
 
 		let (t, rest) = split_one(from).unwrap();
 
 		let v = t.parse()
 			.map_err(|e| format!("while parsing {}: {}", t, e))?;
-		BigEndian::write_i32(&mut dest, v);
+		BigEndian::write_i32(dest, v);
 
 		Ok(rest)
 	}
@@ -181,6 +298,18 @@ impl Element for ElementI32
 		write!(dest, "{}", v)?;
 		Ok(&from[4..])
 	}
+	fn to_number<'a>(&self, from: &'a [u8]) -> Result<(f64, &'a [u8]), String>
+	{
+		Ok((BigEndian::read_i32(&from[0..4]) as f64, &from[4..]))
+	}
+	fn number_to_stored_format(&self, v: f64, dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		let at = dest.len();
+		dest.resize(at + 4, 0);
+		BigEndian::write_i32(&mut dest[at..], v.round() as i32);
+		Ok(())
+	}
 }
 
 struct ElementU32;
@@ -191,13 +320,13 @@ impl Element for ElementU32
 	{
 		let at = dest.len();
 		dest.resize(at + 4, 0);
-		let mut dest = &mut dest[at..];
+		let dest = &mut dest[at..];
 
 		let (t, rest) = split_one(from).unwrap();
 
 		let v = t.parse()
 			.map_err(|e| format!("while parsing {}: {}", t, e))?;
-		BigEndian::write_u32(&mut dest, v);
+		BigEndian::write_u32(dest, v);
 
 		Ok(rest)
 	}
@@ -208,6 +337,18 @@ impl Element for ElementU32
 		write!(dest, "{}", v)?;
 		Ok(&from[4..])
 	}
+	fn to_number<'a>(&self, from: &'a [u8]) -> Result<(f64, &'a [u8]), String>
+	{
+		Ok((BigEndian::read_u32(&from[0..4]) as f64, &from[4..]))
+	}
+	fn number_to_stored_format(&self, v: f64, dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		let at = dest.len();
+		dest.resize(at + 4, 0);
+		BigEndian::write_u32(&mut dest[at..], v.round() as u32);
+		Ok(())
+	}
 }
 
 struct ElementI64;
@@ -218,13 +359,13 @@ impl Element for ElementI64
 	{
 		let at = dest.len();
 		dest.resize(at + 8, 0);
-		let mut dest = &mut dest[at..];
+		let dest = &mut dest[at..];
 
 		let (t, rest) = split_one(from).unwrap();
 
 		let v = t.parse()
 			.map_err(|e| format!("while parsing {}: {}", t, e))?;
-		BigEndian::write_i64(&mut dest, v);
+		BigEndian::write_i64(dest, v);
 
 		Ok(rest)
 	}
@@ -235,6 +376,18 @@ impl Element for ElementI64
 		write!(dest, "{}", v)?;
 		Ok(&from[8..])
 	}
+	fn to_number<'a>(&self, from: &'a [u8]) -> Result<(f64, &'a [u8]), String>
+	{
+		Ok((BigEndian::read_i64(&from[0..8]) as f64, &from[8..]))
+	}
+	fn number_to_stored_format(&self, v: f64, dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		let at = dest.len();
+		dest.resize(at + 8, 0);
+		BigEndian::write_i64(&mut dest[at..], v.round() as i64);
+		Ok(())
+	}
 }
 
 struct ElementU64;
@@ -245,13 +398,13 @@ impl Element for ElementU64
 	{
 		let at = dest.len();
 		dest.resize(at + 8, 0);
-		let mut dest = &mut dest[at..];
+		let dest = &mut dest[at..];
 
 		let (t, rest) = split_one(from).unwrap();
 
 		let v = t.parse()
 			.map_err(|e| format!("while parsing {}: {}", t, e))?;
-		BigEndian::write_u64(&mut dest, v);
+		BigEndian::write_u64(dest, v);
 
 		Ok(rest)
 	}
@@ -262,6 +415,18 @@ impl Element for ElementU64
 		write!(dest, "{}", v)?;
 		Ok(&from[8..])
 	}
+	fn to_number<'a>(&self, from: &'a [u8]) -> Result<(f64, &'a [u8]), String>
+	{
+		Ok((BigEndian::read_u64(&from[0..8]) as f64, &from[8..]))
+	}
+	fn number_to_stored_format(&self, v: f64, dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		let at = dest.len();
+		dest.resize(at + 8, 0);
+		BigEndian::write_u64(&mut dest[at..], v.round() as u64);
+		Ok(())
+	}
 }
 
 
@@ -273,20 +438,19 @@ impl Element for ElementF32
 	{
 		let at = dest.len();
 		dest.resize(at + 4, 0);
-		let mut dest = &mut dest[at..];
+		let dest = &mut dest[at..];
 
 		let (t, rest) = split_one(from).unwrap();
 
-		let v;
-
-		if t == "nan"
-			{ v = ::std::f32::NAN; }
-		else
-		{
-			v = t.parse()
-				.map_err(|e| format!("while parsing {}: {}", t, e))?;
-		}
-		BigEndian::write_f32(&mut dest, v);
+		let v =
+			if t == "nan"
+				{ f32::NAN }
+			else
+			{
+				t.parse()
+					.map_err(|e| format!("while parsing {}: {}", t, e))?
+			};
+		BigEndian::write_f32(dest, v);
 
 		Ok(rest)
 	}
@@ -297,6 +461,36 @@ impl Element for ElementF32
 		write!(dest, "{:.17}", v)?;
 		Ok(&from[4..])
 	}
+	fn to_exact_protocol_format<'a>(&self, from: &'a [u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<&'a [u8]>
+	{
+		// the shortest text that parses back to the same value
+		let v: f32 = BigEndian::read_f32(&from[0..4]);
+		write!(dest, "{:?}", v)?;
+		Ok(&from[4..])
+	}
+	fn to_json_format<'a>(&self, from: &'a [u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<&'a [u8]>
+	{
+		let v: f32 = BigEndian::read_f32(&from[0..4]);
+		if v.is_finite()
+			{ write!(dest, "{:?}", v)?; }
+		else
+			{ write!(dest, "null")?; }
+		Ok(&from[4..])
+	}
+	fn to_number<'a>(&self, from: &'a [u8]) -> Result<(f64, &'a [u8]), String>
+	{
+		Ok((BigEndian::read_f32(&from[0..4]) as f64, &from[4..]))
+	}
+	fn number_to_stored_format(&self, v: f64, dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		let at = dest.len();
+		dest.resize(at + 4, 0);
+		BigEndian::write_f32(&mut dest[at..], v as f32);
+		Ok(())
+	}
 }
 
 struct ElementF64;
@@ -307,20 +501,19 @@ impl Element for ElementF64
 	{
 		let at = dest.len();
 		dest.resize(at + 8, 0);
-		let mut dest = &mut dest[at..];
+		let dest = &mut dest[at..];
 
 		let (t, rest) = split_one(from).unwrap();
 
-		let v;
-
-		if t == "nan"
-			{ v = ::std::f64::NAN; }
-		else
-		{
-			v = t.parse()
-				.map_err(|e| format!("while parsing {}: {}", t, e))?;
-		}
-		BigEndian::write_f64(&mut dest, v);
+		let v =
+			if t == "nan"
+				{ f64::NAN }
+			else
+			{
+				t.parse()
+					.map_err(|e| format!("while parsing {}: {}", t, e))?
+			};
+		BigEndian::write_f64(dest, v);
 
 		Ok(rest)
 	}
@@ -331,6 +524,35 @@ impl Element for ElementF64
 		write!(dest, "{:.17}", v)?;
 		Ok(&from[8..])
 	}
+	fn to_exact_protocol_format<'a>(&self, from: &'a [u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<&'a [u8]>
+	{
+		let v: f64 = BigEndian::read_f64(&from[0..8]);
+		write!(dest, "{:?}", v)?;
+		Ok(&from[8..])
+	}
+	fn to_json_format<'a>(&self, from: &'a [u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<&'a [u8]>
+	{
+		let v: f64 = BigEndian::read_f64(&from[0..8]);
+		if v.is_finite()
+			{ write!(dest, "{:?}", v)?; }
+		else
+			{ write!(dest, "null")?; }
+		Ok(&from[8..])
+	}
+	fn to_number<'a>(&self, from: &'a [u8]) -> Result<(f64, &'a [u8]), String>
+	{
+		Ok((BigEndian::read_f64(&from[0..8]), &from[8..]))
+	}
+	fn number_to_stored_format(&self, v: f64, dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		let at = dest.len();
+		dest.resize(at + 8, 0);
+		BigEndian::write_f64(&mut dest[at..], v);
+		Ok(())
+	}
 }
 
 struct ElementString;
@@ -362,4 +584,28 @@ impl Element for ElementString
 		write!(dest, "{}", escape_string::escape(s))?;
 		Ok(&tail[len as usize..])
 	}
+	fn to_json_format<'a>(&self, from: &'a [u8], dest: &mut dyn ::std::io::Write)
+		-> ::std::io::Result<&'a [u8]>
+	{
+		let (len, tail) = unsigned_varint::decode::u64(from)
+			.map_err(
+				|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+			)?;
+
+		let s = std::str::from_utf8(&tail[0 .. len as usize])
+			.map_err(
+				|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+			)?;
+		serde_json::to_writer(&mut *dest, s)?;
+		Ok(&tail[len as usize..])
+	}
+	fn to_number<'a>(&self, _from: &'a [u8]) -> Result<(f64, &'a [u8]), String>
+	{
+		Err("a string is not a number".to_string())
+	}
+	fn number_to_stored_format(&self, _v: f64, _dest: &mut Vec<u8>)
+		-> Result<(), String>
+	{
+		Err("a string is not a number".to_string())
+	}
 }
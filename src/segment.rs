@@ -23,14 +23,13 @@ impl<'data> Segment<'data>
 	pub(crate) fn scan(from: &'data [u8], origin: usize)
 		-> Option<Segment<'data>>
 	{
-		let at = twoway::find_bytes(from, SEGMENT_INVOCATION);
-		if at.is_none() { return None; }
-		let at = at.unwrap() + SEGMENT_INVOCATION.len();
+		let at = twoway::find_bytes(from, SEGMENT_INVOCATION)?
+			+ SEGMENT_INVOCATION.len();
 
 		if from[at ..].len() < 16 { return None; }
 
 		// the length of the first key
-		let len1 = BigEndian::read_u32(&from[at+0 .. at+4]) as usize;
+		let len1 = BigEndian::read_u32(&from[at .. at+4]) as usize;
 		// the length of the last key
 		let len2 = BigEndian::read_u32(&from[at+4 .. at+8]) as usize;
 		// the length of the payload
@@ -63,3 +62,70 @@ impl<'data> Segment<'data>
 
 }
 
+// decompress a segment's payload into `into`
+pub(crate) fn decompress(payload: &[u8], into: &mut Vec<u8>) -> std::io::Result<()>
+{
+	use std::io::Read;
+	into.clear();
+	lz4::Decoder::new(std::io::Cursor::new(payload))?
+		.read_to_end(into)?;
+	Ok(())
+}
+
+// call `f` with the key, format and value of each
+// record in a decompressed segment
+pub(crate) fn for_each_record<E, F>(decoded: &[u8], mut f: F) -> Result<(), E>
+where
+	E: From<std::io::Error>,
+	F: FnMut(&str, &str, &[u8]) -> Result<(), E>,
+{
+	for_each_key(
+		decoded,
+		|key, format, rlen, data|
+		{
+			for value in data.chunks(rlen)
+			{
+				f(key, format, value)?;
+			}
+			Ok(())
+		}
+	)
+}
+
+// call `f` with the key, format, record length and all of the
+// records of each key in a decompressed segment
+pub(crate) fn for_each_key<E, F>(decoded: &[u8], mut f: F) -> Result<(), E>
+where
+	E: From<std::io::Error>,
+	F: FnMut(&str, &str, usize, &[u8]) -> Result<(), E>,
+{
+	let invalid = |what: &str|
+		std::io::Error::new(std::io::ErrorKind::InvalidData, format!("segment has {}", what));
+
+	let mut pos = 0;
+	while pos < decoded.len()
+	{
+		if decoded.len() - pos < 16
+			{ return Err(invalid("a truncated key header").into()); }
+		let klen = BigEndian::read_u32(&decoded[pos .. pos+4]) as usize;
+		let flen = BigEndian::read_u32(&decoded[pos+4 .. pos+8]) as usize;
+		let rlen = BigEndian::read_u32(&decoded[pos+8 .. pos+12]) as usize;
+		let dlen = BigEndian::read_u32(&decoded[pos+12 .. pos+16]) as usize;
+		pos += 16;
+		if decoded.len() - pos < klen + flen + dlen
+			{ return Err(invalid("a truncated key").into()); }
+		if rlen < 8 || !dlen.is_multiple_of(rlen)
+			{ return Err(invalid("an invalid record length").into()); }
+
+		let key = std::str::from_utf8(&decoded[pos .. pos+klen])
+			.map_err(|_| invalid("a key that isn't UTF-8"))?;
+		pos += klen;
+		let format = std::str::from_utf8(&decoded[pos .. pos+flen])
+			.map_err(|_| invalid("a format that isn't UTF-8"))?;
+		pos += flen;
+
+		f(key, format, rlen, &decoded[pos .. pos+dlen])?;
+		pos += dlen;
+	}
+	Ok(())
+}
@@ -65,6 +65,12 @@ impl SegmentReader
 		Ok(())
 	}
 
+	/// The size of the file
+	pub(crate) fn len(&self) -> usize
+	{
+		self.len
+	}
+
 	pub(crate) fn first<'s>(&'s self) -> Option<Segment<'s>>
 	{
 		Segment::scan(&self.map[..], 0)
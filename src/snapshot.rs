@@ -0,0 +1,272 @@
+//! Consistent backups of a database.
+//!
+//! [`snapshot_database`] holds the compaction lock while it copies a
+//! database, so that no compaction replaces `main` or deletes
+//! transactions partway through. The files that a
+//! [`DatabaseReader`](../struct.DatabaseReader.html) would open are
+//! hard-linked, which costs no space because they never change, or
+//! copied if they're on another filesystem. The files that are edited
+//! in place (`meta`, `retention` and `rollup`) are always copied.
+//!
+//! The snapshot is itself a database that can be read, and
+//! it has a file named `manifest` that lists what's in it:
+//!
+//! ```text
+//! snapshot 1600000000
+//! file 1234 main
+//! file 567 tx.0016338d3b8d2f00.00001a2b
+//! ```
+//!
+//! [`restore_database`] checks a snapshot against its
+//! manifest and copies it to a new database.
+
+use std::path::{Path,PathBuf};
+use std::time::{Duration,SystemTime};
+
+use crate::database_reader::transaction_files;
+use crate::lock::{Lock,LockWait};
+
+/// The name of the manifest file in a snapshot
+pub const MANIFEST_FILE: &str = "manifest";
+
+// copied, not linked, because they're edited in place
+const CONFIG_FILES: &[&str] =
+	&[
+		crate::database::META_FILE,
+		crate::retention::RETENTION_FILE,
+		crate::rollup::ROLLUP_FILE,
+		crate::rollup::ROLLED_UP_FILE,
+	];
+
+/// One file of a snapshot.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SnapshotFile
+{
+	/// Its name in the database directory
+	pub name: String,
+	/// Its size
+	pub bytes: u64,
+}
+
+/// What a snapshot contains, according to its manifest.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Snapshot
+{
+	/// The snapshot's directory
+	pub dir: PathBuf,
+	/// When it was taken
+	pub created: SystemTime,
+	/// Each of its files, `main` last
+	pub files: Vec<SnapshotFile>,
+	/// How many of the files were copied instead of linked,
+	/// when it was taken or restored
+	pub copied: usize,
+}
+
+impl Snapshot
+{
+	/// Read the manifest of the snapshot at `dir`
+	pub fn load(dir: &Path) -> std::io::Result<Snapshot>
+	{
+		let path = dir.join(MANIFEST_FILE);
+		let text = std::fs::read_to_string(&path)
+			.map_err(
+				|e| std::io::Error::new(
+					e.kind(),
+					format!("{} is not a snapshot: {}", dir.display(), e),
+				)
+			)?;
+		let invalid = |what: String|
+			std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("{}: {}", path.display(), what),
+			);
+
+		let mut created = None;
+		let mut files = vec!();
+		for (number, line) in text.lines().enumerate()
+		{
+			let (name, value) = line.split_once(' ')
+				.ok_or_else(|| invalid(format!("line {}: expected a name and a value", number+1)))?;
+			match name
+			{
+				"snapshot" => created = value.parse().ok()
+					.map(|s| SystemTime::UNIX_EPOCH + Duration::from_secs(s)),
+				"file" =>
+				{
+					let file = value.split_once(' ')
+						.and_then(|(bytes, name)| Some(SnapshotFile
+						{
+							name: name.to_owned(),
+							bytes: bytes.parse().ok()?,
+						}))
+						.ok_or_else(|| invalid(format!("line {}: expected a size and a name", number+1)))?;
+					files.push(file);
+				},
+				_ => {},
+			}
+		}
+
+		Ok(Snapshot
+		{
+			dir: dir.to_owned(),
+			created: created.ok_or_else(|| invalid("it has no time".to_string()))?,
+			files,
+			copied: 0,
+		})
+	}
+
+	/// The total size of the files
+	pub fn bytes(&self) -> u64
+	{
+		self.files.iter().map(|f| f.bytes).sum()
+	}
+
+	fn write_manifest(&self) -> std::io::Result<()>
+	{
+		let created = self.created.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap_or_default();
+		let mut text = format!("snapshot {}\n", created.as_secs());
+		for file in &self.files
+		{
+			text += &format!("file {} {}\n", file.bytes, file.name);
+		}
+		// renamed into place, so a snapshot with a manifest is complete
+		let tmp = self.dir.join(format!("{}.tmp", MANIFEST_FILE));
+		std::fs::write(&tmp, text)?;
+		std::fs::File::open(&tmp)?.sync_all()?;
+		std::fs::rename(&tmp, self.dir.join(MANIFEST_FILE))
+	}
+}
+
+impl std::fmt::Display for Snapshot
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		write!(
+			f,
+			"{}: {} files, {} bytes ({} copied, {} linked)",
+			self.dir.display(), self.files.len(), self.bytes(),
+			self.copied, self.files.len() - self.copied,
+		)
+	}
+}
+
+/// Make a snapshot of the database at `dir` in the new directory `dest`.
+///
+/// It waits for a compaction to finish, and keeps another from
+/// starting until it's done. Transactions committed while it runs
+/// aren't in the snapshot.
+pub fn snapshot_database(dir: &Path, dest: &Path) -> std::io::Result<Snapshot>
+{
+	crate::database::check(dir)?;
+	check_empty(dest)?;
+	let _lock = Lock::acquire(dir, "snapshot", LockWait::Forever)?;
+	std::fs::create_dir_all(dest)?;
+
+	let mut snapshot = Snapshot
+	{
+		dir: dest.to_owned(),
+		created: SystemTime::now(),
+		files: vec!(),
+		copied: 0,
+	};
+
+	let mut names: Vec<String> = CONFIG_FILES.iter()
+		.filter(|name| dir.join(name).exists())
+		.map(|name| name.to_string())
+		.collect();
+	for path in transaction_files(dir)?
+	{
+		names.push(path.file_name().unwrap().to_string_lossy().into_owned());
+	}
+	names.push("main".to_string());
+
+	for name in names
+	{
+		let linked = transfer(&dir.join(&name), &dest.join(&name), !CONFIG_FILES.contains(&&name[..]))?;
+		if !linked
+			{ snapshot.copied += 1; }
+		let bytes = std::fs::metadata(dest.join(&name))?.len();
+		snapshot.files.push(SnapshotFile { name, bytes });
+	}
+
+	snapshot.write_manifest()?;
+	Ok(snapshot)
+}
+
+/// Make a new database at `dir` from the snapshot at `snapshot`.
+///
+/// Fails without changing anything if a file of the snapshot is
+/// missing or isn't the size its manifest says, or if there's
+/// already a database at `dir`.
+pub fn restore_database(snapshot: &Path, dir: &Path) -> std::io::Result<Snapshot>
+{
+	let mut restored = Snapshot::load(snapshot)?;
+	for file in &restored.files
+	{
+		let path = snapshot.join(&file.name);
+		let bytes = std::fs::metadata(&path)
+			.map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?
+			.len();
+		if bytes != file.bytes
+		{
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("{} has {} bytes, but the manifest says {}", path.display(), bytes, file.bytes),
+			));
+		}
+	}
+	if !restored.files.iter().any(|f| f.name == "main")
+	{
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("{} has no \"main\" file", snapshot.display()),
+		));
+	}
+	check_empty(dir)?;
+	std::fs::create_dir_all(dir)?;
+
+	// `main` last, so that it's not a database until it's complete
+	restored.files.sort_by_key(|f| f.name == "main");
+	for file in &restored.files
+	{
+		let linked = transfer(
+			&snapshot.join(&file.name),
+			&dir.join(&file.name),
+			!CONFIG_FILES.contains(&&file.name[..]),
+		)?;
+		if !linked
+			{ restored.copied += 1; }
+	}
+	restored.dir = dir.to_owned();
+	Ok(restored)
+}
+
+// fail if `dir` is already a database or a snapshot
+fn check_empty(dir: &Path) -> std::io::Result<()>
+{
+	for name in &["main", MANIFEST_FILE]
+	{
+		if dir.join(name).exists()
+		{
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::AlreadyExists,
+				format!("{} already has a \"{}\"", dir.display(), name),
+			));
+		}
+	}
+	Ok(())
+}
+
+// hard-link `from` to `to` if `link` and it's possible, otherwise
+// copy it; returns true if it was linked
+fn transfer(from: &Path, to: &Path, link: bool) -> std::io::Result<bool>
+{
+	if link && std::fs::hard_link(from, to).is_ok()
+		{ return Ok(true); }
+	std::fs::copy(from, to)
+		.map_err(|e| std::io::Error::new(e.kind(), format!("copying {}: {}", from.display(), e)))?;
+	std::fs::File::open(to)?.sync_all()?;
+	Ok(false)
+}
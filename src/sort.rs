@@ -0,0 +1,186 @@
+//! Sort records that may not fit in memory.
+//!
+//! [`CreateTx::unsorted`](../create_tx/struct.CreateTx.html#method.unsorted)
+//! uses an [`ExternalSort`] so that records can be added in any order.
+
+use std::path::{Path,PathBuf};
+
+use byteorder::{ByteOrder,BigEndian};
+
+use crate::key_reader::Reader;
+use crate::merge::Merge;
+use crate::write::{Writer,WriteFailure};
+
+/// Sorts records by key and timestamp.
+///
+/// Records are kept in memory until they take more than
+/// [`memory`](#method.memory) bytes, then each batch is sorted and
+/// written to an anonymous temporary file in
+/// [`spill_dir`](#method.spill_dir), and the files are merged at the end.
+///
+/// If the same key and timestamp is added more than once,
+/// the last one wins.
+pub struct ExternalSort
+{
+	memory: usize,
+	spill_dir: Option<PathBuf>,
+	rows: Vec<Row>,
+	bytes: usize,
+	spills: Vec<Reader>,
+}
+
+struct Row
+{
+	key: String,
+	format: String,
+	data: Vec<u8>,
+}
+
+impl Row
+{
+	fn timestamp(&self) -> u64
+	{
+		BigEndian::read_u64(&self.data[0..8])
+	}
+}
+
+impl Default for ExternalSort
+{
+	fn default() -> ExternalSort
+	{
+		ExternalSort
+		{
+			memory: 256*1024*1024,
+			spill_dir: None,
+			rows: vec!(),
+			bytes: 0,
+			spills: vec!(),
+		}
+	}
+}
+
+impl ExternalSort
+{
+	/// Keep up to 256MiB in memory, and spill to the system's
+	/// temporary directory
+	pub fn new() -> ExternalSort
+	{
+		Default::default()
+	}
+
+	/// Keep up to about this many bytes of records in memory
+	pub fn memory(mut self, bytes: usize) -> ExternalSort
+	{
+		self.memory = bytes;
+		self
+	}
+
+	/// Write the temporary files in this directory
+	pub fn spill_dir(mut self, dir: &Path) -> ExternalSort
+	{
+		self.spill_dir = Some(dir.to_owned());
+		self
+	}
+
+	/// Add a record with the given key, format, and payload, which
+	/// starts with the timestamp.
+	///
+	/// This fails if `data` is too short to have a timestamp, or if
+	/// the records in memory have to be written out and a key has
+	/// more than one format among them.
+	pub fn add_record(&mut self, key: &str, format: &str, data: &[u8])
+		-> Result<(), WriteFailure>
+	{
+		crate::write::check_timestamp(key, data)?;
+		self.bytes += std::mem::size_of::<Row>() + key.len() + format.len() + data.len();
+		self.rows.push(
+			Row
+			{
+				key: key.to_owned(),
+				format: format.to_owned(),
+				data: data.to_owned(),
+			}
+		);
+		if self.bytes >= self.memory
+		{
+			self.spill()?;
+		}
+		Ok(())
+	}
+
+	/// How many times the records were written to a temporary file
+	pub fn spills(&self) -> usize
+	{
+		self.spills.len()
+	}
+
+	// sort the rows, keeping the last of each key and timestamp
+	fn sort_rows(&mut self)
+	{
+		// a stable sort, so equal rows stay in the order they were added
+		self.rows.sort_by(
+			|a, b| a.key.cmp(&b.key).then_with(|| a.timestamp().cmp(&b.timestamp()))
+		);
+		let mut rows = std::mem::take(&mut self.rows).into_iter().peekable();
+		while let Some(row) = rows.next()
+		{
+			if let Some(next) = rows.peek()
+			{
+				if next.key == row.key && next.timestamp() == row.timestamp()
+					{ continue; }
+			}
+			self.rows.push(row);
+		}
+		self.bytes = 0;
+	}
+
+	fn spill(&mut self) -> Result<(), WriteFailure>
+	{
+		if self.rows.is_empty()
+			{ return Ok(()); }
+		self.sort_rows();
+
+		let file;
+		if let Some(dir) = self.spill_dir.as_ref()
+			{ file = tempfile::tempfile_in(dir)?; }
+		else
+			{ file = tempfile::tempfile()?; }
+		let mut writer = Writer::new(file);
+		for row in self.rows.drain(..)
+		{
+			writer.add_record(&row.key, &row.format, &row.data)?;
+		}
+		let (file, _) = writer.finish()?;
+		self.spills.push(Reader::new(file)?);
+		Ok(())
+	}
+
+	/// Call `add` with each record, in order
+	pub fn finish<F>(mut self, mut add: F)
+		-> Result<(), WriteFailure>
+	where
+		F: FnMut(&str, &str, &[u8]) -> Result<(), WriteFailure>
+	{
+		if self.spills.is_empty()
+		{
+			self.sort_rows();
+			for row in &self.rows
+			{
+				add(&row.key, &row.format, &row.data)?;
+			}
+			return Ok(());
+		}
+
+		self.spill()?;
+		let readers = self.spills.iter()
+			.map(|r| r.get_range(..))
+			.collect();
+		// on a tie, `Merge` takes the record from the last spill
+		let merge = Merge::new(readers, crate::database_reader::record_order);
+		for record in merge
+		{
+			add(record.key(), record.format(), record.value())?;
+		}
+		Ok(())
+	}
+}
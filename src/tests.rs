@@ -4,6 +4,14 @@ use crate::database_reader::DatabaseReader;
 use crate::write::Writer;
 use crate::Reader;
 use crate::formatted::*;
+use crate::database::*;
+use crate::info::*;
+use crate::fsck::*;
+use crate::sort::*;
+use crate::snapshot::*;
+use crate::diff::*;
+use crate::database_reader::record_order;
+use crate::auto_compact::CompactionPolicy;
 
 use std::io::BufWriter;
 
@@ -377,3 +385,1304 @@ fn database_merge_last()
 	assert_eq!(last.value()[8], 2);
 }
 
+
+#[cfg(feature="async")]
+#[tokio::test(threaded_scheduler)]
+async fn async_tx_and_stream()
+{
+	use crate::nonblocking::*;
+	use futures::stream::StreamExt;
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+
+	let mut tx = AsyncCreateTx::new(t.path()).await.unwrap();
+	for n in 0..100000u64
+	{
+		let mut buf = [0u8; 16];
+		byteorder::BigEndian::write_u64(&mut buf[..], n);
+		byteorder::BigEndian::write_u64(&mut buf[8..], n*2);
+		let key = if n < 50000 { "a" } else { "b" };
+		tx.add_record(key, "U", &buf).await.unwrap();
+	}
+	tx.commit().await.unwrap();
+
+	let db = std::sync::Arc::new(DatabaseReader::new(t.path()).unwrap());
+	let records: Vec<_> = stream_filter(db.clone(), crate::Wildcard::new("%"))
+		.collect().await;
+	assert_eq!(records.len(), 100000);
+	assert_eq!(records[49999].key(), "a");
+	assert_eq!(records[50000].key(), "b");
+
+	let b: Vec<_> = stream_key(db, "b".to_string()).collect().await;
+	assert_eq!(b.len(), 50000);
+}
+
+#[test]
+fn ingest_group_commit()
+{
+	use crate::ingest::*;
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+
+	let options = IngestOptions
+	{
+		max_delay: std::time::Duration::from_secs(60),
+		max_records: 40,
+		.. Default::default()
+	};
+	let buffer = std::sync::Arc::new(IngestBuffer::new(t.path(), options));
+
+	let producers: Vec<_> = (0..4u64)
+		.map(
+			|p|
+			{
+				let buffer = buffer.clone();
+				std::thread::spawn(
+					move ||
+					{
+						let mut batch = IngestBatch::new();
+						// unsorted, and sharing keys with the other producers
+						for n in (0..10u64).rev()
+						{
+							let mut buf = [0u8; 16];
+							byteorder::BigEndian::write_u64(&mut buf[..], n*4 + p);
+							byteorder::BigEndian::write_u64(&mut buf[8..], p);
+							batch.add_record(&format!("k{}", n%3), "U", &buf).unwrap();
+						}
+						buffer.submit(batch).unwrap().wait().unwrap();
+					}
+				)
+			}
+		)
+		.collect();
+	for p in producers { p.join().unwrap(); }
+
+	let r = DatabaseReader::new(t.path()).unwrap();
+	// the 40 records were committed all at once, replacing the empty main
+	assert_eq!(r.transaction_paths().len(), 1);
+	assert_eq!(r.get_range(..).count(), 40);
+	assert_eq!(r.get("k0").count(), 16);
+
+	let mut batch = IngestBatch::new();
+	assert!(batch.add_record("k0", "u", &[0u8; 4]).is_err());
+	batch.add_record("k0", "u", &[0u8; 12]).unwrap();
+	batch.add_record("k0", "U", &[1u8; 16]).unwrap();
+	match buffer.submit(batch)
+	{
+		Err(IngestError::HeterogeneousFormats(..)) => {},
+		_ => panic!("formats were not checked"),
+	}
+}
+
+#[test]
+fn rapid_commits()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	{
+		use std::io::Write;
+		let mut main = std::fs::File::create(t.path().join("main")).unwrap();
+		main.write_all(&[0u8]).unwrap();
+	}
+
+	let started = std::time::Instant::now();
+	for n in 0..50u8
+	{
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		tx.add_record("a", "U", &[0,0,0,0,0,0,0,0,n]).unwrap();
+		tx.commit().unwrap();
+	}
+	// commits in the same second used to wait for each other
+	assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+	let r = DatabaseReader::new(t.path()).unwrap();
+	assert_eq!(r.transaction_paths().len(), 51);
+	let last = r.get_range(..).next().unwrap();
+	assert_eq!(last.value()[8], 49);
+}
+
+#[test]
+fn commit_summary()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	{
+		use std::io::Write;
+		let mut main = std::fs::File::create(t.path().join("main")).unwrap();
+		main.write_all(&[0u8]).unwrap();
+	}
+
+	let tx = CreateTx::new(t.path()).unwrap();
+	let summary = tx.commit().unwrap();
+	assert!(summary.path.is_none());
+	assert_eq!(summary.records, 0);
+
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("a", "U", &[0,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,1]).unwrap();
+	tx.add_record("a", "U", &[0,0,0,0,0,0,0,1, 0,0,0,0,0,0,0,2]).unwrap();
+	tx.add_record("b", "U", &[0,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,3]).unwrap();
+	let summary = tx.commit().unwrap();
+	let path = summary.path.as_ref().unwrap();
+	assert_eq!(summary.keys, 2);
+	assert_eq!(summary.records, 3);
+	assert_eq!(summary.segments, 1);
+	assert_eq!(summary.uncompressed_bytes, 2*(16+1+1) + 3*16);
+	assert_eq!(summary.compressed_bytes, std::fs::metadata(path).unwrap().len());
+}
+
+fn three_transactions(dir: &std::path::Path)
+{
+	std::fs::File::create(dir.join("main")).unwrap();
+	for key in &["a", "b", "c"]
+	{
+		let mut tx = CreateTx::new(dir).unwrap();
+		tx.add_record(key, "U", &[0,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,1]).unwrap();
+		// a whole second later, so that --gegnum's timestamps work
+		tx.add_record(key, "U", &[0,0,0,0,0x3b,0x9a,0xca,0, 0,0,0,0,0,0,0,2]).unwrap();
+		tx.commit().unwrap();
+	}
+}
+
+#[test]
+fn compaction()
+{
+	use crate::compact::Compaction;
+
+	let t = tempfile::TempDir::new().unwrap();
+	three_transactions(t.path());
+	// the first one became main
+	assert_eq!(DatabaseReader::new(t.path()).unwrap().transaction_paths().len(), 3);
+
+	let report = Compaction::minor(t.path()).run().unwrap();
+	assert_eq!(report.inputs.len(), 2);
+	// the newest one was replaced
+	assert_eq!(report.removed.len(), 1);
+	assert_eq!(report.records, 4);
+	let r = DatabaseReader::new(t.path()).unwrap();
+	assert_eq!(r.transaction_paths().len(), 2);
+	assert_eq!(r.get_range(..).count(), 6);
+
+	let report = Compaction::major(t.path()).run().unwrap();
+	assert_eq!(report.records, 6);
+	let r = DatabaseReader::new(t.path()).unwrap();
+	assert_eq!(r.transaction_paths(), vec![t.path().join("main")]);
+	assert_eq!(r.get_range(..).count(), 6);
+
+	let report = Compaction::major(t.path()).run().unwrap();
+	assert!(report.commit.is_none());
+
+	let report = Compaction::major(t.path())
+		.gegnum(std::ffi::OsStr::new("grep -v ^b"))
+		.run().unwrap();
+	assert_eq!(report.records, 4);
+	let r = DatabaseReader::new(t.path()).unwrap();
+	assert_eq!(r.get("b").count(), 0);
+
+	assert!(
+		Compaction::major(t.path())
+			.gegnum(std::ffi::OsStr::new("false"))
+			.run().is_err()
+	);
+	assert_eq!(DatabaseReader::new(t.path()).unwrap().get_range(..).count(), 4);
+}
+
+#[test]
+fn compaction_policy()
+{
+	use crate::auto_compact::*;
+
+	let t = tempfile::TempDir::new().unwrap();
+	three_transactions(t.path());
+
+	let mut policy = CompactionPolicy
+	{
+		major_ratio: None,
+		major_interval: None,
+		..Default::default()
+	};
+	assert!(compact_if_needed(t.path(), &policy).is_none());
+
+	policy.max_transactions = 2;
+	let files = DatabaseFiles::scan(t.path()).unwrap();
+	assert_eq!(files.transactions, 2);
+	assert_eq!(policy.decide(&files), Some(CompactionKind::Minor));
+	let report = compact_if_needed(t.path(), &policy).unwrap().unwrap();
+	assert!(!report.major);
+	assert_eq!(DatabaseFiles::scan(t.path()).unwrap().transactions, 1);
+	// one transaction is never worth a minor compaction,
+	// however large it is
+	assert!(compact_if_needed(t.path(), &policy).is_none());
+	policy.max_transaction_bytes = 1;
+	assert_eq!(policy.decide(&DatabaseFiles::scan(t.path()).unwrap()), None);
+
+	policy.major_ratio = Some(0.5);
+	let report = compact_if_needed(t.path(), &policy).unwrap().unwrap();
+	assert!(report.major);
+	assert_eq!(DatabaseFiles::scan(t.path()).unwrap().transactions, 0);
+	assert!(compact_if_needed(t.path(), &policy).is_none());
+	assert_eq!(DatabaseReader::new(t.path()).unwrap().get_range(..).count(), 6);
+}
+
+#[test]
+fn compaction_subset()
+{
+	use crate::compact::*;
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+	// every transaction overwrites "a" at the same timestamp
+	let mut names = vec!();
+	for value in 1..=5u64
+	{
+		let mut tx = CreateTx::new(dir).unwrap();
+		let mut data = vec![0u8; 8];
+		data.extend_from_slice(&value.to_be_bytes());
+		tx.add_record("a", "U", &data).unwrap();
+		if value == 3
+		{
+			// make one big transaction
+			for i in 0..1000u32
+			{
+				tx.add_record(&format!("b{:04}", i), "U", &data).unwrap();
+			}
+		}
+		names.push(tx.commit().unwrap().path.unwrap());
+	}
+	let latest = |dir: &std::path::Path|
+		DatabaseReader::new(dir).unwrap().get("a").next().unwrap().value()[15];
+	assert_eq!(latest(dir), 5);
+
+	// the first went into main, merge the next two
+	let report = Compaction::minor(dir)
+		.select(TransactionSelection::Oldest(2))
+		.run().unwrap();
+	assert_eq!(report.inputs, names[1..3].to_vec());
+	assert_eq!(report.commit.unwrap().path.unwrap(), names[2]);
+	assert_eq!(latest(dir), 5);
+	let files = crate::database_reader::transaction_files(dir).unwrap();
+	assert_eq!(files, names[2..].to_vec());
+
+	// skips the big one
+	let report = Compaction::minor(dir)
+		.select(TransactionSelection::SmallerThan(1000))
+		.run().unwrap();
+	assert_eq!(report.inputs, names[3..].to_vec());
+	assert_eq!(latest(dir), 5);
+	assert_eq!(DatabaseReader::new(dir).unwrap().get_range(..).count(), 1001);
+
+	assert!(
+		Compaction::minor(dir)
+			.select(TransactionSelection::Paths(vec!(dir.join("nonexistent"))))
+			.run().is_err()
+	);
+	assert!(
+		Compaction::major(dir)
+			.select(TransactionSelection::Oldest(1))
+			.run().is_err()
+	);
+}
+
+#[test]
+fn incremental_compaction()
+{
+	use crate::compact::*;
+
+	fn contents(dir: &std::path::Path) -> Vec<(String, Vec<u8>)>
+	{
+		DatabaseReader::new(dir).unwrap()
+			.get_range(..)
+			.map(|r| (r.key().to_owned(), r.value().to_owned()))
+			.collect()
+	}
+	fn record(ts: u64, v: u8) -> Vec<u8>
+	{
+		let mut data = ts.to_be_bytes().to_vec();
+		data.extend_from_slice(&[v; 120]);
+		data
+	}
+	let format = "UUUUUUUUUUUUUUU";
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+
+	// big enough that each key gets its own segment
+	let mut tx = CreateTx::new(dir).unwrap();
+	for key in &["k0", "k1", "k2", "k3"]
+	{
+		for ts in 0..17000
+		{
+			tx.add_record(key, format, &record(ts, 1)).unwrap();
+		}
+	}
+	tx.commit().unwrap();
+
+	let mut tx = CreateTx::new(dir).unwrap();
+	tx.add_record("a", format, &record(1, 2)).unwrap();
+	tx.add_record("k1", format, &record(5, 2)).unwrap();
+	tx.add_record("k1", format, &record(20000, 2)).unwrap();
+	tx.add_record("k1a", format, &record(1, 2)).unwrap();
+	tx.add_record("z", format, &record(1, 2)).unwrap();
+	tx.commit().unwrap();
+
+	let expected = contents(dir);
+	let report = Compaction::major(dir).incremental(true).run().unwrap();
+	assert!(report.copied_segments > 0);
+	// the copied segments' records are counted too
+	assert_eq!(report.records, expected.len() as u64);
+	assert_eq!(report.commit.as_ref().unwrap().keys, 7);
+	assert_eq!(contents(dir), expected);
+	let db = DatabaseReader::new(dir).unwrap();
+	assert_eq!(db.transaction_paths(), vec![dir.join("main")]);
+	assert_eq!(db.get("k1").count(), 17001);
+	assert_eq!(db.get("k1").nth(5).unwrap().value()[8], 2);
+	assert_eq!(db.get("k2").count(), 17000);
+
+	// again, with a main that has copied segments
+	let mut tx = CreateTx::new(dir).unwrap();
+	tx.add_record("k3", format, &record(0, 3)).unwrap();
+	tx.commit().unwrap();
+	let expected = contents(dir);
+	let report = Compaction::major(dir).incremental(true).run().unwrap();
+	assert!(report.copied_segments > 0);
+	assert_eq!(contents(dir), expected);
+	let db = DatabaseReader::new(dir).unwrap();
+	for key in &["k0", "k2", "k3"]
+	{
+		assert_eq!(db.get(key).count(), 17000);
+	}
+	assert_eq!(db.get("k3").next().unwrap().value()[8], 3);
+
+	assert!(Compaction::minor(dir).incremental(true).run().is_err());
+}
+
+#[test]
+fn retention()
+{
+	use crate::compact::*;
+	use crate::retention::*;
+
+	assert!(Retention::parse("debug.% 7x").is_err());
+	assert!(Retention::parse("debug.%").is_err());
+	let r = Retention::parse("# comment\n\ndebug.% 7d\nbilling.% forever\n% 1y\n").unwrap();
+	assert_eq!(r.max_age("debug.x"), Some(std::time::Duration::from_secs(7*86400)));
+	assert_eq!(r.max_age("billing.x"), None);
+	assert_eq!(r.max_age("other"), Some(std::time::Duration::from_secs(365*86400)));
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+	std::fs::write(dir.join(RETENTION_FILE), "debug.% 7d\nbilling.% forever\n").unwrap();
+
+	let now = std::time::SystemTime::now()
+		.duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap()
+		.as_nanos() as u64;
+	let mut tx = CreateTx::new(dir).unwrap();
+	for key in &["billing.a", "debug.a", "other"]
+	{
+		for ts in &[1, now]
+		{
+			let mut data = ts.to_be_bytes().to_vec();
+			data.extend_from_slice(&[0,0,0,1]);
+			tx.add_record(key, "u", &data).unwrap();
+		}
+	}
+	tx.commit().unwrap();
+
+	let report = Compaction::major(dir).run().unwrap();
+	assert_eq!(report.expired, 1);
+	let db = DatabaseReader::new(dir).unwrap();
+	assert_eq!(db.get("billing.a").count(), 2);
+	assert_eq!(db.get("debug.a").count(), 1);
+	assert_eq!(db.get("other").count(), 2);
+
+	let report = Compaction::major(dir)
+		.retention(Retention::new().rule("%", Some(std::time::Duration::from_secs(60))))
+		.incremental(true)
+		.run().unwrap();
+	assert_eq!(report.expired, 2);
+	assert_eq!(DatabaseReader::new(dir).unwrap().get_range(..).count(), 3);
+
+	// a minor compaction keeps an expired record that replaces one in `main`
+	let expire = || Retention::new().rule("late", Some(std::time::Duration::from_secs(60)));
+	for value in 1u8 ..= 3
+	{
+		let mut tx = CreateTx::new(dir).unwrap();
+		let mut data = 1u64.to_be_bytes().to_vec();
+		data.extend_from_slice(&[0,0,0,value]);
+		tx.add_record("late", "u", &data).unwrap();
+		tx.commit().unwrap();
+		if value == 1
+			{ Compaction::major(dir).retention(Retention::new()).run().unwrap(); }
+	}
+	let report = Compaction::minor(dir).retention(expire()).run().unwrap();
+	assert_eq!(report.expired, 0);
+	let db = DatabaseReader::new(dir).unwrap();
+	assert_eq!(db.get("late").next().unwrap().value()[11], 3);
+
+	let report = Compaction::major(dir).retention(expire()).run().unwrap();
+	assert_eq!(report.expired, 1);
+	assert_eq!(DatabaseReader::new(dir).unwrap().get("late").count(), 0);
+}
+
+#[test]
+fn rollup()
+{
+	use crate::compact::*;
+	use crate::rollup::*;
+	use byteorder::{ByteOrder,BigEndian};
+
+	assert!(Rollups::parse("m.% 1d 1m").is_err());
+	assert!(Rollups::parse("m.% 1d 1m median").is_err());
+	assert!(Rollups::parse("m.% 1d 1m mean,max same-key").is_err());
+	assert!(Rollups::parse("# comment\nm.% 1d 1m mean,max,count\n").is_ok());
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+	std::fs::write(
+		dir.join(ROLLUP_FILE),
+		"m.% 1d 1m mean,max,count\ns.% 1d 1m max same-key\n",
+	).unwrap();
+
+	let now = std::time::SystemTime::now()
+		.duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap()
+		.as_nanos() as u64;
+	let mut tx = CreateTx::new(dir).unwrap();
+	for key in &["m.a", "s.a", "t.a"]
+	{
+		for &(ts, value) in &[(0, 1u32), (1_000_000_000, 2), (2_000_000_000, 3), (60_000_000_000, 10), (now, 5)]
+		{
+			let mut data = ts.to_be_bytes().to_vec();
+			data.extend_from_slice(&value.to_be_bytes());
+			tx.add_record(key, "u", &data).unwrap();
+		}
+	}
+	tx.commit().unwrap();
+
+	let report = Compaction::major(dir).run().unwrap();
+	// s.a's record at 60s is already at the start of its period
+	assert_eq!(report.rolled_up, 7);
+	let db = DatabaseReader::new(dir).unwrap();
+	assert_eq!(db.get("m.a").count(), 1);
+	let mean: Vec<_> = db.get("m.a.mean").collect();
+	assert_eq!(mean.len(), 2);
+	assert_eq!(mean[0].format(), "F");
+	assert_eq!(BigEndian::read_f64(&mean[0].value()[8..]), 2.0);
+	let count: Vec<_> = db.get("m.a.count").collect();
+	assert_eq!(BigEndian::read_u64(&count[0].value()[8..]), 3);
+	let max: Vec<_> = db.get("s.a").collect();
+	assert_eq!(max.len(), 3);
+	assert_eq!(max[0].format(), "u");
+	assert_eq!(BigEndian::read_u32(&max[0].value()[8..]), 3);
+	assert_eq!(db.get("t.a").count(), 5);
+	let records = db.get_range(..).count();
+
+	// rolling up again changes nothing
+	let report = Compaction::major(dir).incremental(true).run().unwrap();
+	assert_eq!(report.rolled_up, 0);
+	assert_eq!(DatabaseReader::new(dir).unwrap().get_range(..).count(), records);
+
+	let report = Compaction::minor(dir).run().unwrap();
+	assert_eq!(report.rolled_up, 0);
+
+	// a late record in a period that was already rolled up is
+	// kept, and doesn't replace the period's aggregates
+	let mut tx = CreateTx::new(dir).unwrap();
+	let mut data = 3_000_000_000u64.to_be_bytes().to_vec();
+	data.extend_from_slice(&100u32.to_be_bytes());
+	tx.add_record("m.a", "u", &data).unwrap();
+	tx.commit().unwrap();
+	for _ in 0 .. 2
+	{
+		let report = Compaction::major(dir).run().unwrap();
+		assert_eq!(report.rolled_up, 0);
+		let db = DatabaseReader::new(dir).unwrap();
+		assert_eq!(db.get("m.a").count(), 2);
+		let mean: Vec<_> = db.get("m.a.mean").collect();
+		assert_eq!(mean.len(), 2);
+		assert_eq!(BigEndian::read_f64(&mean[0].value()[8..]), 2.0);
+		assert_eq!(db.get_range(..).count(), records+1);
+	}
+}
+
+#[test]
+fn rollup_same_key_late()
+{
+	use crate::compact::*;
+	use crate::rollup::*;
+	use byteorder::{ByteOrder,BigEndian};
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+	std::fs::write(dir.join(ROLLUP_FILE), "s.% 1d 1m mean same-key\n").unwrap();
+
+	let add = |records: &[(u64, f64)]|
+	{
+		let mut tx = CreateTx::new(dir).unwrap();
+		for &(ts, value) in records
+		{
+			let mut data = ts.to_be_bytes().to_vec();
+			data.extend_from_slice(&value.to_be_bytes());
+			tx.add_record("s.a", "F", &data).unwrap();
+		}
+		tx.commit().unwrap();
+	};
+	let values = ||
+		DatabaseReader::new(dir).unwrap().get("s.a")
+			.map(|r| (r.timestamp(), BigEndian::read_f64(&r.value()[8..])))
+			.collect::<Vec<_>>();
+
+	add(&[(0, 1.0), (1_000_000_000, 2.0), (2_000_000_000, 3.0)]);
+	let report = Compaction::major(dir).run().unwrap();
+	assert_eq!(report.rolled_up, 3);
+	assert_eq!(values(), vec![(0, 2.0)]);
+	assert!(dir.join(ROLLED_UP_FILE).exists());
+
+	// a late record in the rolled up period is kept as it is, while
+	// records in a period that had none are rolled up
+	add(&[(3_000_000_000, 100.0), (120_000_000_000, 4.0), (121_000_000_000, 6.0)]);
+	for _ in 0 .. 2
+	{
+		Compaction::major(dir).run().unwrap();
+		assert_eq!(
+			values(),
+			vec![(0, 2.0), (3_000_000_000, 100.0), (120_000_000_000, 5.0)],
+		);
+	}
+}
+
+#[test]
+fn transforms()
+{
+	use crate::compact::*;
+	use crate::transform::*;
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+
+	let mut tx = CreateTx::new(dir).unwrap();
+	for &(key, ts, value) in &[
+		("a.x", 1u64, 1.25f64), ("a.y", 1, 2.0), ("a.y", 5, 2.5),
+		("bad.z", 1, 3.0), ("new.k", 1, 9.0), ("new.k", 3, 9.0),
+		("old.k", 1, 0.1), ("old.k", 2, 0.2),
+	]
+	{
+		let mut data = ts.to_be_bytes().to_vec();
+		data.extend_from_slice(&value.to_be_bytes());
+		tx.add_record(key, "F", &data).unwrap();
+	}
+	tx.commit().unwrap();
+
+	let transforms = Transforms::new()
+		.delete("bad%")
+		.delete_range("a.%", 0 .. 2)
+		.rename("old.", "new.");
+	assert!(Compaction::minor(dir).transforms(transforms.clone()).run().is_err());
+
+	let report = Compaction::major(dir).transforms(transforms).run().unwrap();
+	assert_eq!(report.deleted, 3);
+
+	let db = DatabaseReader::new(dir).unwrap();
+	let records: Vec<_> = db.get_range(..)
+		.map(|r| (r.key().to_owned(), r.value().to_vec()))
+		.collect();
+	let keys: Vec<_> = records.iter().map(|(k, v)| (&k[..], v[7])).collect();
+	assert_eq!(keys, vec!(("a.y", 5), ("new.k", 1), ("new.k", 2), ("new.k", 3)));
+	// the renamed record replaced the one in new.k, exactly
+	assert_eq!(&records[1].1[8..], &0.1f64.to_be_bytes());
+
+	let report = Compaction::major(dir)
+		.transforms(Transforms::new().keep("new%"))
+		.run().unwrap();
+	assert_eq!(report.deleted, 1);
+	assert_eq!(DatabaseReader::new(dir).unwrap().get_range(..).count(), 3);
+}
+
+#[test]
+fn compaction_progress()
+{
+	use crate::compact::*;
+	use std::sync::{Arc,Mutex};
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+	// this replaces the empty main
+	let mut tx = CreateTx::new(dir).unwrap();
+	tx.add_record("a", "U", &[0; 16]).unwrap();
+	tx.commit().unwrap();
+
+	let mut tx = CreateTx::new(dir).unwrap();
+	let mut value: u64 = 1;
+	for key in 0..100
+	{
+		for ts in 0..2000u64
+		{
+			value = value.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+			let mut data = ts.to_be_bytes().to_vec();
+			data.extend_from_slice(&value.to_be_bytes());
+			tx.add_record(&format!("k{:03}", key), "U", &data).unwrap();
+		}
+	}
+	tx.commit().unwrap();
+
+	let reports = Arc::new(Mutex::new(vec!()));
+	let saved = reports.clone();
+	let started = std::time::Instant::now();
+	let report = Compaction::major(dir)
+		.progress(
+			std::time::Duration::from_millis(0),
+			move |p| saved.lock().unwrap().push(p.clone()),
+		)
+		.max_bytes_per_second(4*1024*1024)
+		.run().unwrap();
+	let elapsed = started.elapsed();
+
+	let reports = reports.lock().unwrap();
+	assert!(reports.len() > 1);
+	let last = reports.last().unwrap();
+	assert_eq!(last.records, 200_001);
+	assert_eq!(last.keys, 101);
+	assert_eq!(last.bytes_read, last.total_bytes);
+	assert!(reports.windows(2).all(|w| w[0].bytes_read <= w[1].bytes_read));
+
+	// the last report before the end had been slowed down to the limit
+	let before_end = &reports[reports.len()-2];
+	let bytes = before_end.bytes_read + before_end.bytes_written;
+	assert!(bytes > 0);
+	assert!(elapsed.as_secs_f64() >= bytes as f64 / (4.0*1024.0*1024.0));
+	assert_eq!(report.records, 200_001);
+}
+
+#[test]
+fn compaction_verify()
+{
+	use crate::compact::*;
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	three_transactions(dir);
+
+	let report = Compaction::minor(dir).verify(true).run().unwrap();
+	let commit = report.commit.as_ref().unwrap();
+	assert_eq!(commit.verified.unwrap().records, commit.records);
+	assert!(report.to_string().contains("verified"));
+
+	let report = Compaction::minor(dir).run().unwrap();
+	assert!(report.commit.is_none() || report.commit.unwrap().verified.is_none());
+
+	let mut tx = CreateTx::new(dir).unwrap();
+	tx.add_record("zz", "u", &[0,0,0,0,0,0,0,1, 0,0,0,1]).unwrap();
+	tx.commit().unwrap();
+
+	let report = Compaction::major(dir).incremental(true).verify(true).run().unwrap();
+	assert_eq!(report.copied_segments, 1);
+	let verified = report.commit.unwrap().verified.unwrap();
+	assert_eq!(verified.records, DatabaseReader::new(dir).unwrap().get_range(..).count() as u64);
+}
+
+#[test]
+fn gegnum_formats()
+{
+	use crate::compact::*;
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+
+	let mut rows = vec!();
+	for &(key, ts, value) in &[("a", 1_000_000_123u64, 0.1f64), ("b", 5, 1e-30), ("c", 7, -0.0)]
+	{
+		let mut data = ts.to_be_bytes().to_vec();
+		data.extend_from_slice(&value.to_be_bytes());
+		data.extend_from_slice(&(value as f32).to_be_bytes());
+		rows.push((key.to_string(), data));
+	}
+	let mut tx = CreateTx::new(dir).unwrap();
+	for (key, data) in &rows
+	{
+		tx.add_record(key, "Ff", data).unwrap();
+	}
+	tx.commit().unwrap();
+
+	let read = ||
+		DatabaseReader::new(dir).unwrap().get_range(..)
+			.map(|r| (r.key().to_owned(), r.value().to_vec()))
+			.collect::<Vec<_>>();
+
+	for &format in &[GegnumFormat::Exact, GegnumFormat::Binary]
+	{
+		Compaction::major(dir)
+			.gegnum(std::ffi::OsStr::new("cat"))
+			.gegnum_format(format)
+			.run().unwrap();
+		assert_eq!(read(), rows);
+	}
+
+	// the text format rounds the timestamps to seconds
+	Compaction::major(dir)
+		.gegnum(std::ffi::OsStr::new("cat"))
+		.run().unwrap();
+	assert_ne!(read(), rows);
+}
+
+#[test]
+fn row_format_defaults()
+{
+	use crate::row_format::*;
+
+	// a format from outside the crate, with only the required methods
+	struct Hex;
+	impl RowFormat for Hex
+	{
+		fn to_stored_format(&self, _ts: Timestamp, _from: &str, _dest: &mut Vec<u8>)
+			-> Result<(), String>
+		{
+			unimplemented!()
+		}
+		fn to_protocol_format(&self, from: &[u8], dest: &mut dyn std::io::Write)
+			-> std::io::Result<()>
+		{
+			from.iter().try_for_each(|b| write!(dest, "{:02x}", b))
+		}
+		fn row_size(&self) -> usize
+		{
+			8
+		}
+	}
+
+	let mut exact = vec!();
+	Hex.to_exact_protocol_format(&[1, 0xab], &mut exact).unwrap();
+	assert_eq!(exact, b"01ab");
+	let mut json = vec!();
+	Hex.to_json_format(&[1, 0xab], &mut json).unwrap();
+	assert_eq!(json, b"[\"01ab\"]");
+	assert!(Hex.to_numbers(&[1], &mut vec!()).is_err());
+
+	let mut json = vec!();
+	parse_row_format("uF").to_json_format(&[0,0,0,7, 0x7f,0xf8,0,0,0,0,0,0], &mut json).unwrap();
+	assert_eq!(json, b"[7,null]");
+}
+
+#[test]
+fn bad_binary_stream()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	std::fs::File::create(dir.join("main")).unwrap();
+	let db = DatabaseReader::new(dir).unwrap();
+
+	let mut too_long = vec!();
+	print_row_binary("a", "u", &[0,0,0,0,0,0,0,1, 0,0,0,1, 0], &mut too_long).unwrap();
+	// a huge length, and then not enough data
+	let garbled = [0xff, 0xff, 0xff, 0xff, b'a'];
+
+	for input in &[&too_long[..], &garbled[..]]
+	{
+		let mut tx = CreateTx::new(dir).unwrap();
+		let e = add_from_binary_stream(&mut tx, &db, &mut &input[..], true);
+		assert!(e.is_err());
+	}
+}
+
+#[test]
+fn compaction_lock()
+{
+	use crate::compact::*;
+	use crate::lock::*;
+
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	three_transactions(dir);
+	assert!(lock_holder(dir).unwrap().is_none());
+
+	let lock = Lock::acquire(dir, "test", LockWait::No).unwrap();
+	let holder = lock_holder(dir).unwrap().unwrap();
+	assert_eq!(holder.kind, "test");
+	assert_eq!(holder.pid, std::process::id());
+
+	let kind = |r: Result<CompactionReport, crate::WriteFailure>|
+		match r
+		{
+			Err(crate::WriteFailure::IOError(e)) => e.kind(),
+			_ => panic!("compacted while locked"),
+		};
+	assert_eq!(
+		kind(Compaction::minor(dir).wait(LockWait::No).run()),
+		std::io::ErrorKind::WouldBlock,
+	);
+	assert_eq!(
+		kind(Compaction::minor(dir).wait(LockWait::Timeout(std::time::Duration::from_millis(200))).run()),
+		std::io::ErrorKind::TimedOut,
+	);
+
+	drop(lock);
+	assert!(lock_holder(dir).unwrap().is_none());
+	assert!(Compaction::minor(dir).wait(LockWait::No).run().is_ok());
+}
+
+#[test]
+fn create_database()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = &t.path().join("db");
+
+	let e = DatabaseReader::new(dir).err().unwrap();
+	assert!(e.to_string().contains("sonnerie init"));
+	std::fs::create_dir(dir).unwrap();
+	let e = DatabaseReader::new(dir).err().unwrap();
+	assert!(e.to_string().contains("not a sonnerie database"));
+	std::fs::remove_dir(dir).unwrap();
+
+	let db = Database::create(dir).unwrap();
+	assert_eq!(db.metadata().format, FORMAT_VERSION);
+	let e = Database::create(dir).err().unwrap();
+	assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+
+	let mut tx = db.transaction().unwrap();
+	tx.add_record("a", "u", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 7]).unwrap();
+	tx.commit().unwrap();
+	assert_eq!(db.reader().unwrap().get("a").count(), 1);
+
+	assert_eq!(Database::open(dir).unwrap().metadata(), db.metadata());
+	std::fs::write(dir.join(META_FILE), "format 99\n").unwrap();
+	assert!(Database::open(dir).is_err());
+	assert!(DatabaseReader::new(dir).is_err());
+}
+
+#[test]
+fn database_info()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	Database::create(dir).unwrap();
+
+	let record = |ts: u64, v: u32|
+	{
+		let mut r = vec![0u8; 12];
+		BigEndian::write_u64(&mut r[0 .. 8], ts);
+		BigEndian::write_u32(&mut r[8 .. 12], v);
+		r
+	};
+
+	// "a" is in more than one segment
+	let mut tx = CreateTx::new(dir).unwrap();
+	for ts in 0 .. 300000
+	{
+		tx.add_record("a", "u", &record(ts, ts as u32)).unwrap();
+	}
+	tx.add_record("b", "u", &record(0, 1)).unwrap();
+	tx.commit().unwrap();
+	let mut tx = CreateTx::new(dir).unwrap();
+	tx.add_record("b", "u", &record(1, 2)).unwrap();
+	tx.add_record("c", "u", &record(1, 3)).unwrap();
+	tx.commit().unwrap();
+
+	let info = DatabaseInfo::scan(dir, &CompactionPolicy::default()).unwrap();
+	assert_eq!(info.files.len(), 2);
+	let main = &info.files[0];
+	assert!(main.segments > 1);
+	assert_eq!(main.keys, 2);
+	assert_eq!(main.records, 300001);
+	assert!(main.compression_ratio() > 1.0);
+	assert_eq!(info.files[1].keys, 2);
+	assert_eq!(info.files[1].first_key.as_deref(), Some("b"));
+
+	assert_eq!(info.total.keys, 3);
+	assert_eq!(info.total.records, 300003);
+	assert_eq!(info.total.first_key.as_deref(), Some("a"));
+	assert_eq!(info.total.last_key.as_deref(), Some("c"));
+	assert_eq!(info.transactions, 1);
+	assert!(!info.too_many_transactions());
+	assert!(info.to_json().contains("\"total\":{\"bytes\":"));
+	assert!(info.to_json().ends_with("\"too_many_transactions\":false}"));
+}
+
+#[test]
+fn check_and_repair()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	Database::create(dir).unwrap();
+
+	let mut tx = CreateTx::new(dir).unwrap();
+	for key in 0 .. 20000
+	{
+		for ts in 0 .. 10u64
+		{
+			let mut r = vec![0u8; 12];
+			BigEndian::write_u64(&mut r[0 .. 8], ts);
+			BigEndian::write_u32(&mut r[8 .. 12], key);
+			tx.add_record(&format!("k{:05}", key), "u", &r).unwrap();
+		}
+	}
+	tx.commit().unwrap();
+
+	let report = check_database(dir).unwrap();
+	assert!(report.is_ok(), "{:?}", report.problems().collect::<Vec<_>>());
+	assert!(report.files[0].segments > 1);
+	assert_eq!(report.files[0].records, 200000);
+
+	// break the second segment's LZ4 magic number, and add garbage
+	let main = dir.join("main");
+	use std::os::unix::fs::PermissionsExt;
+	std::fs::set_permissions(&main, std::fs::Permissions::from_mode(0o644)).unwrap();
+	let mut data = std::fs::read(&main).unwrap();
+	let second = twoway::find_bytes(&data[1 ..], crate::segment::SEGMENT_INVOCATION).unwrap() + 1;
+	let first_len = BigEndian::read_u32(&data[second+16 .. second+20]) as usize;
+	let last_len = BigEndian::read_u32(&data[second+20 .. second+24]) as usize;
+	let payload = second + 32 + first_len + last_len;
+	data[payload .. payload+4].copy_from_slice(b"junk");
+	data.extend_from_slice(b"junk");
+	std::fs::write(&main, &data).unwrap();
+
+	let report = check_database(dir).unwrap();
+	let problems: Vec<_> = report.problems().collect();
+	assert_eq!(problems.len(), 2, "{:?}", problems);
+	assert_eq!(problems[0].offset, second as u64);
+	assert!(problems[0].description.contains("decompress"));
+	assert_eq!(problems[1].offset, data.len() as u64 - 4);
+
+	let repaired = repair_database(dir, &report).unwrap();
+	assert_eq!(repaired.len(), 1);
+	assert_eq!(repaired[0].dropped_segments, 1);
+	assert!(repaired[0].records > 0 && repaired[0].records < 200000);
+	assert_eq!(std::fs::read(dir.join("corrupt.main")).unwrap(), data);
+
+	assert!(check_database(dir).unwrap().is_ok());
+	let db = DatabaseReader::new(dir).unwrap();
+	assert_eq!(db.get("k00000").count(), 10);
+	assert_eq!(db.get_range(..).count() as u64, repaired[0].records);
+
+	// repair again, when nothing can be read
+	std::fs::set_permissions(&main, std::fs::Permissions::from_mode(0o644)).unwrap();
+	std::fs::write(&main, b"junk").unwrap();
+	let repaired = repair_database(dir, &check_database(dir).unwrap()).unwrap();
+	assert_eq!(repaired[0].original, dir.join("corrupt.main.1"));
+	assert_eq!(std::fs::read(dir.join("corrupt.main.1")).unwrap(), b"junk");
+	assert_eq!(std::fs::metadata(&main).unwrap().len(), 0);
+	assert!(check_database(dir).unwrap().is_ok());
+}
+
+#[test]
+fn csv_import_export()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	Database::create(dir).unwrap();
+
+	let input = "\
+id;time;ignored;name;count\n\
+a;2020-01-01T00:00:00;x;\"o; t\";1\n\
+a;2020-01-01T00:00:01;x;four;2\n\
+b c;2020-01-02T00:00:00;x;\"\"\"four\"\"\";3\n";
+	let options = CsvOptions::new()
+		.delimiter(b';')
+		.key_column("id")
+		.timestamp_column("time")
+		.value_columns(&["name", "count"]);
+	let db = DatabaseReader::new(dir).unwrap();
+	let mut tx = CreateTx::new(dir).unwrap();
+	add_from_csv(&mut tx, &db, Some("su"), input.as_bytes(), Some("%FT%T"), false, &options, &mut BadLines::new(OnError::Abort)).unwrap();
+	tx.commit().unwrap();
+
+	let db = DatabaseReader::new(dir).unwrap();
+	let mut out = CsvPrinter::new(vec!(), &options, PrintTimestamp::Nanos, PrintRecordFormat::Yes);
+	for record in db.get_range(..)
+	{
+		out.print(&record).unwrap();
+	}
+	let out = String::from_utf8(out.finish().unwrap()).unwrap();
+	assert_eq!(
+		out,
+		"id;time;format;name;count\n\
+		a;1577836800000000000;su;\"o; t\";1\n\
+		a;1577836801000000000;su;four;2\n\
+		b c;1577923200000000000;su;\"\"\"four\"\"\";3\n"
+	);
+
+	// it reads back what it wrote, into another database
+	let t2 = tempfile::TempDir::new().unwrap();
+	Database::create(t2.path()).unwrap();
+	let db2 = DatabaseReader::new(t2.path()).unwrap();
+	let mut tx = CreateTx::new(t2.path()).unwrap();
+	let options = options.format_column("format");
+	add_from_csv(&mut tx, &db2, None, out.as_bytes(), None, false, &options, &mut BadLines::new(OnError::Abort)).unwrap();
+	tx.commit().unwrap();
+	let records = |dir: &std::path::Path|
+		DatabaseReader::new(dir).unwrap().get_range(..)
+			.map(|r| (r.key().to_owned(), r.format().to_owned(), r.value().to_vec()))
+			.collect::<Vec<_>>();
+	assert_eq!(records(dir), records(t2.path()));
+
+	let bad = "key,timestamp,value\na,1,x\n";
+	let mut tx = CreateTx::new(dir).unwrap();
+	let e = add_from_csv(&mut tx, &db, Some("u"), bad.as_bytes(), None, false, &CsvOptions::new(), &mut BadLines::new(OnError::Abort))
+		.err().unwrap();
+	assert!(format!("{:?}", e).contains("line 2"));
+}
+
+#[test]
+fn jsonl_import_export()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	Database::create(dir).unwrap();
+
+	let input = "\
+{\"key\":\"a\",\"ts\":1,\"values\":[1.5,\"say \\\"hi\\\"\"]}\n\
+\n\
+{\"key\":\"a\",\"ts\":\"2\",\"values\":[null,\"two word\"]}\n";
+	let db = DatabaseReader::new(dir).unwrap();
+	let mut tx = CreateTx::new(dir).unwrap();
+	add_from_jsonl(&mut tx, &db, Some("Fs"), &mut input.as_bytes(), None, false, &mut BadLines::new(OnError::Abort)).unwrap();
+	let input = "{\"key\":\"b\",\"ts\":3,\"format\":\"u\",\"values\":[7]}";
+	add_from_jsonl(&mut tx, &db, None, &mut input.as_bytes(), None, false, &mut BadLines::new(OnError::Abort)).unwrap();
+	tx.commit().unwrap();
+
+	let db = DatabaseReader::new(dir).unwrap();
+	let mut out = vec!();
+	for record in db.get_range(..)
+	{
+		print_record_jsonl(&record, &mut out, PrintTimestamp::Nanos, PrintRecordFormat::Yes).unwrap();
+		out.push(b'\n');
+	}
+	let out = String::from_utf8(out).unwrap();
+	assert_eq!(
+		out,
+		"{\"key\":\"a\",\"ts\":1,\"format\":\"Fs\",\"values\":[1.5,\"say \\\"hi\\\"\"]}\n\
+		{\"key\":\"a\",\"ts\":2,\"format\":\"Fs\",\"values\":[null,\"two word\"]}\n\
+		{\"key\":\"b\",\"ts\":3,\"format\":\"u\",\"values\":[7]}\n"
+	);
+
+	let bad = "{\"key\":\"c\",\"ts\":1,\"values\":[1]}\n{\"key\":\"c\",\"ts\":2}\n";
+	let mut tx = CreateTx::new(dir).unwrap();
+	let e = add_from_jsonl(&mut tx, &db, Some("u"), &mut bad.as_bytes(), None, false, &mut BadLines::new(OnError::Abort))
+		.err().unwrap();
+	assert!(format!("{:?}", e).contains("line 2"));
+}
+
+#[test]
+fn range_filter()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	Database::create(dir).unwrap();
+
+	let keys: Vec<String> = (0..20000).map(|i| format!("{}{:05}", ["a","b","c"][i%3], i)).collect();
+	let mut sorted = keys.clone();
+	sorted.sort();
+	let mut tx = CreateTx::new(dir).unwrap();
+	for (idx, key) in sorted.iter().enumerate()
+	{
+		let mut value = vec!();
+		value.write_u64::<BigEndian>(idx as u64).unwrap();
+		value.write_u64::<BigEndian>(idx as u64).unwrap();
+		tx.add_record(key, "u", &value).unwrap();
+	}
+	tx.commit().unwrap();
+
+	let db = DatabaseReader::new(dir).unwrap();
+	let wildcard = crate::Wildcard::new("b%5");
+	let keys = |i: &mut dyn Iterator<Item=crate::record::OwnedRecord>|
+		i.map(|r| (r.key().to_owned(), r.timestamp())).collect::<Vec<_>>();
+	let expected: Vec<_> = keys(&mut db.get_range("b05" .. "c"))
+		.into_iter()
+		.filter(|(k, _)| k.ends_with('5'))
+		.collect();
+	assert!(!expected.is_empty());
+	assert_eq!(keys(&mut db.get_range_filter("b05" .. "c", &wildcard)), expected);
+	// a range that starts before the prefix
+	assert_eq!(
+		keys(&mut db.get_range_filter("a" ..= "b05", &wildcard)),
+		keys(&mut db.get_filter(&wildcard)).into_iter().filter(|(k, _)| k.as_str() <= "b05").collect::<Vec<_>>(),
+	);
+	assert!(keys(&mut db.get_range_filter("c" .., &wildcard)).is_empty());
+}
+
+#[test]
+fn timestamp_formats()
+{
+	assert_eq!(parse_timestamp("86400000000001", None).unwrap(), 86400000000001);
+	assert_eq!(parse_timestamp("1970-01-02 00:00:01", Some("%F %T")).unwrap(), 86_401_000_000_000);
+	// only a date is midnight
+	assert_eq!(parse_timestamp("1970-01-02", Some("%F")).unwrap(), 86_400_000_000_000);
+	assert!(parse_timestamp("1969-12-31", Some("%F")).is_err());
+	assert!(parse_timestamp("2700-01-01", Some("%F")).is_err());
+	assert!(parse_timestamp("01:00", Some("%H:%M")).is_err());
+}
+
+#[test]
+fn unsorted_tx()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	Database::create(dir).unwrap();
+
+	let record = |ts: u64, value: u32|
+	{
+		let mut data = vec!();
+		data.write_u64::<BigEndian>(ts).unwrap();
+		data.write_u32::<BigEndian>(value).unwrap();
+		data
+	};
+
+	let sort = ExternalSort::new()
+		.memory(10000)
+		.spill_dir(dir);
+	let mut tx = CreateTx::new(dir).unwrap().unsorted(sort);
+	for i in (0..5000u64).rev()
+	{
+		let key = format!("k{}", i % 7);
+		tx.add_record(&key, "u", &record(i, 0)).unwrap();
+	}
+	// a repeated key and timestamp, in a later spill, wins
+	tx.add_record("k3", "u", &record(3, 1)).unwrap();
+	tx.commit().unwrap();
+
+	let db = DatabaseReader::new(dir).unwrap();
+	let records: Vec<_> = db.get_range(..)
+		.map(|r| (r.key().to_owned(), r.timestamp(), BigEndian::read_u32(&r.value()[8..])))
+		.collect();
+	let mut expected: Vec<_> = (0..5000u64)
+		.map(|i| (format!("k{}", i % 7), i, if i == 3 { 1 } else { 0 }))
+		.collect();
+	expected.sort();
+	assert_eq!(records, expected);
+
+	// the formats are still checked, when the records are sorted
+	let mut tx = CreateTx::new(dir).unwrap().unsorted(ExternalSort::new());
+	tx.add_record("k", "u", &record(2, 0)).unwrap();
+	tx.add_record("k", "i", &record(1, 0)).unwrap();
+	assert!(tx.commit().is_err());
+
+	// a record without a timestamp
+	assert!(ExternalSort::new().add_record("k", "u", &[0; 4]).is_err());
+}
+
+#[test]
+fn skip_bad_lines()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = t.path();
+	Database::create(dir).unwrap();
+	let db = DatabaseReader::new(dir).unwrap();
+
+	let data = "a 1 1\na x 2\nb 2 3\nb 3 many\nc 1 4\n";
+
+	let mut tx = CreateTx::new(dir).unwrap();
+	let e = add_from_stream(&mut tx, &db, "u", &mut data.as_bytes(), None, false)
+		.err().unwrap();
+	assert!(e.to_string().starts_with("line 2: "));
+	assert!(e.to_string().ends_with(": \"a x 2\""));
+
+	let mut tx = CreateTx::new(dir).unwrap();
+	let mut reject = vec!();
+	let mut bad_lines = BadLines::new(OnError::Skip).reject_to(&mut reject);
+	add_from_stream2(&mut tx, &db, "u", &mut data.as_bytes(), None, false, &mut bad_lines).unwrap();
+	assert_eq!(bad_lines.skipped(), 2);
+	tx.commit().unwrap();
+	assert_eq!(String::from_utf8(reject).unwrap(), "a x 2\nb 3 many\n");
+
+	let db = DatabaseReader::new(dir).unwrap();
+	let keys: Vec<_> = db.get_range(..).map(|r| (r.key().to_owned(), r.timestamp())).collect();
+	assert_eq!(keys, vec!(("a".to_string(), 1), ("b".to_string(), 2), ("c".to_string(), 1)));
+}
+
+#[test]
+fn snapshot_and_restore()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let dir = &t.path().join("db");
+	Database::create(dir).unwrap();
+	for (key, value) in &[("a", b"\0\0\0\0\0\0\0\x01\0\0\0\x01"), ("b", b"\0\0\0\0\0\0\0\x01\0\0\0\x02")]
+	{
+		let mut tx = CreateTx::new(dir).unwrap();
+		tx.add_record(key, "u", &value[..]).unwrap();
+		tx.commit().unwrap();
+	}
+	std::fs::write(dir.join(crate::RETENTION_FILE), "a 7d\n").unwrap();
+	let records = |dir: &std::path::Path|
+		DatabaseReader::new(dir).unwrap().get_range(..)
+			.map(|r| (r.key().to_owned(), r.value().to_vec()))
+			.collect::<Vec<_>>();
+	let before = records(dir);
+
+	let backup = &t.path().join("backup");
+	let snapshot = snapshot_database(dir, backup).unwrap();
+	assert_eq!(
+		snapshot.files.iter().map(|f| &f.name[..]).filter(|n| !n.starts_with("tx.")).collect::<Vec<_>>(),
+		vec!("meta", "retention", "main"),
+	);
+	assert_eq!(Snapshot::load(backup).unwrap().files, snapshot.files);
+	assert!(snapshot_database(dir, backup).is_err());
+
+	// compacting the database doesn't change the snapshot
+	crate::Compaction::major(dir).run().unwrap();
+	assert_eq!(records(backup), before);
+
+	let restored = &t.path().join("restored");
+	restore_database(backup, restored).unwrap();
+	assert_eq!(records(restored), before);
+	assert_eq!(std::fs::read_to_string(restored.join(crate::RETENTION_FILE)).unwrap(), "a 7d\n");
+	assert!(restore_database(backup, restored).is_err());
+
+	// a file that doesn't match the manifest
+	std::fs::write(backup.join(crate::RETENTION_FILE), "").unwrap();
+	let e = restore_database(backup, &t.path().join("again")).err().unwrap();
+	assert!(e.to_string().contains("the manifest says"));
+	assert!(!t.path().join("again").exists());
+}
+
+#[test]
+fn diff_databases()
+{
+	let t = tempfile::TempDir::new().unwrap();
+	let old = &t.path().join("old");
+	let new = &t.path().join("new");
+	Database::create(old).unwrap();
+	Database::create(new).unwrap();
+
+	let add = |dir: &std::path::Path, data: &str|
+	{
+		let db = DatabaseReader::new(dir).unwrap();
+		let mut tx = CreateTx::new(dir).unwrap();
+		add_from_stream(&mut tx, &db, "u", &mut data.as_bytes(), None, false).unwrap();
+		tx.commit().unwrap();
+	};
+	add(old, "a 1 1\na 2 2\nb 1 3\nc 1 4\n");
+	add(new, "a 1 1\na 2 5\na 3 6\nc 1 4\nd 1 7\n");
+
+	let old = DatabaseReader::new(old).unwrap();
+	let new = DatabaseReader::new(new).unwrap();
+	let changes: Vec<_> = crate::diff(old.get_range(..), new.get_range(..))
+		.map(
+			|c| match c
+			{
+				Change::Added(r) => format!("+{}{}", r.key(), r.timestamp()),
+				Change::Removed(r) => format!("-{}{}", r.key(), r.timestamp()),
+				Change::Changed(o, n) =>
+				{
+					assert_eq!(record_order(&o, &n), std::cmp::Ordering::Equal);
+					format!("~{}{}", o.key(), o.timestamp())
+				},
+			}
+		)
+		.collect();
+	assert_eq!(changes, vec!("~a2", "+a3", "-b1", "+d1"));
+
+	let keys: Vec<_> = crate::diff(old.get_range(..), new.get_range(..)).by_key().collect();
+	assert_eq!(
+		keys,
+		vec!(
+			KeyDiff { key: "a".to_string(), added: 1, removed: 0, changed: 1 },
+			KeyDiff { key: "b".to_string(), added: 0, removed: 1, changed: 0 },
+			KeyDiff { key: "d".to_string(), added: 1, removed: 0, changed: 0 },
+		)
+	);
+	assert_eq!(crate::diff(old.get_range(..), old.get_range(..)).count(), 0);
+}
@@ -0,0 +1,254 @@
+//! Change the keys while compacting, without `--gegnum`.
+//!
+//! [`Transforms`] delete keys, delete the records in a range of
+//! time, rename keys by their prefix, or keep only some keys.
+//! The records never go through text, so it's fast and floats
+//! stay exactly as they were.
+//!
+//! Deleting and keeping look at the keys as they were before
+//! being renamed. If a key is renamed onto a record that already
+//! exists, with the same timestamp, the renamed record replaces it.
+
+use std::ops::Range;
+
+use byteorder::{ByteOrder,BigEndian};
+
+use crate::database_reader::DatabaseReader;
+use crate::record::OwnedRecord;
+use crate::row_format::Timestamp;
+use crate::Wildcard;
+
+#[derive(Debug,Clone)]
+struct Matcher
+{
+	wildcard: Wildcard,
+	regex: Option<regex::Regex>,
+}
+
+impl Matcher
+{
+	fn new(wildcard: &str) -> Matcher
+	{
+		let wildcard = Wildcard::new(wildcard);
+		Matcher
+		{
+			regex: wildcard.as_regex(),
+			wildcard,
+		}
+	}
+
+	fn matches(&self, key: &str) -> bool
+	{
+		match self.regex.as_ref()
+		{
+			Some(re) => re.is_match(key),
+			None => key.starts_with(self.wildcard.prefix()),
+		}
+	}
+}
+
+/// Changes to make to the records while compacting.
+#[derive(Debug,Clone,Default)]
+pub struct Transforms
+{
+	delete: Vec<Matcher>,
+	delete_range: Vec<(Matcher, Range<Timestamp>)>,
+	keep: Vec<Matcher>,
+	rename: Vec<(String, String)>,
+}
+
+impl Transforms
+{
+	/// Change nothing
+	pub fn new() -> Transforms
+	{
+		Default::default()
+	}
+
+	/// Delete the keys that match `wildcard`
+	pub fn delete(mut self, wildcard: &str) -> Transforms
+	{
+		self.delete.push(Matcher::new(wildcard));
+		self
+	}
+
+	/// Delete the records of the keys that match `wildcard`
+	/// whose timestamps are in `range`
+	pub fn delete_range(mut self, wildcard: &str, range: Range<Timestamp>) -> Transforms
+	{
+		self.delete_range.push((Matcher::new(wildcard), range));
+		self
+	}
+
+	/// Delete the keys that don't match `wildcard`.
+	///
+	/// With more than one, the keys that match any of them are kept.
+	pub fn keep(mut self, wildcard: &str) -> Transforms
+	{
+		self.keep.push(Matcher::new(wildcard));
+		self
+	}
+
+	/// Rename the keys that start with `from` to start with `to` instead.
+	///
+	/// A key is only renamed by the first rename whose prefix it has.
+	pub fn rename(mut self, from: &str, to: &str) -> Transforms
+	{
+		self.rename.push((from.to_owned(), to.to_owned()));
+		self
+	}
+
+	/// Returns true if nothing is changed
+	pub fn is_empty(&self) -> bool
+	{
+		self.delete.is_empty()
+			&& self.delete_range.is_empty()
+			&& self.keep.is_empty()
+			&& self.rename.is_empty()
+	}
+
+	fn rename_for(&self, key: &str) -> Option<usize>
+	{
+		self.rename.iter().position(|(from, _)| key.starts_with(&from[..]))
+	}
+
+	fn keep_record(&self, key: &str, timestamp: Timestamp) -> bool
+	{
+		if self.delete.iter().any(|m| m.matches(key))
+			{ return false; }
+		if !self.keep.is_empty() && !self.keep.iter().any(|m| m.matches(key))
+			{ return false; }
+		!self.delete_range.iter()
+			.any(|(m, range)| range.contains(&timestamp) && m.matches(key))
+	}
+
+	/// Read all of `db` with the changes made, sorted by key.
+	pub(crate) fn apply<'d>(&'d self, db: &'d DatabaseReader) -> Transformed<'d>
+	{
+		let mut sources = vec!();
+		sources.push(
+			Box::new(
+				db.get_range(..)
+					.filter(move |r| self.rename_for(r.key()).is_none())
+					.map(|r| TransformedRecord { key: None, record: r })
+			) as Box<dyn Iterator<Item=TransformedRecord> + 'd>
+		);
+		for (idx, (from, to)) in self.rename.iter().enumerate()
+		{
+			sources.push(
+				Box::new(
+					db.get_range(&from[..] ..)
+						.take_while(move |r| r.key().starts_with(&from[..]))
+						.filter(move |r| self.rename_for(r.key()) == Some(idx))
+						.map(
+							move |r|
+							{
+								let key = format!("{}{}", to, &r.key()[from.len() ..]);
+								TransformedRecord { key: Some(key), record: r }
+							}
+						)
+				)
+			);
+		}
+
+		Transformed
+		{
+			transforms: self,
+			heads: sources.iter_mut().map(|s| s.next()).collect(),
+			sources,
+			deleted: 0,
+		}
+	}
+}
+
+/// A record, maybe with a new key.
+pub(crate) struct TransformedRecord
+{
+	key: Option<String>,
+	pub(crate) record: OwnedRecord,
+}
+
+impl TransformedRecord
+{
+	pub(crate) fn key(&self) -> &str
+	{
+		self.key.as_deref().unwrap_or_else(|| self.record.key())
+	}
+
+	pub(crate) fn original_key(&self) -> &str
+	{
+		self.record.key()
+	}
+
+	fn timestamp(&self) -> Timestamp
+	{
+		BigEndian::read_u64(self.record.value())
+	}
+}
+
+/// The records of a database, transformed.
+pub(crate) struct Transformed<'d>
+{
+	transforms: &'d Transforms,
+	// the records that aren't renamed, then one for each rename
+	sources: Vec<Box<dyn Iterator<Item=TransformedRecord> + 'd>>,
+	// the next record from each source
+	heads: Vec<Option<TransformedRecord>>,
+	/// The number of records deleted so far
+	pub(crate) deleted: u64,
+}
+
+impl Iterator for Transformed<'_>
+{
+	type Item = TransformedRecord;
+
+	fn next(&mut self) -> Option<TransformedRecord>
+	{
+		loop
+		{
+			// the renamed records win ties, the earlier renames first
+			let mut best: Option<usize> = None;
+			for idx in (1 .. self.heads.len()).chain(0 .. 1)
+			{
+				let candidate = match self.heads[idx].as_ref()
+				{
+					Some(r) => (r.key(), r.timestamp()),
+					None => continue,
+				};
+				let better = match best
+				{
+					None => true,
+					Some(b) =>
+					{
+						let b = self.heads[b].as_ref().unwrap();
+						candidate < (b.key(), b.timestamp())
+					},
+				};
+				if better
+					{ best = Some(idx); }
+			}
+
+			let best = best?;
+			let record = std::mem::replace(&mut self.heads[best], self.sources[best].next())
+				.unwrap();
+			if !self.transforms.keep_record(record.original_key(), record.timestamp())
+			{
+				self.deleted += 1;
+				continue;
+			}
+
+			// drop the records this one replaces
+			for (head, source) in self.heads.iter_mut().zip(self.sources.iter_mut())
+			{
+				while head.as_ref()
+					.map(|r| (r.key(), r.timestamp()) == (record.key(), record.timestamp()))
+					.unwrap_or(false)
+				{
+					*head = source.next();
+				}
+			}
+
+			return Some(record);
+		}
+	}
+}
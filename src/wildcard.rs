@@ -1,6 +1,7 @@
 //! Parse SQL "`LIKE`"-like filters.
 
 /// matches % as a wildcard operator
+#[derive(Clone,Debug)]
 pub struct Wildcard
 {
 	w: String,
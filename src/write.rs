@@ -21,20 +21,36 @@ pub(crate) struct Writer<W: Write+Send+'static>
 	thread_handles: Vec<std::thread::JoinHandle<std::io::Result<()>>>,
 	// a counter to keep each thread writing its output in the right order
 	thread_ordering: usize,
+	keys: u64,
+	records: u64,
+	uncompressed_bytes: u64,
 }
 
 struct WriterState<W: Write+Send>
 {
 	counter: usize,
 	prev_size: u32,
+	written: u64,
 	writer: W,
 }
 
+/// What a `Writer` wrote
+#[derive(Debug,Default,Clone,Copy)]
+pub(crate) struct WriteStats
+{
+	pub(crate) keys: u64,
+	pub(crate) records: u64,
+	pub(crate) segments: u64,
+	pub(crate) uncompressed_bytes: u64,
+	pub(crate) compressed_bytes: u64,
+}
+
 struct WorkerMessage
 {
 	counter: usize,
 	header: Vec<u8>, // not to compress
 	payload: Vec<u8>, // to compress
+	compressed: bool, // true if payload is already compressed
 }
 
 /// A reason a write could not be completed
@@ -49,6 +65,26 @@ pub enum WriteFailure
 	IOError(std::io::Error),
 }
 
+impl std::fmt::Display for WriteFailure
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self
+		{
+			WriteFailure::OrderingViolation(key, last) =>
+				write!(
+					f,
+					"the record for {:?} doesn't come after the one for {:?} \
+						(records must be sorted by key and timestamp)",
+					key, last,
+				),
+			WriteFailure::HeterogeneousFormats(key, existing, new) =>
+				write!(f, "the key {:?} has the format {:?}, not {:?}", key, existing, new),
+			WriteFailure::IOError(e) => write!(f, "{}", e),
+		}
+	}
+}
+
 impl From<std::io::Error> for WriteFailure
 {
 	fn from(e: std::io::Error) -> Self
@@ -57,6 +93,19 @@ impl From<std::io::Error> for WriteFailure
 	}
 }
 
+// fail unless `data` is long enough to start with a timestamp
+pub(crate) fn check_timestamp(key: &str, data: &[u8]) -> Result<(), WriteFailure>
+{
+	if data.len() < 8
+	{
+		return Err(WriteFailure::IOError(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("a record of {:?} has no timestamp", key),
+		)));
+	}
+	Ok(())
+}
+
 impl<W: Write+Send> Writer<W>
 {
 	pub fn new(writer: W)
@@ -69,6 +118,7 @@ impl<W: Write+Send> Writer<W>
 			{
 				counter: 0,
 				prev_size: 0,
+				written: 0,
 				writer,
 			};
 
@@ -104,15 +154,36 @@ impl<W: Write+Send> Writer<W>
 			worker_threads: Some(send),
 			thread_handles,
 			thread_ordering: 0,
+			keys: 0,
+			records: 0,
+			uncompressed_bytes: 0,
 		}
 	}
 
+	/// Returns a function that says how many bytes have been written so far
+	pub(crate) fn written(&self) -> impl Fn() -> u64 + Send + Sync + 'static
+		where W: 'static
+	{
+		let state = self.writer_state.clone().expect("writer_state");
+		move || state.lock().written
+	}
+
 	pub(crate) fn add_record(&mut self, key: &str, format: &str, data: &[u8])
 		-> std::result::Result<(), WriteFailure>
 	{
-		// this is the first key ever seen
+		// this is the first key ever seen, or the first after
+		// a compressed segment
 		if self.current_key_data.is_empty()
 		{
+			// a copied segment's last key may really be in the next one
+			if self.thread_ordering != 0
+				&& key.as_bytes() < self.last_key.as_bytes()
+			{
+				return Err(WriteFailure::OrderingViolation(
+					key.to_string(),
+					self.last_key.clone(),
+				));
+			}
 			self.last_key.replace_range(.., key);
 			self.last_format.replace_range(.., format);
 			self.first_segment_key.replace_range(.., key);
@@ -199,6 +270,11 @@ impl<W: Write+Send> Writer<W>
 			}
 		}
 
+		if self.current_key_data.len() == 16 + key.len() + format.len()
+		{
+			self.keys += 1;
+		}
+		self.records += 1;
 		self.current_timestamp.copy_from_slice(&data[0..8]);
 
 		assert_eq!(self.current_key_record_len, data.len());
@@ -206,42 +282,136 @@ impl<W: Write+Send> Writer<W>
 		Ok(())
 	}
 
+	/// Add a segment that was already compressed, such as one
+	/// from another file, without compressing it again.
+	/// `decoded` is its payload decompressed, to count its records.
+	///
+	/// A segment written after another has the key after its
+	/// actual first key as its `first_key`, so `lowest_key`
+	/// is the lowest key it may have, which is the previous
+	/// segment's last key. If nothing already written has
+	/// `lowest_key` in its range, it becomes the first key, so
+	/// that a search for that key finds this segment.
+	pub(crate) fn add_compressed_segment(
+		&mut self,
+		lowest_key: &str,
+		first_key: &str,
+		last_key: &str,
+		compressed: &[u8],
+		decoded: &[u8],
+	) -> std::result::Result<(), WriteFailure>
+	{
+		self.flush_current_key();
+		if !self.current_segment_data.is_empty()
+		{
+			self.store_current_segment()?;
+		}
+
+		let mut previous_key = self.last_key.clone();
+		crate::segment::for_each_key(
+			decoded,
+			|key, _, record_len, data| -> std::io::Result<()>
+			{
+				if key != previous_key
+				{
+					self.keys += 1;
+					previous_key.replace_range(.., key);
+				}
+				self.records += (data.len() / record_len) as u64;
+				Ok(())
+			}
+		)?;
+		self.uncompressed_bytes += decoded.len() as u64;
+
+		let mut first_key = first_key;
+		if self.thread_ordering != 0
+		{
+			if lowest_key.as_bytes() < self.last_key.as_bytes()
+			{
+				return Err(WriteFailure::OrderingViolation(
+					lowest_key.to_string(),
+					self.last_key.clone(),
+				));
+			}
+			if lowest_key != self.last_key
+				{ first_key = lowest_key; }
+		}
+		else
+		{
+			first_key = lowest_key;
+		}
+
+		let header = segment_header(first_key, last_key);
+		self.send_segment(header, compressed.to_owned(), true);
+
+		// the next record starts a new segment, but still
+		// has to come after this one
+		self.last_key.replace_range(.., last_key);
+		self.last_format.clear();
+		Ok(())
+	}
+
 	/// send the current segment to a worker thread to get written
 	pub(crate) fn store_current_segment(&mut self) -> std::io::Result<()>
 	{
-		let mut header = vec!();
-		header.write_all(crate::segment::SEGMENT_INVOCATION)?;
-		header.write_u32::<BigEndian>(self.first_segment_key.len() as u32)?;
-		header.write_u32::<BigEndian>(self.last_key.len() as u32)?;
-		header.write_u32::<BigEndian>(0u32)?; // compressed data size (filled by worker thread)
-		header.write_u32::<BigEndian>(0u32)?; // prev_size (filled by worker thread)
-		header.write_all(&self.first_segment_key.as_bytes())?;
-		header.write_all(&self.last_key.as_bytes())?;
+		let header = segment_header(&self.first_segment_key, &self.last_key);
 
 		let payload = std::mem::replace(
 			&mut self.current_segment_data,
 			Vec::with_capacity(SEGMENT_SIZE_EXTRA)
 		);
+		self.uncompressed_bytes += payload.len() as u64;
+
+		self.send_segment(header, payload, false);
+		Ok(())
+	}
 
+	fn send_segment(&mut self, header: Vec<u8>, payload: Vec<u8>, compressed: bool)
+	{
 		let message =
 			WorkerMessage
 			{
 				counter: self.thread_ordering,
 				header,
 				payload,
+				compressed,
 			};
 		self.thread_ordering += 1;
 
 		self.worker_threads
 			.as_ref().unwrap()
 			.send(message).expect("failed to send data to worker");
-		Ok(())
+	}
+
+	// move the current key's records into the current segment
+	fn flush_current_key(&mut self)
+	{
+		if !self.current_key_data.is_empty()
+		{
+			// set key data length for previous key
+			{
+				let l = self.current_key_data.len() as u32
+					- 16 - self.last_key.len() as u32
+					- self.last_format.len() as u32;
+				BigEndian::write_u32(&mut self.current_key_data[12..16], l);
+			}
+			self.current_segment_data.extend_from_slice(&self.current_key_data);
+			self.current_key_data.clear();
+		}
 	}
 
 	pub(crate) fn finish(mut self)
-		-> std::io::Result<W>
+		-> std::io::Result<(W, WriteStats)>
 	{
 		self.fin()?;
+		let mut stats = WriteStats
+		{
+			keys: self.keys,
+			records: self.records,
+			segments: self.thread_ordering as u64,
+			uncompressed_bytes: self.uncompressed_bytes,
+			compressed_bytes: 0,
+		};
 		// destructure the entire writer_state to get
 		// the tasty cream-filled `Write` inside
 		let e = Arc::try_unwrap(
@@ -251,7 +421,9 @@ impl<W: Write+Send> Writer<W>
 		);
 		if let Ok(k) = e
 		{
-			Ok(k.into_inner().writer)
+			let state = k.into_inner();
+			stats.compressed_bytes = state.written;
+			Ok((state.writer, stats))
 		}
 		else
 		{
@@ -262,18 +434,7 @@ impl<W: Write+Send> Writer<W>
 	fn fin(&mut self)
 		-> std::io::Result<()>
 	{
-		if !self.current_key_data.is_empty()
-		{
-			// set key data length for previous key
-			{
-				let l = self.current_key_data.len() as u32
-					- 16 - self.last_key.len() as u32
-					- self.last_format.len() as u32;
-				BigEndian::write_u32(&mut self.current_key_data[12..16], l);
-			}
-			self.current_segment_data.extend_from_slice(&self.current_key_data);
-			self.current_key_data.clear();
-		}
+		self.flush_current_key();
 
 		if !self.current_segment_data.is_empty()
 		{
@@ -296,6 +457,19 @@ impl<W: Write+Send> Drop for Writer<W>
 	}
 }
 
+fn segment_header(first_key: &str, last_key: &str) -> Vec<u8>
+{
+	let mut header = vec!();
+	header.extend_from_slice(crate::segment::SEGMENT_INVOCATION);
+	header.write_u32::<BigEndian>(first_key.len() as u32).unwrap();
+	header.write_u32::<BigEndian>(last_key.len() as u32).unwrap();
+	header.write_u32::<BigEndian>(0u32).unwrap(); // compressed data size (filled by worker thread)
+	header.write_u32::<BigEndian>(0u32).unwrap(); // prev_size (filled by worker thread)
+	header.extend_from_slice(first_key.as_bytes());
+	header.extend_from_slice(last_key.as_bytes());
+	header
+}
+
 fn worker_thread<W: Write+Send>(
 	recv: channel::Receiver<WorkerMessage>,
 	writer_state: &Mutex<WriterState<W>>,
@@ -304,16 +478,25 @@ fn worker_thread<W: Write+Send>(
 {
 	for message in recv
 	{
-		let WorkerMessage { counter, mut header, payload }
+		let WorkerMessage { counter, mut header, payload, compressed }
 			= message;
 
-		let mut encoder = lz4::EncoderBuilder::new()
-			.level(9)
-			.build(vec!())
-			.unwrap();
-		encoder.write_all(&payload)?;
-		let (compressed, e) = encoder.finish();
-		e?;
+		let compressed =
+			if compressed
+			{
+				payload
+			}
+			else
+			{
+				let mut encoder = lz4::EncoderBuilder::new()
+					.level(9)
+					.build(vec!())
+					.unwrap();
+				encoder.write_all(&payload)?;
+				let (compressed, e) = encoder.finish();
+				e?;
+				compressed
+			};
 
 		BigEndian::write_u32(&mut header[16+8 .. 16+8+4], compressed.len() as u32);
 
@@ -330,6 +513,7 @@ fn worker_thread<W: Write+Send>(
 			.expect("failed to write compressed data");
 		wl.counter = counter+1;
 		wl.prev_size = compressed.len() as u32+32;
+		wl.written += (header.len() + compressed.len()) as u64;
 		writer_notifier.notify_all();
 	}
 	Ok(())